@@ -0,0 +1,174 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Adoption of the devices already mounted at startup (e.g. after a restart)
+//!
+//! The ifuse mounts under the base path are matched to the connected devices, by the mountpoints of
+//! their metadata file or, if missing, by the mountpoints the config gives them.
+//! The mounts left without a connected device, or of a device no longer allowed, are cleaned up as
+//! stale ones.
+
+use std::path::PathBuf;
+
+use rusb::{Context, DeviceDescriptor, UsbContext};
+
+use crate::config::{Config, DeviceSettings, Naming};
+use crate::details::DeviceDetails;
+use crate::meta::{self, Metadata};
+use crate::mounts::{self, MountEntry};
+use crate::state::{self, MountedDevice, State};
+use crate::template::DeviceInfo;
+use crate::{
+    is_apple_device, join_paths, model_name, open_and_read_device_info, unmount_stale, DeviceAddr,
+    Error,
+};
+
+/// Track the connected devices that are already mounted, and unmount the orphan mounts
+pub fn adopt(config: &Config, context: &Context, state: &State) -> Result<(), Error> {
+    let mount_table: Vec<MountEntry> = mounts::read()?;
+    let mut orphans: Vec<PathBuf> = mount_table
+        .iter()
+        .filter(|entry| entry.is_ifuse() && entry.target.starts_with(&config.base_path))
+        .map(|entry| entry.target.clone())
+        .collect();
+
+    if orphans.is_empty() {
+        return Ok(());
+    }
+
+    let mut adopted: usize = 0;
+
+    for device in context.devices()?.iter() {
+        let descriptor: DeviceDescriptor = match device.device_descriptor() {
+            Ok(descriptor) => descriptor,
+            Err(..) => continue,
+        };
+
+        if !is_apple_device(config, &device, &descriptor) {
+            continue;
+        }
+
+        // Without reset: it may be mounted
+        let mut info: DeviceInfo = match open_and_read_device_info(
+            &device,
+            &descriptor,
+            config.usb_timeout,
+            config.needs_name(),
+        ) {
+            Ok(info) => info,
+            Err(e) => {
+                tracing::warn!(
+                    "Can't read serial number of device {:03}:{:03}: {}",
+                    device.bus_number(),
+                    device.address(),
+                    e.report()
+                );
+                continue;
+            }
+        };
+
+        // Mounted before it was denied (or left out of the allow list): cleaned up as an orphan
+        if !config.is_allowed(&info.serial_number) {
+            tracing::info!("{} is not allowed, not adopting it", info.serial_number);
+            continue;
+        }
+
+        let metadata: Option<Metadata> = meta::read(&config.base_path, &info.serial_number);
+        let settings: DeviceSettings = config.device(&info.serial_number);
+        let expected: Vec<PathBuf> = match &metadata {
+            Some(metadata) => metadata
+                .mounts
+                .iter()
+                .map(|mount| mount.mountpoint.clone())
+                .collect(),
+            None => {
+                if config.naming == Naming::Model {
+                    info.model = model_name(&info, &state.devices(), config.command_timeout);
+                }
+                settings
+                    .mounts
+                    .iter()
+                    .map(|spec| config.mountpoint(&info, spec))
+                    .collect()
+            }
+        };
+
+        let mountpoints: Vec<PathBuf> = expected
+            .into_iter()
+            .filter(|path| orphans.contains(path))
+            .collect();
+        if mountpoints.is_empty() {
+            continue;
+        }
+        orphans.retain(|path| !mountpoints.contains(path));
+
+        // Saved by the previous instance, maybe with another address (replugged meanwhile)
+        let saved: Option<MountedDevice> = state
+            .devices()
+            .into_iter()
+            .find(|device| device.serial_number == info.serial_number);
+        let addr: DeviceAddr = DeviceAddr {
+            bus: info.bus,
+            addr: info.address,
+        };
+        let mounted: MountedDevice = match saved {
+            Some(mut saved) => {
+                state.remove(&DeviceAddr {
+                    bus: saved.bus,
+                    addr: saved.address,
+                });
+                saved.bus = info.bus;
+                saved.address = info.address;
+                saved.mountpoints = mountpoints;
+                saved
+            }
+            None => MountedDevice {
+                serial_number: info.serial_number.clone(),
+                mountpoints,
+                read_only: settings.read_only,
+                bus: info.bus,
+                address: info.address,
+                vendor_id: info.vendor_id,
+                product_id: info.product_id,
+                name: info.name.clone(),
+                model: info.model.clone(),
+                mounted_at: metadata.map_or_else(state::now, |metadata| metadata.mounted_at),
+                user: None,
+                details: DeviceDetails::default(),
+            },
+        };
+
+        tracing::info!(
+            "Adopted {}, already mounted at {}",
+            mounted.serial_number,
+            join_paths(&mounted.mountpoints)
+        );
+        state.insert(addr, mounted);
+        adopted += 1;
+    }
+
+    // Saved, but gone
+    for device in state.devices() {
+        if device.mountpoints.iter().any(|path| orphans.contains(path)) {
+            tracing::info!("{} is no longer connected", device.serial_number);
+            state.remove(&DeviceAddr {
+                bus: device.bus,
+                addr: device.address,
+            });
+        }
+    }
+
+    // Reverse order: nested mounts first
+    for path in orphans.iter().rev() {
+        tracing::info!("Cleaning up orphan mount at {}", path.display());
+        if let Err(e) = unmount_stale(config, path) {
+            tracing::warn!("Can't unmount {}: {}", path.display(), e.report());
+        }
+    }
+
+    if adopted > 0 {
+        tracing::info!("Adopted {adopted} device(s) already mounted");
+    }
+
+    Ok(())
+}
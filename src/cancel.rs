@@ -0,0 +1,115 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! In-flight mounts, canceled when their device leaves
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+use crate::DeviceAddr;
+
+/// Cancellation flag of a mount, and its current stage
+#[derive(Debug, Clone)]
+pub struct CancelToken {
+    canceled: Arc<AtomicBool>,
+    started_at: Instant,
+    stage: Arc<Mutex<&'static str>>,
+}
+
+impl Default for CancelToken {
+    fn default() -> Self {
+        Self {
+            canceled: Arc::new(AtomicBool::new(false)),
+            started_at: Instant::now(),
+            stage: Arc::new(Mutex::new("starting")),
+        }
+    }
+}
+
+impl CancelToken {
+    /// Record the current stage of the mount (e.g. `pairing`), for the state dump
+    #[inline]
+    pub fn enter(&self, stage: &'static str) {
+        *self.stage.lock().unwrap_or_else(PoisonError::into_inner) = stage;
+    }
+
+    #[inline]
+    fn stage(&self) -> &'static str {
+        *self.stage.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    #[inline]
+    pub fn is_canceled(&self) -> bool {
+        self.canceled.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    fn cancel(&self) {
+        self.canceled.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Mount in progress, for the state dump
+#[derive(Debug, Clone)]
+pub struct InFlightMount {
+    pub addr: DeviceAddr,
+    pub stage: &'static str,
+    pub started_at: Instant,
+}
+
+/// Mounts in progress, shared between the handler thread and the hotplug callbacks
+#[derive(Debug, Clone, Default)]
+pub struct InFlight {
+    tokens: Arc<Mutex<HashMap<DeviceAddr, CancelToken>>>,
+}
+
+impl InFlight {
+    // Left consistent by every operation, so it's safe to ignore poisoning
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, HashMap<DeviceAddr, CancelToken>> {
+        self.tokens.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Register a mount of the device
+    pub fn begin(&self, addr: &DeviceAddr) -> CancelToken {
+        let token: CancelToken = CancelToken::default();
+        self.lock().insert(addr.clone(), token.clone());
+        token
+    }
+
+    pub fn end(&self, addr: &DeviceAddr) {
+        self.lock().remove(addr);
+    }
+
+    /// Mounts in progress
+    pub fn list(&self) -> Vec<InFlightMount> {
+        self.lock()
+            .iter()
+            .map(|(addr, token)| InFlightMount {
+                addr: addr.clone(),
+                stage: token.stage(),
+                started_at: token.started_at,
+            })
+            .collect()
+    }
+
+    /// Forget all the mounts (e.g. left behind by a panic)
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    /// Cancel the mount of the device, if any
+    ///
+    /// Returns `true` if a mount was in progress.
+    pub fn cancel(&self, addr: &DeviceAddr) -> bool {
+        match self.lock().get(addr) {
+            Some(token) => {
+                token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,76 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Child processes with a timeout
+
+use std::io::Read;
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::Error;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Run the command and collect its output, like [`Command::output`]
+///
+/// The child is killed if it doesn't exit within `timeout` (e.g. ifuse stuck on lockdownd).
+/// Stdout is discarded unless the caller configured it.
+pub fn output(cmd: &mut Command, timeout: Duration) -> Result<Output, Error> {
+    let program: String = cmd.get_program().to_string_lossy().into_owned();
+
+    let mut child: Child = cmd
+        .stdin(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|source| Error::Spawn {
+            program: program.clone(),
+            source,
+        })?;
+
+    // Read the pipes in the background, so the child never blocks on a full pipe
+    let stdout: Option<JoinHandle<Vec<u8>>> = child.stdout.take().map(read_to_end);
+    let stderr: Option<JoinHandle<Vec<u8>>> = child.stderr.take().map(read_to_end);
+
+    let start: Instant = Instant::now();
+    let status: ExitStatus = loop {
+        match child.try_wait()? {
+            Some(status) => break status,
+            None if start.elapsed() >= timeout => {
+                tracing::warn!(
+                    "{program} timed out after {} s, killing it",
+                    timeout.as_secs()
+                );
+                child.kill()?;
+                // Reap it
+                child.wait()?;
+                return Err(Error::CommandTimeout(program, timeout));
+            }
+            None => thread::sleep(POLL_INTERVAL),
+        }
+    };
+
+    Ok(Output {
+        status,
+        stdout: join(stdout),
+        stderr: join(stderr),
+    })
+}
+
+fn read_to_end<R>(mut pipe: R) -> JoinHandle<Vec<u8>>
+where
+    R: Read + Send + 'static,
+{
+    thread::spawn(move || {
+        let mut buf: Vec<u8> = Vec::new();
+        let _ = pipe.read_to_end(&mut buf);
+        buf
+    })
+}
+
+#[inline]
+fn join(handle: Option<JoinHandle<Vec<u8>>>) -> Vec<u8> {
+    handle
+        .and_then(|handle| handle.join().ok())
+        .unwrap_or_default()
+}
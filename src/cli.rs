@@ -0,0 +1,349 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use clap::{ArgAction, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use tracing::Level;
+
+use crate::config::{MountMode, USB_TIMEOUT_RANGE};
+use crate::logger::{ColorChoice, LogFormat, LogTarget};
+
+#[derive(Debug, Parser)]
+#[command(author, version, about)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Config file [default: ~/.config/ifuse-automount/config.toml]
+    #[arg(short, long, value_name = "FILE", global = true)]
+    pub config: Option<PathBuf>,
+    /// System-wide deployment (requires root): mount under /media/ifuse, readable by all the users
+    #[arg(long, global = true)]
+    pub system: bool,
+    /// Directory where the devices are mounted [default: $XDG_RUNTIME_DIR/ifuse-automount, or /media/ifuse with --system]
+    #[arg(long, value_name = "DIR", global = true)]
+    pub base_path: Option<PathBuf>,
+    /// Name of the mountpoints, with `{serial}`, `{bus}`, `{address}`, `{vendor_id}`, `{product_id}` and `{name}` placeholders [default: "{serial}"]
+    #[arg(long, value_name = "TEMPLATE", global = true)]
+    pub mount_template: Option<String>,
+    /// What ifuse exposes: `media`, `documents:<bundle-id>` or `root` [default: media]
+    #[arg(long, value_name = "MODE", global = true)]
+    pub mount_mode: Option<MountMode>,
+    /// Time to wait before opening a new device, in milliseconds (0 to disable) [default: 500]
+    #[arg(long, value_name = "MS", global = true)]
+    pub settle_delay_ms: Option<u64>,
+    /// Timeout for reading the USB string descriptors, in seconds [default: 5]
+    #[arg(long, value_name = "SECS", global = true, value_parser = clap::value_parser!(u64).range(USB_TIMEOUT_RANGE))]
+    pub usb_timeout: Option<u64>,
+    /// Don't reset the devices before reading their serial number (avoids re-enumeration behind some hubs)
+    #[arg(long, global = true)]
+    pub no_reset: bool,
+    /// Absolute path of the ifuse binary [default: looked up in PATH]
+    #[arg(long, value_name = "FILE", global = true)]
+    pub ifuse_path: Option<PathBuf>,
+    /// Run ifuse as this user (name or uid) when running as root, given before the subcommand [default: the active session's user with --system]
+    // Not global: `generate systemd --user` asks for a user unit
+    #[arg(long, value_name = "USER")]
+    pub user: Option<String>,
+    /// Unmount command, the mountpoint is appended (e.g. "umount") [default: "fusermount3 -u", "fusermount -u" or "umount" (root only), the first installed]
+    #[arg(long, value_name = "CMD", global = true)]
+    pub unmount_command: Option<String>,
+    /// Extra ifuse option, passed as `-o` (can be repeated or comma-separated, e.g. "ro,allow_other")
+    #[arg(
+        short = 'o',
+        long = "mount-option",
+        value_name = "OPT",
+        value_delimiter = ',',
+        global = true
+    )]
+    pub mount_options: Vec<String>,
+    /// Mount the devices read-only
+    #[arg(long, global = true)]
+    pub read_only: bool,
+    /// Let other users access the mounts (requires `user_allow_other` in /etc/fuse.conf)
+    #[arg(long, global = true)]
+    pub allow_other: bool,
+    /// Don't mount the devices already connected at startup, only the newly plugged ones
+    #[arg(long)]
+    pub no_enumerate: bool,
+    /// Don't unmount the devices when the daemon stops (overrides `unmount_on_exit`)
+    #[arg(long)]
+    pub keep_mounts_on_exit: bool,
+    /// Detect devices and read their serial numbers, but don't create directories nor mount/unmount
+    #[arg(long)]
+    pub dry_run: bool,
+    /// Mount the currently connected devices, print their mountpoints and exit
+    #[arg(long, conflicts_with = "oneshot_unmount")]
+    pub oneshot: bool,
+    /// Unmount the devices mounted under the base path, print their mountpoints and exit
+    #[arg(long)]
+    pub oneshot_unmount: bool,
+    /// Run in the background: detach from the terminal and write a PID file under the runtime dir
+    #[arg(long, conflicts_with_all = ["oneshot", "oneshot_unmount"])]
+    pub daemon: bool,
+    /// Heartbeat file, refreshed while the daemon is healthy ("" to disable) [default: $XDG_RUNTIME_DIR/ifuse-automount/healthy]
+    #[arg(long, value_name = "FILE")]
+    pub health_file: Option<PathBuf>,
+    /// Serve Prometheus metrics over HTTP on this address (e.g. 127.0.0.1:9187)
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_listen: Option<SocketAddr>,
+    /// Label the metrics with hashes of the serial numbers instead of the serial numbers
+    #[arg(long, requires = "metrics_listen")]
+    pub metrics_hash_serials: bool,
+    /// Increase the log verbosity (-v for debug, -vv for trace)
+    #[arg(short, long, action = ArgAction::Count, global = true)]
+    pub verbose: u8,
+    /// Only log warnings and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Log level: error, warn, info, debug or trace (overrides -v/-q and RUST_LOG) [default: info]
+    #[arg(long, value_name = "LEVEL", global = true, conflicts_with_all = ["verbose", "quiet"])]
+    pub log_level: Option<Level>,
+    /// Where the logs go [default: journald if stderr is connected to the journal, stderr otherwise]
+    #[arg(long, value_name = "TARGET", global = true)]
+    pub log_target: Option<LogTarget>,
+    /// Format of the logs written to stderr
+    #[arg(long, value_name = "FORMAT", global = true, default_value = "human")]
+    pub log_format: LogFormat,
+    /// Color the levels and the device identifiers of the human logs on stderr
+    #[arg(long, value_name = "WHEN", global = true, default_value = "auto")]
+    pub color: ColorChoice,
+    /// Write the logs to this file instead of stderr, rotating it by size
+    #[arg(
+        long,
+        value_name = "FILE",
+        global = true,
+        conflicts_with = "log_target"
+    )]
+    pub log_file: Option<PathBuf>,
+    /// Size of the log file, in MiB, from which it's rotated
+    #[arg(long, value_name = "MIB", global = true, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    pub log_file_max_size: u64,
+    /// Rotated log files kept (`daemon.log.1`, `daemon.log.2`...)
+    #[arg(long, value_name = "N", global = true, default_value_t = 5)]
+    pub log_file_max_files: usize,
+    /// Identifier of the syslog messages, with `--log-target syslog`
+    #[arg(
+        long,
+        value_name = "NAME",
+        global = true,
+        default_value = "ifuse-automount"
+    )]
+    pub syslog_identifier: String,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Validate the configuration and the environment, then print the effective configuration
+    CheckConfig,
+    /// List the connected Apple devices
+    List {
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show the status of the running daemon
+    Status {
+        /// Print as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Show the last mounts and unmounts, from the mount history
+    History {
+        /// Number of entries
+        #[arg(short = 'n', long, value_name = "N", default_value_t = 20)]
+        count: usize,
+        /// Only the entries of this device (serial number)
+        #[arg(long, value_name = "SERIAL")]
+        serial: Option<String>,
+    },
+    /// Ask the running daemon to mount the connected devices that aren't mounted (e.g. after a failed attempt)
+    Rescan,
+    /// Mount a connected device
+    Mount {
+        /// Serial number of the device
+        serial_number: String,
+        /// What ifuse exposes: `media`, `documents:<bundle-id>` or `root` (overrides the device config)
+        #[arg(long, value_name = "MODE")]
+        mode: Option<MountMode>,
+    },
+    /// Unmount a device
+    Unmount {
+        /// Serial number of the device
+        #[arg(required_unless_present = "all", conflicts_with = "all")]
+        serial_number: Option<String>,
+        /// Mount mode of the mount to remove (overrides the device config)
+        #[arg(long, value_name = "MODE", conflicts_with = "all")]
+        mode: Option<MountMode>,
+        /// Unmount all the devices mounted under the base path
+        #[arg(long)]
+        all: bool,
+    },
+    /// Generate the files integrating the daemon with the system
+    Generate {
+        #[command(subcommand)]
+        target: GenerateTarget,
+    },
+    /// Print a shell completion script to stdout
+    Completions {
+        /// Target shell
+        #[arg(value_enum)]
+        shell: Shell,
+    },
+    /// Print the serial numbers of the mounted devices, one per line (used by the completion scripts)
+    #[command(hide = true)]
+    CompleteSerials,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum GenerateTarget {
+    /// Print a systemd unit running the daemon with the given flags (a user unit, or a system one with --system)
+    Systemd {
+        /// Generate a user unit (the default)
+        #[arg(long = "user", id = "user_unit", conflicts_with = "system")]
+        user_unit: bool,
+        /// Write it to ~/.config/systemd/user (or /etc/systemd/system with --system) instead
+        #[arg(long)]
+        install: bool,
+    },
+    /// Print udev rules giving access to the handled devices (built-in and extra vendor/product IDs)
+    Udev {
+        /// Grant access to the members of this group (`MODE="0660"`) instead of the user of the active session (`uaccess`)
+        #[arg(long, value_name = "GROUP")]
+        group: Option<String>,
+        /// Match all the products of the handled vendors (e.g. devices newer than this release)
+        #[arg(long)]
+        any_product: bool,
+        /// Write them to /etc/udev/rules.d and reload udev instead (requires root)
+        #[arg(long)]
+        install: bool,
+    },
+}
+
+/// Complete the serial numbers of `mount`, `unmount` and `history --serial` (bash)
+///
+/// Wraps the generated function, registered again so that it takes over.
+const BASH_SERIALS: &str = r#"
+_ifuse-automount_serials() {
+    local cur="${COMP_WORDS[COMP_CWORD]}" prev="${COMP_WORDS[COMP_CWORD-1]}" word
+    if [[ "${prev}" == "--serial" ]]; then
+        COMPREPLY=( $(compgen -W "$(ifuse-automount complete-serials 2>/dev/null)" -- "${cur}") )
+        return 0
+    fi
+    if [[ "${cur}" != -* && "${prev}" != -* ]]; then
+        for word in "${COMP_WORDS[@]:1:COMP_CWORD-1}"; do
+            case "${word}" in
+                mount | unmount)
+                    COMPREPLY=( $(compgen -W "$(ifuse-automount complete-serials 2>/dev/null)" -- "${cur}") )
+                    return 0
+                    ;;
+            esac
+        done
+    fi
+    _ifuse-automount "$@"
+}
+
+complete -F _ifuse-automount_serials -o bashdefault -o default ifuse-automount
+"#;
+
+/// Complete the serial numbers (zsh), called by the `serial_number` and `--serial` specs
+const ZSH_SERIALS: &str = r#"
+_ifuse-automount_serials() {
+    local -a serials
+    serials=(${(f)"$(ifuse-automount complete-serials 2>/dev/null)"})
+    _describe -t serials 'serial number' serials
+}
+"#;
+
+/// Complete the serial numbers of `mount`, `unmount` and `history --serial` (fish)
+const FISH_SERIALS: &str = r#"
+complete -c ifuse-automount -n "__fish_ifuse_automount_using_subcommand mount unmount" -f -a "(ifuse-automount complete-serials 2>/dev/null)"
+complete -c ifuse-automount -n "__fish_ifuse_automount_using_subcommand history" -l serial -x -a "(ifuse-automount complete-serials 2>/dev/null)"
+"#;
+
+/// Print the completion script for `shell`
+pub fn print_completions(shell: Shell) {
+    print!("{}", completions(shell));
+}
+
+/// Completion script for `shell`
+///
+/// The serial numbers are completed dynamically (bash, zsh and fish), with the `complete-serials`
+/// subcommand.
+fn completions(shell: Shell) -> String {
+    let mut command: clap::Command = Cli::command();
+    let bin_name: String = command.get_name().to_string();
+    let mut script: Vec<u8> = Vec::new();
+    clap_complete::generate(shell, &mut command, bin_name, &mut script);
+    let script: String = String::from_utf8_lossy(&script).into_owned();
+
+    match shell {
+        Shell::Bash => script + BASH_SERIALS,
+        Shell::Zsh => script
+            .replacen(
+                "autoload -U is-at-least\n",
+                &format!("autoload -U is-at-least\n{ZSH_SERIALS}"),
+                1,
+            )
+            .replace(
+                ":serial_number -- Serial number of the device:_default'",
+                ":serial_number -- Serial number of the device:_ifuse-automount_serials'",
+            )
+            .replace(":SERIAL:_default'", ":SERIAL:_ifuse-automount_serials'"),
+        Shell::Fish => script + FISH_SERIALS,
+        _ => script,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Cli, clap::Error> {
+        Cli::try_parse_from(["ifuse-automount"].iter().chain(args))
+    }
+
+    #[test]
+    fn generate_user_unit() {
+        let args: Cli = parse(&["generate", "systemd", "--user", "--install"]).unwrap();
+        assert_eq!(args.user, None);
+        assert!(!args.system);
+        assert!(matches!(
+            args.command,
+            Some(Command::Generate {
+                target: GenerateTarget::Systemd {
+                    user_unit: true,
+                    install: true
+                }
+            })
+        ));
+
+        // The user running ifuse, before the subcommand
+        let args: Cli = parse(&["--user", "alice", "generate", "systemd", "--system"]).unwrap();
+        assert_eq!(args.user.as_deref(), Some("alice"));
+        assert!(matches!(
+            args.command,
+            Some(Command::Generate {
+                target: GenerateTarget::Systemd {
+                    user_unit: false,
+                    ..
+                }
+            })
+        ));
+
+        let e: clap::Error = parse(&["generate", "systemd", "--user", "--system"]).unwrap_err();
+        assert_eq!(e.kind(), clap::error::ErrorKind::ArgumentConflict);
+    }
+
+    #[test]
+    fn complete_serials() {
+        let zsh: String = completions(Shell::Zsh);
+        assert!(zsh.contains(ZSH_SERIALS));
+        assert_eq!(zsh.matches(":_ifuse-automount_serials'").count(), 3);
+
+        assert!(completions(Shell::Bash).ends_with(BASH_SERIALS));
+        assert!(completions(Shell::Fish).ends_with(FISH_SERIALS));
+    }
+}
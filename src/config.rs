@@ -0,0 +1,1791 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::collections::BTreeMap;
+use std::env::{self, VarError};
+use std::io::ErrorKind;
+use std::ops::RangeInclusive;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use std::{fmt, fs};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cli::Cli;
+use crate::template::{self, DeviceInfo, Template};
+use crate::Error;
+
+const ENV_PREFIX: &str = "IFUSE_AUTOMOUNT_";
+/// Name of the default base path, under the user dirs
+const APP_DIR_NAME: &str = "ifuse-automount";
+/// Name of the default health file, under `<runtime-dir>/ifuse-automount`
+const HEALTH_FILE_NAME: &str = "healthy";
+const HISTORY_FILE_NAME: &str = "history.jsonl";
+const DEFAULT_SYSTEM_BASE_PATH: &str = "/media/ifuse";
+/// Mode of the created directories in system mode, if not set
+const DEFAULT_SYSTEM_DIR_MODE: u32 = 0o755;
+const MAX_DIR_MODE: u32 = 0o7777;
+const DEFAULT_SETTLE_DELAY: Duration = Duration::from_millis(500);
+const DEFAULT_USB_TIMEOUT: u64 = 5;
+const DEFAULT_IFUSE_PATH: &str = "ifuse";
+const DEFAULT_MOUNT_RETRIES: u32 = 3;
+const DEFAULT_RETRY_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const DEFAULT_RETRY_BACKOFF_FACTOR: f64 = 2.0;
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+const DEFAULT_HOOK_TIMEOUT: u64 = 30;
+const DEFAULT_COMMAND_TIMEOUT: u64 = 30;
+const DEFAULT_LOCKDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_TRUST_TIMEOUT: u64 = 120;
+const DEFAULT_REENUMERATE_GRACE: Duration = Duration::from_secs(3);
+const DEFAULT_OPEN_COMMAND: &str = "xdg-open";
+const DEFAULT_DEBOUNCE: Duration = Duration::from_secs(2);
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const DEFAULT_USB_ERROR_LIMIT: u32 = 10;
+const DEFAULT_BUSY_UNMOUNT_RETRIES: u32 = 5;
+const DEFAULT_BUSY_UNMOUNT_INTERVAL: Duration = Duration::from_secs(2);
+/// Characters not allowed in the ifuse options (`,` is the separator)
+const INVALID_OPTION_CHARS: &str = ",;&|$`'\"\\<>(){}*?!#~";
+/// Default unmount commands, by preference (fuse3 first)
+const DEFAULT_UNMOUNT_COMMANDS: [&[&str]; 2] = [&["fusermount3", "-u"], &["fusermount", "-u"]];
+/// Last resort unmount command, when running as root without fusermount
+const ROOT_UNMOUNT_COMMAND: &[&str] = &["umount"];
+
+/// Allowed USB timeout values (seconds)
+pub const USB_TIMEOUT_RANGE: RangeInclusive<u64> = 1..=60;
+
+/// Config file content
+///
+/// Every field is optional: missing ones fall back to the defaults.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    /// System-wide deployment (requires root)
+    system: Option<bool>,
+    base_path: Option<PathBuf>,
+    mount_template: Option<String>,
+    /// `template` (`mount_template`) or `model` (marketing name, e.g. `iPhone 13`)
+    naming: Option<Naming>,
+    mount_mode: Option<MountMode>,
+    settle_delay_ms: Option<u64>,
+    usb_timeout_secs: Option<u64>,
+    usb_reset: Option<bool>,
+    ifuse_path: Option<PathBuf>,
+    /// Program and leading args, the mountpoint is appended
+    unmount_command: Option<Vec<String>>,
+    mount_options: Option<Vec<String>>,
+    read_only: Option<bool>,
+    allow_other: Option<bool>,
+    allow: Option<Vec<String>>,
+    deny: Option<Vec<String>>,
+    extra_vendor_ids: Option<Vec<u16>>,
+    extra_product_ids: Option<Vec<u16>>,
+    /// Accept any Apple device with an unknown product ID if it answers to lockdown
+    accept_unknown_apple: Option<bool>,
+    enumerate_on_start: Option<bool>,
+    mount_retries: Option<u32>,
+    retry_initial_delay_ms: Option<u64>,
+    retry_backoff_factor: Option<f64>,
+    /// Commands run after mounting/unmounting a device (program and args)
+    on_mount: Option<Vec<String>>,
+    on_unmount: Option<Vec<String>>,
+    hook_timeout_secs: Option<u64>,
+    /// Time after which ifuse and the unmount command are killed
+    command_timeout_secs: Option<u64>,
+    /// Time to wait for lockdownd before mounting (0 to disable)
+    lockdown_timeout_ms: Option<u64>,
+    /// Pair the devices with `idevicepair` before mounting
+    pair: Option<bool>,
+    /// Time to wait for the trust dialog to be accepted (0 to disable)
+    trust_timeout_secs: Option<u64>,
+    /// Time to wait for a disconnected device to come back before unmounting it (0 to disable)
+    reenumerate_grace_ms: Option<u64>,
+    /// Window in which the repeated hotplug events of a device are coalesced (0 to disable)
+    debounce_ms: Option<u64>,
+    /// Interval of the device scans, where libusb hotplug is unsupported
+    poll_interval_ms: Option<u64>,
+    /// Consecutive libusb event errors before the libusb context is rebuilt
+    usb_error_limit: Option<u32>,
+    /// Heartbeat file refreshed while the daemon is healthy (empty to disable)
+    health_file: Option<PathBuf>,
+    /// Mount history, appended on every mount and unmount (empty to disable)
+    history_file: Option<PathBuf>,
+    /// Retries of an unmount failing because the mountpoint is busy, before a lazy unmount
+    busy_unmount_retries: Option<u32>,
+    busy_unmount_interval_ms: Option<u64>,
+    /// Create `<base>/by-name/<DeviceName>` symlinks
+    friendly_links: Option<bool>,
+    keep_mountpoints: Option<bool>,
+    /// Desktop notifications on mount, unmount and failures (with `notify-send`)
+    notifications: Option<bool>,
+    /// Open the device directory after mounting (not at startup), with `open_command`
+    open_on_mount: Option<bool>,
+    /// The directory is appended [default: `["xdg-open"]`]
+    open_command: Option<Vec<String>>,
+    /// Move a non-empty mountpoint aside (`<name>.bak-<timestamp>`) instead of failing
+    backup_nonempty_mountpoints: Option<bool>,
+    /// Unmount the devices when the daemon stops
+    unmount_on_exit: Option<bool>,
+    /// Delay the suspend while a device is mounted, unmounting everything before it
+    inhibit_suspend: Option<bool>,
+    /// Permissions and ownership of the created directories (e.g. `dir_mode = 0o750`)
+    dir_mode: Option<u32>,
+    dir_uid: Option<u32>,
+    dir_gid: Option<u32>,
+    /// Unmount with the `umount2` syscall instead of the unmount command (requires `CAP_SYS_ADMIN`)
+    native_unmount: Option<bool>,
+    /// User running ifuse when the daemon is root (name or uid) [default: the active session's user in system mode]
+    user: Option<String>,
+    /// Per-device sections: `[device."<serial>"]`
+    device: BTreeMap<String, DeviceConfig>,
+}
+
+impl FileConfig {
+    /// Load the config file
+    ///
+    /// If `required` is `false`, a missing file returns the default config.
+    fn load(path: &Path, required: bool) -> Result<Self, Error> {
+        let content: String = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound && !required => return Ok(Self::default()),
+            Err(e) => return Err(Error::fs("read", path)(e)),
+        };
+
+        toml::from_str(&content).map_err(|e| Error::InvalidConfig(path.to_path_buf(), e))
+    }
+}
+
+/// What ifuse exposes
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum MountMode {
+    /// Media partition (`media`)
+    #[default]
+    Media,
+    /// Documents of an app (`documents:<bundle-id>`)
+    Documents(String),
+    /// Whole filesystem of a jailbroken device, through AFC2 (`root`)
+    Root,
+}
+
+impl MountMode {
+    /// Name of the mountpoint: the bundle id (or `root`) is appended in documents (or root) mode,
+    /// so the modes don't collide for the same device.
+    pub fn dir_name(&self, name: String) -> String {
+        match self {
+            Self::Media => name,
+            Self::Documents(bundle_id) => format!("{name}-{bundle_id}"),
+            Self::Root => format!("{name}-root"),
+        }
+    }
+}
+
+impl FromStr for MountMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode.split_once(':') {
+            None if mode == "media" => Ok(Self::Media),
+            None if mode == "root" => Ok(Self::Root),
+            Some(("documents", bundle_id)) => {
+                let is_valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_');
+                if bundle_id.is_empty() || !bundle_id.chars().all(is_valid) {
+                    return Err(format!("invalid bundle id '{bundle_id}'"));
+                }
+                Ok(Self::Documents(bundle_id.to_string()))
+            }
+            _ => Err(format!(
+                "unknown mount mode '{mode}' (expected `media`, `documents:<bundle-id>` or `root`)"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for MountMode {
+    type Error = String;
+
+    #[inline]
+    fn try_from(mode: String) -> Result<Self, Self::Error> {
+        mode.parse()
+    }
+}
+
+impl From<MountMode> for String {
+    fn from(mode: MountMode) -> Self {
+        mode.to_string()
+    }
+}
+
+impl fmt::Display for MountMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Media => write!(f, "media"),
+            Self::Documents(bundle_id) => write!(f, "documents:{bundle_id}"),
+            Self::Root => write!(f, "root"),
+        }
+    }
+}
+
+/// How the mountpoints are named
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Naming {
+    /// With the mount template (`template`)
+    #[default]
+    Template,
+    /// With the marketing name of the device, e.g. `iPhone 13` (`model`)
+    Model,
+}
+
+impl FromStr for Naming {
+    type Err = String;
+
+    fn from_str(naming: &str) -> Result<Self, Self::Err> {
+        match naming {
+            "template" => Ok(Self::Template),
+            "model" => Ok(Self::Model),
+            _ => Err(format!(
+                "unknown naming '{naming}' (expected `template` or `model`)"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Naming {
+    type Error = String;
+
+    #[inline]
+    fn try_from(naming: String) -> Result<Self, Self::Error> {
+        naming.parse()
+    }
+}
+
+impl From<Naming> for String {
+    fn from(naming: Naming) -> Self {
+        naming.to_string()
+    }
+}
+
+impl fmt::Display for Naming {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Template => write!(f, "template"),
+            Self::Model => write!(f, "model"),
+        }
+    }
+}
+
+/// One of the mounts of a device with several (`[[device."<serial>".mounts]]`)
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct MountSpec {
+    /// What ifuse exposes
+    #[serde(default)]
+    pub mode: MountMode,
+    /// Directory, relative to the device mountpoint [default: `media`, `apps/<bundle-id>` or `root`]
+    ///
+    /// `None` means the device mountpoint itself.
+    pub path: Option<PathBuf>,
+}
+
+impl MountSpec {
+    /// Mountpoint, given the name of the device directory
+    pub fn mountpoint(&self, base_path: &Path, name: String) -> PathBuf {
+        match &self.path {
+            Some(path) => base_path.join(name).join(path),
+            None => base_path.join(self.mode.dir_name(name)),
+        }
+    }
+
+    /// Default directory of a mount, when the device has several
+    pub fn default_path(mode: &MountMode) -> PathBuf {
+        match mode {
+            MountMode::Media => PathBuf::from("media"),
+            MountMode::Documents(bundle_id) => Path::new("apps").join(bundle_id),
+            MountMode::Root => PathBuf::from("root"),
+        }
+    }
+}
+
+/// Per-device config section
+///
+/// Fields that are set override the global ones.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Whether the device must be mounted
+    pub mount: Option<bool>,
+    /// Time to wait before mounting the device, in milliseconds
+    ///
+    /// The serial number is known only after the global delay elapsed, so this only extends it.
+    pub settle_delay_ms: Option<u64>,
+    /// Extra ifuse options (`-o`)
+    pub mount_options: Option<Vec<String>>,
+    /// Mount read-only
+    pub read_only: Option<bool>,
+    /// What ifuse exposes
+    pub mount_mode: Option<MountMode>,
+    /// Mount the whole filesystem (shorthand for `mount_mode = "root"`, requires AFC2)
+    pub root: Option<bool>,
+    /// Several mounts, under the device mountpoint (`mount_mode` is ignored)
+    pub mounts: Vec<MountSpec>,
+}
+
+/// Settings of a specific device (per-device config merged over the global one)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSettings {
+    pub mount: bool,
+    pub settle_delay: Duration,
+    pub mount_options: Vec<String>,
+    pub read_only: bool,
+    pub allow_other: bool,
+    /// Mounts of the device (a single one, unless configured otherwise)
+    pub mounts: Vec<MountSpec>,
+}
+
+impl DeviceSettings {
+    /// Options passed to ifuse (`-o`), including `ro` if read-only
+    pub fn ifuse_options(&self) -> Vec<String> {
+        let mut options: Vec<String> = self.mount_options.clone();
+
+        if self.read_only && !options.iter().any(|o| o == "ro") {
+            options.push(String::from("ro"));
+        }
+
+        if self.allow_other && !options.iter().any(|o| o == "allow_other") {
+            options.push(String::from("allow_other"));
+        }
+
+        options
+    }
+}
+
+/// Resolved configuration
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// System-wide deployment: mounts under `/media/ifuse`, readable by all the users
+    pub system: bool,
+    /// Directory where the devices are mounted
+    pub base_path: PathBuf,
+    /// Where the base path comes from, for the logs (e.g. `--base-path`, `XDG_RUNTIME_DIR`)
+    pub base_path_source: &'static str,
+    /// Name of the mountpoints, under the base path
+    pub mount_template: Template,
+    /// How the mountpoints are named
+    pub naming: Naming,
+    /// What ifuse exposes
+    pub mount_mode: MountMode,
+    /// Time to wait before opening a new device
+    pub settle_delay: Duration,
+    /// Timeout for reading the USB string descriptors
+    pub usb_timeout: Duration,
+    /// Reset the device before reading its serial number
+    pub usb_reset: bool,
+    /// ifuse binary (looked up in `PATH` if not set)
+    pub ifuse_path: PathBuf,
+    /// Unmount program and leading args (the mountpoint is appended)
+    pub unmount_command: Vec<String>,
+    /// Extra ifuse options (`-o`)
+    pub mount_options: Vec<String>,
+    /// Mount read-only
+    pub read_only: bool,
+    /// Let other users access the mounts (requires `user_allow_other` in `/etc/fuse.conf`)
+    pub allow_other: bool,
+    /// Serial numbers allowed to be mounted (empty means all)
+    pub allow: Vec<String>,
+    /// Serial numbers never mounted (take precedence over the allow list)
+    pub deny: Vec<String>,
+    /// USB vendor IDs handled in addition to Apple's
+    pub extra_vendor_ids: Vec<u16>,
+    /// USB product IDs handled in addition to the built-in ones
+    pub extra_product_ids: Vec<u16>,
+    /// Accept the Apple devices with an unknown product ID, after a lockdown probe
+    pub accept_unknown_apple: bool,
+    /// Mount the devices already connected at startup
+    pub enumerate_on_start: bool,
+    /// How many times a failed mount is retried
+    pub mount_retries: u32,
+    /// Delay before the first retry
+    pub retry_initial_delay: Duration,
+    /// Multiplier applied to the delay after each retry
+    pub retry_backoff_factor: f64,
+    /// Command run after mounting a device (empty to disable)
+    pub on_mount: Vec<String>,
+    /// Command run after unmounting a device (empty to disable)
+    pub on_unmount: Vec<String>,
+    /// Time after which the hooks are killed
+    pub hook_timeout: Duration,
+    /// Time after which ifuse and the unmount command are killed
+    pub command_timeout: Duration,
+    /// Time to wait for lockdownd before mounting (zero to disable)
+    pub lockdown_timeout: Duration,
+    /// Pair the devices with `idevicepair` before mounting
+    pub pair: bool,
+    /// Time to wait for the trust dialog to be accepted (zero to disable)
+    pub trust_timeout: Duration,
+    /// Time to wait for a disconnected device to come back before unmounting it (zero to disable)
+    pub reenumerate_grace: Duration,
+    /// Window in which the repeated hotplug events of a device are coalesced (zero to disable)
+    pub debounce: Duration,
+    /// Interval of the device scans, where libusb hotplug is unsupported
+    pub poll_interval: Duration,
+    /// Consecutive libusb event errors before the libusb context is rebuilt (and the daemon exits, if it fails again)
+    pub usb_error_limit: u32,
+    /// Heartbeat file refreshed while the daemon is healthy (`None` to disable)
+    pub health_file: Option<PathBuf>,
+    /// Mount history, appended on every mount and unmount (`None` to disable)
+    pub history_file: Option<PathBuf>,
+    /// Retries of an unmount failing because the mountpoint is busy, before a lazy unmount (0 to unmount lazily at once)
+    pub busy_unmount_retries: u32,
+    /// Time between the busy unmount retries
+    pub busy_unmount_interval: Duration,
+    /// Create `<base>/by-name/<DeviceName>` symlinks to the device directories
+    pub friendly_links: bool,
+    /// Don't remove the mountpoint directories after unmounting
+    pub keep_mountpoints: bool,
+    /// Desktop notifications on mount, unmount and failures
+    pub notifications: bool,
+    /// Open the device directory after mounting, except for the devices connected at startup
+    pub open_on_mount: bool,
+    /// Command opening the directory (appended)
+    pub open_command: Vec<String>,
+    /// Move the non-empty mountpoints aside instead of failing
+    pub backup_nonempty_mountpoints: bool,
+    /// Unmount the devices on SIGTERM/SIGINT
+    pub unmount_on_exit: bool,
+    /// Hold a logind "sleep" inhibitor lock while a device is mounted, unmounting everything before
+    /// the system suspends
+    pub inhibit_suspend: bool,
+    /// Mode of the created directories (umask if not set)
+    pub dir_mode: Option<u32>,
+    /// Owner of the created directories
+    pub dir_uid: Option<u32>,
+    /// Group of the created directories
+    pub dir_gid: Option<u32>,
+    /// Unmount with the `umount2` syscall (only if the process has `CAP_SYS_ADMIN`)
+    pub native_unmount: bool,
+    /// User (name or uid) running ifuse, only when the daemon is root
+    pub user: Option<String>,
+    /// Per-device configs, by serial number
+    pub devices: BTreeMap<String, DeviceConfig>,
+    /// Only log what would be done, without mounting/unmounting
+    pub dry_run: bool,
+}
+
+impl Config {
+    /// Resolve the configuration from CLI args, environment variables and config file.
+    ///
+    /// Precedence: CLI args > environment variables > config file > defaults.
+    pub fn resolve(args: &Cli) -> Result<Self, Error> {
+        // Load environment variables
+        let env: EnvConfig = EnvConfig::load()?;
+
+        // Load config file
+        let file: FileConfig = match args.config.clone().or(env.config) {
+            Some(path) => FileConfig::load(&expand_tilde(path), true)?,
+            None => match default_config_path() {
+                Some(path) => FileConfig::load(&path, false)?,
+                None => FileConfig::default(),
+            },
+        };
+
+        let system: bool = args.system || env.system.or(file.system).unwrap_or(false);
+        if system && unsafe { libc::geteuid() } != 0 {
+            return Err(Error::SystemModeRequiresRoot);
+        }
+
+        let (base_path, base_path_source): (PathBuf, &'static str) =
+            match (args.base_path.clone(), env.base_path, file.base_path) {
+                (Some(path), ..) => (expand_tilde(path), "--base-path"),
+                (None, Some(path), _) => (expand_tilde(path), "IFUSE_AUTOMOUNT_BASE_PATH"),
+                (None, None, Some(path)) => (expand_tilde(path), "config file"),
+                (None, None, None) if system => (
+                    PathBuf::from(DEFAULT_SYSTEM_BASE_PATH),
+                    "system mode default",
+                ),
+                (None, None, None) => default_base_path()?,
+            };
+
+        let mount_template: Template = match (args.mount_template.clone(), env.mount_template) {
+            (Some(template), _) => parse_template("--mount-template", &template)?,
+            (None, Some(template)) => parse_template(&env_name("MOUNT_TEMPLATE"), &template)?,
+            (None, None) => match file.mount_template {
+                Some(template) => parse_template("mount_template", &template)?,
+                None => Template::default(),
+            },
+        };
+
+        let usb_timeout: u64 = match (args.usb_timeout, env.usb_timeout_secs) {
+            (Some(secs), _) | (None, Some(secs)) => secs,
+            (None, None) => match file.usb_timeout_secs {
+                Some(secs) => check_usb_timeout("usb_timeout_secs", secs)?,
+                None => DEFAULT_USB_TIMEOUT,
+            },
+        };
+
+        let ifuse_path: PathBuf = match args.ifuse_path.clone() {
+            Some(path) => check_absolute("--ifuse-path", expand_tilde(path))?,
+            None => match (env.ifuse_path, file.ifuse_path) {
+                (Some(path), _) => check_absolute(&env_name("IFUSE_PATH"), expand_tilde(path))?,
+                (None, Some(path)) => check_absolute("ifuse_path", expand_tilde(path))?,
+                (None, None) => PathBuf::from(DEFAULT_IFUSE_PATH),
+            },
+        };
+
+        let unmount_command: Vec<String> = match args
+            .unmount_command
+            .as_deref()
+            .map(split_command)
+            .or(env.unmount_command)
+        {
+            Some(command) => command,
+            None => match file.unmount_command {
+                Some(command) => command,
+                None => default_unmount_command(),
+            },
+        };
+
+        if unmount_command.is_empty() {
+            return Err(Error::InvalidSetting(
+                String::from("unmount_command"),
+                String::from("empty command"),
+            ));
+        }
+
+        let mount_options: Vec<String> = match (args.mount_options.is_empty(), env.mount_options) {
+            (false, _) => check_mount_options("--mount-option", args.mount_options.clone())?,
+            (true, Some(options)) => check_mount_options(&env_name("MOUNT_OPTIONS"), options)?,
+            (true, None) => {
+                check_mount_options("mount_options", file.mount_options.unwrap_or_default())?
+            }
+        };
+
+        for (serial_number, device) in file.device.iter() {
+            if let Some(options) = &device.mount_options {
+                check_mount_options(
+                    &format!("device.\"{serial_number}\".mount_options"),
+                    options.clone(),
+                )?;
+            }
+
+            check_mount_specs(
+                &format!("device.\"{serial_number}\".mounts"),
+                &device.mounts,
+            )?;
+
+            if device.root == Some(true) && device.mount_mode.is_some() {
+                return Err(Error::InvalidSetting(
+                    format!("device.\"{serial_number}\".root"),
+                    String::from("conflicts with `mount_mode`"),
+                ));
+            }
+        }
+
+        let command_timeout_secs: u64 = env
+            .command_timeout_secs
+            .or(file.command_timeout_secs)
+            .unwrap_or(DEFAULT_COMMAND_TIMEOUT);
+        if command_timeout_secs == 0 {
+            return Err(Error::InvalidSetting(
+                String::from("command_timeout_secs"),
+                String::from("must be greater than 0"),
+            ));
+        }
+
+        // Unprivileged users keep the unmount command
+        let mut native_unmount: bool = env.native_unmount.or(file.native_unmount).unwrap_or(false);
+        if native_unmount && !crate::has_cap_sys_admin() {
+            tracing::warn!(
+                "native_unmount requires CAP_SYS_ADMIN, using `{}` instead",
+                unmount_command.join(" ")
+            );
+            native_unmount = false;
+        }
+
+        let dir_mode: Option<u32> = match env.dir_mode.or(file.dir_mode) {
+            Some(mode) if mode > MAX_DIR_MODE => {
+                return Err(Error::InvalidSetting(
+                    String::from("dir_mode"),
+                    format!("{mode:o} is not a valid mode"),
+                ))
+            }
+            Some(mode) => Some(mode),
+            None if system => Some(DEFAULT_SYSTEM_DIR_MODE),
+            None => None,
+        };
+
+        // Without root, only the own uid and groups can be applied
+        let is_root: bool = unsafe { libc::geteuid() } == 0;
+        let dir_uid: Option<u32> = match env.dir_uid.or(file.dir_uid) {
+            Some(uid) if !is_root && uid != unsafe { libc::geteuid() } => {
+                tracing::warn!("dir_uid {uid} requires root, ignored");
+                None
+            }
+            uid => uid,
+        };
+        let dir_gid: Option<u32> = match env.dir_gid.or(file.dir_gid) {
+            Some(gid) if !is_root && !crate::is_member_of(gid) => {
+                tracing::warn!("dir_gid {gid} is not a group of the current user, ignored");
+                None
+            }
+            gid => gid,
+        };
+
+        let user: Option<String> = match args.user.clone().or(env.user).or(file.user) {
+            Some(user) if !is_root => {
+                tracing::warn!("user {user} requires root, ignored");
+                None
+            }
+            Some(user) => {
+                // Fail early on typos
+                crate::user::lookup(&user)
+                    .map_err(|e| Error::InvalidSetting(String::from("user"), e.report()))?;
+                Some(user)
+            }
+            None => None,
+        };
+
+        let retry_backoff_factor: f64 = env
+            .retry_backoff_factor
+            .or(file.retry_backoff_factor)
+            .unwrap_or(DEFAULT_RETRY_BACKOFF_FACTOR);
+        if !retry_backoff_factor.is_finite() || retry_backoff_factor < 1.0 {
+            return Err(Error::InvalidSetting(
+                String::from("retry_backoff_factor"),
+                format!("{retry_backoff_factor} is not a number >= 1"),
+            ));
+        }
+
+        let mount_mode: MountMode = match (args.mount_mode.clone(), env.mount_mode) {
+            (Some(mode), _) => mode,
+            (None, Some(mode)) => mode
+                .parse()
+                .map_err(|e| Error::InvalidSetting(env_name("MOUNT_MODE"), e))?,
+            (None, None) => file.mount_mode.unwrap_or_default(),
+        };
+
+        let config: Self = Self {
+            system,
+            base_path,
+            base_path_source,
+            mount_template,
+            naming: env.naming.or(file.naming).unwrap_or_default(),
+            mount_mode,
+            settle_delay: args
+                .settle_delay_ms
+                .or(env.settle_delay_ms)
+                .or(file.settle_delay_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_SETTLE_DELAY),
+            usb_timeout: Duration::from_secs(usb_timeout),
+            usb_reset: match args.no_reset {
+                true => false,
+                false => env.usb_reset.or(file.usb_reset).unwrap_or(true),
+            },
+            ifuse_path,
+            unmount_command,
+            mount_options,
+            read_only: args.read_only || env.read_only.or(file.read_only).unwrap_or(false),
+            // In system mode, the mounts must be readable by the logged-in users
+            allow_other: args.allow_other
+                || system
+                || env.allow_other.or(file.allow_other).unwrap_or(false),
+            allow: serial_numbers(env.allow.or(file.allow).unwrap_or_default()),
+            deny: serial_numbers(env.deny.or(file.deny).unwrap_or_default()),
+            extra_vendor_ids: env
+                .extra_vendor_ids
+                .or(file.extra_vendor_ids)
+                .unwrap_or_default(),
+            extra_product_ids: env
+                .extra_product_ids
+                .or(file.extra_product_ids)
+                .unwrap_or_default(),
+            accept_unknown_apple: env
+                .accept_unknown_apple
+                .or(file.accept_unknown_apple)
+                .unwrap_or(false),
+            enumerate_on_start: match args.no_enumerate {
+                true => false,
+                false => env
+                    .enumerate_on_start
+                    .or(file.enumerate_on_start)
+                    .unwrap_or(true),
+            },
+            mount_retries: env
+                .mount_retries
+                .or(file.mount_retries)
+                .unwrap_or(DEFAULT_MOUNT_RETRIES),
+            retry_initial_delay: env
+                .retry_initial_delay_ms
+                .or(file.retry_initial_delay_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_RETRY_INITIAL_DELAY),
+            retry_backoff_factor,
+            on_mount: env.on_mount.or(file.on_mount).unwrap_or_default(),
+            on_unmount: env.on_unmount.or(file.on_unmount).unwrap_or_default(),
+            hook_timeout: Duration::from_secs(
+                env.hook_timeout_secs
+                    .or(file.hook_timeout_secs)
+                    .unwrap_or(DEFAULT_HOOK_TIMEOUT),
+            ),
+            command_timeout: Duration::from_secs(command_timeout_secs),
+            lockdown_timeout: env
+                .lockdown_timeout_ms
+                .or(file.lockdown_timeout_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_LOCKDOWN_TIMEOUT),
+            pair: env.pair.or(file.pair).unwrap_or(true),
+            trust_timeout: Duration::from_secs(
+                env.trust_timeout_secs
+                    .or(file.trust_timeout_secs)
+                    .unwrap_or(DEFAULT_TRUST_TIMEOUT),
+            ),
+            reenumerate_grace: env
+                .reenumerate_grace_ms
+                .or(file.reenumerate_grace_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_REENUMERATE_GRACE),
+            debounce: env
+                .debounce_ms
+                .or(file.debounce_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_DEBOUNCE),
+            poll_interval: env
+                .poll_interval_ms
+                .or(file.poll_interval_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_POLL_INTERVAL)
+                .max(MIN_POLL_INTERVAL),
+            usb_error_limit: env
+                .usb_error_limit
+                .or(file.usb_error_limit)
+                .unwrap_or(DEFAULT_USB_ERROR_LIMIT)
+                .max(1),
+            health_file: match args
+                .health_file
+                .clone()
+                .or(env.health_file)
+                .or(file.health_file)
+            {
+                Some(path) if path.as_os_str().is_empty() => None,
+                Some(path) => Some(expand_tilde(path)),
+                None => default_health_file(),
+            },
+            history_file: match env.history_file.or(file.history_file) {
+                Some(path) if path.as_os_str().is_empty() => None,
+                Some(path) => Some(expand_tilde(path)),
+                None => default_history_file(),
+            },
+            busy_unmount_retries: env
+                .busy_unmount_retries
+                .or(file.busy_unmount_retries)
+                .unwrap_or(DEFAULT_BUSY_UNMOUNT_RETRIES),
+            busy_unmount_interval: env
+                .busy_unmount_interval_ms
+                .or(file.busy_unmount_interval_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_BUSY_UNMOUNT_INTERVAL),
+            friendly_links: env.friendly_links.or(file.friendly_links).unwrap_or(false),
+            keep_mountpoints: env
+                .keep_mountpoints
+                .or(file.keep_mountpoints)
+                .unwrap_or(false),
+            notifications: env.notifications.or(file.notifications).unwrap_or(false),
+            open_on_mount: env.open_on_mount.or(file.open_on_mount).unwrap_or(false),
+            open_command: env
+                .open_command
+                .or(file.open_command)
+                .filter(|command| !command.is_empty())
+                .unwrap_or_else(|| vec![DEFAULT_OPEN_COMMAND.to_string()]),
+            backup_nonempty_mountpoints: env
+                .backup_nonempty_mountpoints
+                .or(file.backup_nonempty_mountpoints)
+                .unwrap_or(false),
+            unmount_on_exit: !args.keep_mounts_on_exit
+                && env.unmount_on_exit.or(file.unmount_on_exit).unwrap_or(true),
+            inhibit_suspend: env
+                .inhibit_suspend
+                .or(file.inhibit_suspend)
+                .unwrap_or(false),
+            native_unmount,
+            dir_mode,
+            dir_uid,
+            dir_gid,
+            user,
+            devices: file
+                .device
+                .into_iter()
+                .map(|(serial_number, device)| {
+                    (crate::serial_number_from_udid(&serial_number), device)
+                })
+                .collect(),
+            dry_run: args.dry_run,
+        };
+
+        config.check_read_only()?;
+
+        Ok(config)
+    }
+
+    /// Apply the settings that can be changed at runtime and return a summary of the changes.
+    ///
+    /// Settings that require a restart are left untouched and reported as such.
+    pub fn reload(&mut self, new: Self) -> Vec<String> {
+        let mut changes: Vec<String> = Vec::new();
+
+        macro_rules! apply {
+            ($($field:ident),* $(,)?) => {
+                $(
+                    if self.$field != new.$field {
+                        changes.push(format!(
+                            "{}: {:?} -> {:?}",
+                            stringify!($field),
+                            self.$field,
+                            new.$field
+                        ));
+                        self.$field = new.$field;
+                    }
+                )*
+            };
+        }
+
+        macro_rules! requires_restart {
+            ($($field:ident),* $(,)?) => {
+                $(
+                    if self.$field != new.$field {
+                        changes.push(format!(
+                            "{}: {:?} -> {:?} (restart required)",
+                            stringify!($field),
+                            self.$field,
+                            new.$field
+                        ));
+                    }
+                )*
+            };
+        }
+
+        // The hotplug callbacks are registered by vendor
+        requires_restart!(
+            system,
+            base_path,
+            enumerate_on_start,
+            extra_vendor_ids,
+            poll_interval,
+            usb_error_limit,
+            health_file,
+            inhibit_suspend
+        );
+        apply!(
+            mount_template,
+            naming,
+            mount_mode,
+            settle_delay,
+            usb_timeout,
+            usb_reset,
+            ifuse_path,
+            unmount_command,
+            mount_options,
+            read_only,
+            allow_other,
+            allow,
+            deny,
+            extra_product_ids,
+            accept_unknown_apple,
+            mount_retries,
+            retry_initial_delay,
+            retry_backoff_factor,
+            on_mount,
+            on_unmount,
+            hook_timeout,
+            command_timeout,
+            lockdown_timeout,
+            pair,
+            trust_timeout,
+            reenumerate_grace,
+            debounce,
+            busy_unmount_retries,
+            busy_unmount_interval,
+            history_file,
+            friendly_links,
+            keep_mountpoints,
+            notifications,
+            open_on_mount,
+            open_command,
+            backup_nonempty_mountpoints,
+            unmount_on_exit,
+            native_unmount,
+            dir_mode,
+            dir_uid,
+            dir_gid,
+            user,
+            devices
+        );
+
+        changes
+    }
+
+    /// Delay before the retry number `attempt` (starting from 0)
+    pub fn retry_delay(&self, attempt: u32) -> Duration {
+        let secs: f64 = self.retry_initial_delay.as_secs_f64()
+            * self
+                .retry_backoff_factor
+                .powi(i32::try_from(attempt).unwrap_or(i32::MAX));
+        Duration::from_secs_f64(secs.min(MAX_RETRY_DELAY.as_secs_f64()))
+    }
+
+    /// How the devices are unmounted, for the logs
+    pub fn unmount_backend(&self) -> String {
+        match self.native_unmount {
+            true => String::from("umount2"),
+            false => self.unmount_command.join(" "),
+        }
+    }
+
+    /// Check if the USB product string must be read
+    #[inline]
+    pub fn needs_name(&self) -> bool {
+        self.dir_uses_name() || self.friendly_links || self.notifications
+    }
+
+    /// Check if the directory names depend on the USB product string
+    #[inline]
+    pub fn dir_uses_name(&self) -> bool {
+        match self.naming {
+            Naming::Template => self.mount_template.uses_name(),
+            // Fallback of the model name
+            Naming::Model => true,
+        }
+    }
+
+    /// Directory name of the device, under the base path
+    ///
+    /// With `naming = "model"`, the serial number is used if the model is unknown.
+    pub fn dir_name(&self, info: &DeviceInfo) -> String {
+        match self.naming {
+            Naming::Template => self.mount_template.render(info),
+            Naming::Model => template::escape(
+                info.model
+                    .as_deref()
+                    .or(info.name.as_deref())
+                    .unwrap_or(&info.serial_number),
+            ),
+        }
+    }
+
+    /// Directory name of the device, if it only depends on the serial number
+    #[inline]
+    pub fn dir_name_serial_only(&self, serial_number: &str) -> Option<String> {
+        match self.naming {
+            Naming::Template => self.mount_template.render_serial_only(serial_number),
+            Naming::Model => None,
+        }
+    }
+
+    /// Directory of the device: its mountpoint, or the parent of its mounts if it has several
+    pub fn device_dir(&self, info: &DeviceInfo, mounts: &[MountSpec]) -> PathBuf {
+        match mounts {
+            [spec] if spec.path.is_none() => self.mountpoint(info, spec),
+            _ => self.base_path.join(self.dir_name(info)),
+        }
+    }
+
+    /// Mountpoint of one of the mounts of the device
+    #[inline]
+    pub fn mountpoint(&self, info: &DeviceInfo, spec: &MountSpec) -> PathBuf {
+        spec.mountpoint(&self.base_path, self.dir_name(info))
+    }
+
+    /// Check if the device is in the deny list
+    pub fn is_denied(&self, serial_number: &str) -> bool {
+        self.deny.iter().any(|s| s == serial_number)
+    }
+
+    /// Check if the device is allowed: not denied, and in the allow list if there's one
+    ///
+    /// The deny list wins over the allow list.
+    pub fn is_allowed(&self, serial_number: &str) -> bool {
+        !self.is_denied(serial_number)
+            && (self.allow.is_empty() || self.allow.iter().any(|s| s == serial_number))
+    }
+
+    /// Get the settings of a device, merging its config section (if any) over the global config.
+    pub fn device(&self, serial_number: &str) -> DeviceSettings {
+        let device: Option<&DeviceConfig> = self.devices.get(serial_number);
+
+        DeviceSettings {
+            mount: device.and_then(|d| d.mount).unwrap_or(true),
+            settle_delay: device
+                .and_then(|d| d.settle_delay_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(self.settle_delay),
+            mount_options: device
+                .and_then(|d| d.mount_options.clone())
+                .unwrap_or_else(|| self.mount_options.clone()),
+            read_only: device.and_then(|d| d.read_only).unwrap_or(self.read_only),
+            allow_other: self.allow_other,
+            mounts: match device {
+                Some(device) if !device.mounts.is_empty() => device
+                    .mounts
+                    .iter()
+                    .map(|spec| MountSpec {
+                        mode: spec.mode.clone(),
+                        path: Some(
+                            spec.path
+                                .clone()
+                                .unwrap_or_else(|| MountSpec::default_path(&spec.mode)),
+                        ),
+                    })
+                    .collect(),
+                Some(DeviceConfig {
+                    root: Some(true), ..
+                }) => vec![MountSpec {
+                    mode: MountMode::Root,
+                    path: None,
+                }],
+                _ => vec![MountSpec {
+                    mode: device
+                        .and_then(|d| d.mount_mode.clone())
+                        .unwrap_or_else(|| self.mount_mode.clone()),
+                    path: None,
+                }],
+            },
+        }
+    }
+
+    /// Check that read-only mounts don't have write-implying options
+    fn check_read_only(&self) -> Result<(), Error> {
+        let is_writable = |read_only: bool, options: &[String]| -> bool {
+            read_only && options.iter().any(|o| o == "rw")
+        };
+
+        if is_writable(self.read_only, &self.mount_options) {
+            return Err(Error::InvalidSetting(
+                String::from("mount_options"),
+                String::from("`rw` conflicts with read-only mode"),
+            ));
+        }
+
+        for serial_number in self.devices.keys() {
+            let settings: DeviceSettings = self.device(serial_number);
+            if is_writable(settings.read_only, &settings.mount_options) {
+                return Err(Error::InvalidSetting(
+                    format!("device.\"{serial_number}\".mount_options"),
+                    String::from("`rw` conflicts with read-only mode"),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Effective config, formatted as a config file
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let file: FileConfig = FileConfig {
+            system: Some(self.system),
+            base_path: Some(self.base_path.clone()),
+            mount_template: Some(self.mount_template.to_string()),
+            naming: Some(self.naming),
+            mount_mode: Some(self.mount_mode.clone()),
+            settle_delay_ms: Some(self.settle_delay.as_millis() as u64),
+            usb_timeout_secs: Some(self.usb_timeout.as_secs()),
+            usb_reset: Some(self.usb_reset),
+            ifuse_path: Some(self.ifuse_path.clone()),
+            unmount_command: Some(self.unmount_command.clone()),
+            mount_options: Some(self.mount_options.clone()),
+            read_only: Some(self.read_only),
+            allow_other: Some(self.allow_other),
+            allow: Some(self.allow.clone()),
+            deny: Some(self.deny.clone()),
+            extra_vendor_ids: Some(self.extra_vendor_ids.clone()),
+            extra_product_ids: Some(self.extra_product_ids.clone()),
+            accept_unknown_apple: Some(self.accept_unknown_apple),
+            enumerate_on_start: Some(self.enumerate_on_start),
+            mount_retries: Some(self.mount_retries),
+            retry_initial_delay_ms: Some(self.retry_initial_delay.as_millis() as u64),
+            retry_backoff_factor: Some(self.retry_backoff_factor),
+            on_mount: Some(self.on_mount.clone()),
+            on_unmount: Some(self.on_unmount.clone()),
+            hook_timeout_secs: Some(self.hook_timeout.as_secs()),
+            command_timeout_secs: Some(self.command_timeout.as_secs()),
+            lockdown_timeout_ms: Some(self.lockdown_timeout.as_millis() as u64),
+            pair: Some(self.pair),
+            trust_timeout_secs: Some(self.trust_timeout.as_secs()),
+            reenumerate_grace_ms: Some(self.reenumerate_grace.as_millis() as u64),
+            debounce_ms: Some(self.debounce.as_millis() as u64),
+            poll_interval_ms: Some(self.poll_interval.as_millis() as u64),
+            usb_error_limit: Some(self.usb_error_limit),
+            health_file: Some(self.health_file.clone().unwrap_or_default()),
+            history_file: Some(self.history_file.clone().unwrap_or_default()),
+            busy_unmount_retries: Some(self.busy_unmount_retries),
+            busy_unmount_interval_ms: Some(self.busy_unmount_interval.as_millis() as u64),
+            friendly_links: Some(self.friendly_links),
+            keep_mountpoints: Some(self.keep_mountpoints),
+            notifications: Some(self.notifications),
+            open_on_mount: Some(self.open_on_mount),
+            open_command: Some(self.open_command.clone()),
+            backup_nonempty_mountpoints: Some(self.backup_nonempty_mountpoints),
+            unmount_on_exit: Some(self.unmount_on_exit),
+            inhibit_suspend: Some(self.inhibit_suspend),
+            native_unmount: Some(self.native_unmount),
+            dir_mode: self.dir_mode,
+            dir_uid: self.dir_uid,
+            dir_gid: self.dir_gid,
+            user: self.user.clone(),
+            device: self.devices.clone(),
+        };
+        let content: String = toml::to_string_pretty(&file).map_err(|_| fmt::Error)?;
+        write!(f, "{content}")
+    }
+}
+
+/// Settings overridden by the `IFUSE_AUTOMOUNT_*` environment variables
+#[derive(Debug, Default)]
+struct EnvConfig {
+    config: Option<PathBuf>,
+    system: Option<bool>,
+    base_path: Option<PathBuf>,
+    mount_template: Option<String>,
+    naming: Option<Naming>,
+    mount_mode: Option<String>,
+    settle_delay_ms: Option<u64>,
+    usb_timeout_secs: Option<u64>,
+    usb_reset: Option<bool>,
+    ifuse_path: Option<PathBuf>,
+    unmount_command: Option<Vec<String>>,
+    mount_options: Option<Vec<String>>,
+    read_only: Option<bool>,
+    allow_other: Option<bool>,
+    allow: Option<Vec<String>>,
+    deny: Option<Vec<String>>,
+    extra_vendor_ids: Option<Vec<u16>>,
+    extra_product_ids: Option<Vec<u16>>,
+    accept_unknown_apple: Option<bool>,
+    enumerate_on_start: Option<bool>,
+    mount_retries: Option<u32>,
+    retry_initial_delay_ms: Option<u64>,
+    retry_backoff_factor: Option<f64>,
+    on_mount: Option<Vec<String>>,
+    on_unmount: Option<Vec<String>>,
+    hook_timeout_secs: Option<u64>,
+    command_timeout_secs: Option<u64>,
+    lockdown_timeout_ms: Option<u64>,
+    pair: Option<bool>,
+    trust_timeout_secs: Option<u64>,
+    reenumerate_grace_ms: Option<u64>,
+    debounce_ms: Option<u64>,
+    poll_interval_ms: Option<u64>,
+    usb_error_limit: Option<u32>,
+    health_file: Option<PathBuf>,
+    history_file: Option<PathBuf>,
+    busy_unmount_retries: Option<u32>,
+    busy_unmount_interval_ms: Option<u64>,
+    friendly_links: Option<bool>,
+    keep_mountpoints: Option<bool>,
+    notifications: Option<bool>,
+    open_on_mount: Option<bool>,
+    open_command: Option<Vec<String>>,
+    backup_nonempty_mountpoints: Option<bool>,
+    unmount_on_exit: Option<bool>,
+    inhibit_suspend: Option<bool>,
+    native_unmount: Option<bool>,
+    dir_mode: Option<u32>,
+    dir_uid: Option<u32>,
+    dir_gid: Option<u32>,
+    user: Option<String>,
+}
+
+impl EnvConfig {
+    fn load() -> Result<Self, Error> {
+        let usb_timeout_secs: Option<u64> = match env_parse("USB_TIMEOUT_SECS")? {
+            Some(secs) => Some(check_usb_timeout(&env_name("USB_TIMEOUT_SECS"), secs)?),
+            None => None,
+        };
+
+        Ok(Self {
+            config: env_var("CONFIG")?.map(PathBuf::from),
+            system: env_parse("SYSTEM")?,
+            base_path: env_var("BASE_PATH")?.map(PathBuf::from),
+            mount_template: env_var("MOUNT_TEMPLATE")?,
+            naming: env_parse("NAMING")?,
+            mount_mode: env_var("MOUNT_MODE")?,
+            settle_delay_ms: env_parse("SETTLE_DELAY_MS")?,
+            usb_timeout_secs,
+            usb_reset: env_parse("USB_RESET")?,
+            ifuse_path: env_var("IFUSE_PATH")?.map(PathBuf::from),
+            unmount_command: env_var("UNMOUNT_COMMAND")?.as_deref().map(split_command),
+            mount_options: env_list("MOUNT_OPTIONS")?,
+            read_only: env_parse("READ_ONLY")?,
+            allow_other: env_parse("ALLOW_OTHER")?,
+            allow: env_list("ALLOW")?,
+            deny: env_list("DENY")?,
+            extra_vendor_ids: env_id_list("EXTRA_VENDOR_IDS")?,
+            extra_product_ids: env_id_list("EXTRA_PRODUCT_IDS")?,
+            accept_unknown_apple: env_parse("ACCEPT_UNKNOWN_APPLE")?,
+            enumerate_on_start: env_parse("ENUMERATE_ON_START")?,
+            mount_retries: env_parse("MOUNT_RETRIES")?,
+            retry_initial_delay_ms: env_parse("RETRY_INITIAL_DELAY_MS")?,
+            retry_backoff_factor: env_parse("RETRY_BACKOFF_FACTOR")?,
+            on_mount: env_var("ON_MOUNT")?.as_deref().map(split_command),
+            on_unmount: env_var("ON_UNMOUNT")?.as_deref().map(split_command),
+            hook_timeout_secs: env_parse("HOOK_TIMEOUT_SECS")?,
+            command_timeout_secs: env_parse("COMMAND_TIMEOUT_SECS")?,
+            lockdown_timeout_ms: env_parse("LOCKDOWN_TIMEOUT_MS")?,
+            pair: env_parse("PAIR")?,
+            trust_timeout_secs: env_parse("TRUST_TIMEOUT_SECS")?,
+            reenumerate_grace_ms: env_parse("REENUMERATE_GRACE_MS")?,
+            debounce_ms: env_parse("DEBOUNCE_MS")?,
+            poll_interval_ms: env_parse("POLL_INTERVAL_MS")?,
+            usb_error_limit: env_parse("USB_ERROR_LIMIT")?,
+            health_file: env_var("HEALTH_FILE")?.map(PathBuf::from),
+            history_file: env_var("HISTORY_FILE")?.map(PathBuf::from),
+            busy_unmount_retries: env_parse("BUSY_UNMOUNT_RETRIES")?,
+            busy_unmount_interval_ms: env_parse("BUSY_UNMOUNT_INTERVAL_MS")?,
+            friendly_links: env_parse("FRIENDLY_LINKS")?,
+            keep_mountpoints: env_parse("KEEP_MOUNTPOINTS")?,
+            notifications: env_parse("NOTIFICATIONS")?,
+            open_on_mount: env_parse("OPEN_ON_MOUNT")?,
+            open_command: env_var("OPEN_COMMAND")?.as_deref().map(split_command),
+            backup_nonempty_mountpoints: env_parse("BACKUP_NONEMPTY_MOUNTPOINTS")?,
+            unmount_on_exit: env_parse("UNMOUNT_ON_EXIT")?,
+            inhibit_suspend: env_parse("INHIBIT_SUSPEND")?,
+            native_unmount: env_parse("NATIVE_UNMOUNT")?,
+            dir_mode: env_mode("DIR_MODE")?,
+            dir_uid: env_parse("DIR_UID")?,
+            dir_gid: env_parse("DIR_GID")?,
+            user: env_var("USER")?,
+        })
+    }
+}
+
+/// First installed default unmount command
+///
+/// As root, `umount` is used if no fusermount is installed.
+/// If none is available, the last fusermount is returned, to report it at startup.
+fn default_unmount_command() -> Vec<String> {
+    let is_root: bool = unsafe { libc::geteuid() } == 0;
+    let command: &[&str] = DEFAULT_UNMOUNT_COMMANDS
+        .iter()
+        .copied()
+        .find(|command| crate::is_program_installed(command[0]))
+        .or_else(|| {
+            (is_root && crate::is_program_installed(ROOT_UNMOUNT_COMMAND[0]))
+                .then_some(ROOT_UNMOUNT_COMMAND)
+        })
+        .unwrap_or(DEFAULT_UNMOUNT_COMMANDS[DEFAULT_UNMOUNT_COMMANDS.len() - 1]);
+    command.iter().map(|s| s.to_string()).collect()
+}
+
+#[inline]
+fn env_name(name: &str) -> String {
+    format!("{ENV_PREFIX}{name}")
+}
+
+/// Get an environment variable (empty values are ignored)
+fn env_var(name: &str) -> Result<Option<String>, Error> {
+    let name: String = env_name(name);
+    match env::var(&name) {
+        Ok(value) if value.is_empty() => Ok(None),
+        Ok(value) => Ok(Some(value)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(..)) => Err(Error::InvalidSetting(
+            name,
+            String::from("value is not valid unicode"),
+        )),
+    }
+}
+
+/// USB serial numbers of the devices, given as serial numbers or UDIDs (e.g. from `idevice_id -l`)
+fn serial_numbers(serials: Vec<String>) -> Vec<String> {
+    serials
+        .iter()
+        .map(|serial| crate::serial_number_from_udid(serial))
+        .collect()
+}
+
+/// Get a comma-separated list from an environment variable
+fn env_list(name: &str) -> Result<Option<Vec<String>>, Error> {
+    Ok(env_var(name)?.map(|list| list.split(',').map(|s| s.trim().to_string()).collect()))
+}
+
+/// Get a comma-separated list of USB IDs (hex with `0x` prefix, or decimal)
+fn env_id_list(name: &str) -> Result<Option<Vec<u16>>, Error> {
+    let list: Vec<String> = match env_list(name)? {
+        Some(list) => list,
+        None => return Ok(None),
+    };
+
+    let mut ids: Vec<u16> = Vec::with_capacity(list.len());
+    for id in list.iter() {
+        let parsed = match id.strip_prefix("0x").or_else(|| id.strip_prefix("0X")) {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => id.parse(),
+        };
+        match parsed {
+            Ok(parsed) => ids.push(parsed),
+            Err(e) => {
+                return Err(Error::InvalidSetting(
+                    env_name(name),
+                    format!("can't parse '{id}': {e}"),
+                ))
+            }
+        }
+    }
+
+    Ok(Some(ids))
+}
+
+/// Get an octal mode from an environment variable (e.g. `750` or `0750`)
+fn env_mode(name: &str) -> Result<Option<u32>, Error> {
+    match env_var(name)? {
+        Some(value) => match u32::from_str_radix(value.trim_start_matches("0o"), 8) {
+            Ok(mode) => Ok(Some(mode)),
+            Err(e) => Err(Error::InvalidSetting(
+                env_name(name),
+                format!("can't parse '{value}' as an octal mode: {e}"),
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Get and parse an environment variable
+fn env_parse<T>(name: &str) -> Result<Option<T>, Error>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match env_var(name)? {
+        Some(value) => match value.parse() {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => Err(Error::InvalidSetting(
+                env_name(name),
+                format!("can't parse '{value}': {e}"),
+            )),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Split a command line on whitespaces (no quoting support)
+#[inline]
+fn split_command(command: &str) -> Vec<String> {
+    command.split_whitespace().map(|s| s.to_string()).collect()
+}
+
+#[inline]
+fn parse_template(name: &str, template: &str) -> Result<Template, Error> {
+    template
+        .parse()
+        .map_err(|e| Error::InvalidSetting(name.to_string(), e))
+}
+
+/// Check that the ifuse options can be safely joined into a single `-o` argument
+fn check_mount_options(name: &str, options: Vec<String>) -> Result<Vec<String>, Error> {
+    for option in options.iter() {
+        if option.is_empty() {
+            return Err(Error::InvalidSetting(
+                name.to_string(),
+                String::from("empty option"),
+            ));
+        }
+
+        if let Some(c) = option
+            .chars()
+            .find(|c| c.is_whitespace() || c.is_control() || INVALID_OPTION_CHARS.contains(*c))
+        {
+            return Err(Error::InvalidSetting(
+                name.to_string(),
+                format!("invalid character {c:?} in option '{option}'"),
+            ));
+        }
+    }
+
+    Ok(options)
+}
+
+/// Check that the mounts stay under the device mountpoint and don't collide
+fn check_mount_specs(name: &str, specs: &[MountSpec]) -> Result<(), Error> {
+    let mut paths: Vec<PathBuf> = Vec::with_capacity(specs.len());
+
+    for spec in specs.iter() {
+        let path: PathBuf = spec
+            .path
+            .clone()
+            .unwrap_or_else(|| MountSpec::default_path(&spec.mode));
+
+        let is_relative: bool = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(..)));
+        if !is_relative || path.as_os_str().is_empty() {
+            return Err(Error::InvalidSetting(
+                name.to_string(),
+                format!("{} is not a relative path without `..`", path.display()),
+            ));
+        }
+
+        if paths.contains(&path) {
+            return Err(Error::InvalidSetting(
+                name.to_string(),
+                format!("{} is used by multiple mounts", path.display()),
+            ));
+        }
+
+        paths.push(path);
+    }
+
+    Ok(())
+}
+
+fn check_usb_timeout(name: &str, secs: u64) -> Result<u64, Error> {
+    if USB_TIMEOUT_RANGE.contains(&secs) {
+        Ok(secs)
+    } else {
+        Err(Error::InvalidSetting(
+            name.to_string(),
+            format!(
+                "{secs} is not in {}..={}",
+                USB_TIMEOUT_RANGE.start(),
+                USB_TIMEOUT_RANGE.end()
+            ),
+        ))
+    }
+}
+
+fn check_absolute(name: &str, path: PathBuf) -> Result<PathBuf, Error> {
+    if path.is_absolute() {
+        Ok(path)
+    } else {
+        Err(Error::InvalidSetting(
+            name.to_string(),
+            format!("{} is not an absolute path", path.display()),
+        ))
+    }
+}
+
+/// Default config file path: `~/.config/ifuse-automount/config.toml`
+fn default_config_path() -> Option<PathBuf> {
+    let config_dir: PathBuf = dirs::config_dir()?;
+    Some(config_dir.join("ifuse-automount").join("config.toml"))
+}
+
+/// Base path in the user dirs: the runtime dir, or the cache dir if not set (e.g. over SSH or in cron jobs)
+fn default_base_path() -> Result<(PathBuf, &'static str), Error> {
+    if let Some(runtime_dir) = dirs::runtime_dir() {
+        return Ok((runtime_dir.join(APP_DIR_NAME), "XDG_RUNTIME_DIR"));
+    }
+
+    // `$XDG_CACHE_HOME`, or `~/.cache`
+    if let Some(cache_dir) = dirs::cache_dir() {
+        return Ok((
+            cache_dir.join(APP_DIR_NAME),
+            "cache dir, XDG_RUNTIME_DIR not set",
+        ));
+    }
+
+    Err(Error::NoBasePath(vec![
+        String::from("--base-path"),
+        String::from("XDG_RUNTIME_DIR"),
+        String::from("XDG_CACHE_HOME"),
+        String::from("~/.cache"),
+    ]))
+}
+
+/// Default health file: `$XDG_RUNTIME_DIR/ifuse-automount/healthy`, disabled without a runtime dir
+fn default_health_file() -> Option<PathBuf> {
+    let runtime_dir: PathBuf = dirs::runtime_dir()?;
+    Some(runtime_dir.join(APP_DIR_NAME).join(HEALTH_FILE_NAME))
+}
+
+/// Default history file: `~/.local/state/ifuse-automount/history.jsonl`, disabled without a state dir
+fn default_history_file() -> Option<PathBuf> {
+    let state_dir: PathBuf = dirs::state_dir()?;
+    Some(state_dir.join(APP_DIR_NAME).join(HISTORY_FILE_NAME))
+}
+
+/// Expand a leading `~` to the home directory
+pub fn expand_tilde(path: PathBuf) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home_dir)) => home_dir.join(rest),
+        _ => path,
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::process;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Mutex, MutexGuard, PoisonError};
+
+    use clap::Parser;
+
+    use super::*;
+
+    /// The environment is shared by the tests
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    fn lock_env() -> MutexGuard<'static, ()> {
+        ENV_LOCK.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Resolve with a config file, CLI args and environment variables (without the prefix)
+    fn resolve(file: &str, args: &[&str], vars: &[(&str, &str)]) -> Result<Config, Error> {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let path: PathBuf = env::temp_dir().join(format!(
+            "ifuse-automount-test-{}-{}.toml",
+            process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&path, file).unwrap();
+
+        let mut argv: Vec<&str> = vec!["ifuse-automount", "--config", path.to_str().unwrap()];
+        argv.extend_from_slice(args);
+        let cli: Cli = Cli::parse_from(argv);
+
+        let config: Result<Config, Error> = with_env(vars, || Config::resolve(&cli));
+
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    /// Run `f` with environment variables set (without the prefix)
+    fn with_env<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+        let _guard: MutexGuard<'static, ()> = lock_env();
+        for (name, value) in vars.iter() {
+            env::set_var(env_name(name), value);
+        }
+        let result: T = f();
+        for (name, _) in vars.iter() {
+            env::remove_var(env_name(name));
+        }
+        result
+    }
+
+    /// Default config, with a base path in the temp dir
+    pub fn default_config() -> Config {
+        resolve("base_path = \"/tmp/ifuse-automount-test\"", &[], &[]).unwrap()
+    }
+
+    #[test]
+    fn parse_config_file() {
+        let config: Config = resolve(
+            r#"
+            base_path = "/media/apple"
+            mount_mode = "documents:com.example.app"
+            mount_options = ["noatime"]
+            deny = ["00008030-001A2C3E0E38802E"]
+            extra_product_ids = [0x12ab]
+            dir_mode = 0o750
+
+            [device."00008030-001A2C3E0E38802E"]
+            read_only = true
+            "#,
+            &[],
+            &[],
+        )
+        .unwrap();
+
+        assert_eq!(config.base_path, PathBuf::from("/media/apple"));
+        assert_eq!(config.base_path_source, "config file");
+        assert_eq!(
+            config.mount_mode,
+            MountMode::Documents(String::from("com.example.app"))
+        );
+        assert_eq!(config.mount_options, vec![String::from("noatime")]);
+        // UDIDs, stored as the USB serial numbers
+        assert_eq!(config.deny, vec![String::from("00008030001A2C3E0E38802E")]);
+        assert_eq!(config.extra_product_ids, vec![0x12ab]);
+        assert_eq!(config.dir_mode, Some(0o750));
+        assert!(config.device("00008030001A2C3E0E38802E").read_only);
+        assert!(!config.device("00008101000A1B2C3D4E5F60").read_only);
+    }
+
+    #[test]
+    fn reject_invalid_config_file() {
+        // Unknown field
+        assert!(matches!(
+            resolve("base_pth = \"/media/apple\"", &[], &[]),
+            Err(Error::InvalidConfig(..))
+        ));
+
+        // Wrong type
+        assert!(matches!(
+            resolve("settle_delay_ms = \"fast\"", &[], &[]),
+            Err(Error::InvalidConfig(..))
+        ));
+
+        // Invalid value
+        assert!(matches!(
+            resolve("mount_mode = \"everything\"", &[], &[]),
+            Err(Error::InvalidConfig(..))
+        ));
+        assert!(matches!(
+            resolve("mount_options = [\"uid=0,gid=0\"]", &[], &[]),
+            Err(Error::InvalidSetting(..))
+        ));
+    }
+
+    #[test]
+    fn cli_over_env_over_file_over_default() {
+        let settle_delay = |file: &str, args: &[&str], vars: &[(&str, &str)]| -> Duration {
+            resolve(file, args, vars).unwrap().settle_delay
+        };
+
+        let file: &str = "settle_delay_ms = 100";
+        let env: &[(&str, &str)] = &[("SETTLE_DELAY_MS", "200")];
+        let args: &[&str] = &["--settle-delay-ms", "300"];
+
+        assert_eq!(settle_delay("", &[], &[]), DEFAULT_SETTLE_DELAY);
+        assert_eq!(settle_delay(file, &[], &[]), Duration::from_millis(100));
+        assert_eq!(settle_delay(file, &[], env), Duration::from_millis(200));
+        assert_eq!(settle_delay(file, args, env), Duration::from_millis(300));
+        assert_eq!(settle_delay("", args, &[]), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn base_path_source() {
+        let file: &str = "base_path = \"/media/file\"";
+        let env: &[(&str, &str)] = &[("BASE_PATH", "/media/env")];
+        let args: &[&str] = &["--base-path", "/media/cli"];
+
+        let config: Config = resolve(file, &[], &[]).unwrap();
+        assert_eq!(config.base_path, PathBuf::from("/media/file"));
+        assert_eq!(config.base_path_source, "config file");
+
+        let config: Config = resolve(file, &[], env).unwrap();
+        assert_eq!(config.base_path, PathBuf::from("/media/env"));
+        assert_eq!(config.base_path_source, "IFUSE_AUTOMOUNT_BASE_PATH");
+
+        let config: Config = resolve(file, args, env).unwrap();
+        assert_eq!(config.base_path, PathBuf::from("/media/cli"));
+        assert_eq!(config.base_path_source, "--base-path");
+    }
+
+    #[test]
+    fn parse_env_vars() {
+        with_env(
+            &[
+                ("SETTLE_DELAY_MS", "250"),
+                ("PAIR", "false"),
+                ("NAMING", "model"),
+                ("DIR_MODE", "0750"),
+                ("EXTRA_PRODUCT_IDS", "0x12ab, 4661"),
+                ("DENY", "a, b"),
+                ("MOUNT_TEMPLATE", ""),
+            ],
+            || {
+                assert_eq!(env_parse::<u64>("SETTLE_DELAY_MS").unwrap(), Some(250));
+                assert_eq!(env_parse::<bool>("PAIR").unwrap(), Some(false));
+                assert_eq!(env_parse::<Naming>("NAMING").unwrap(), Some(Naming::Model));
+                assert_eq!(env_mode("DIR_MODE").unwrap(), Some(0o750));
+                assert_eq!(
+                    env_id_list("EXTRA_PRODUCT_IDS").unwrap(),
+                    Some(vec![0x12ab, 4661])
+                );
+                assert_eq!(
+                    env_list("DENY").unwrap(),
+                    Some(vec![String::from("a"), String::from("b")])
+                );
+
+                // Empty or unset: ignored
+                assert_eq!(env_var("MOUNT_TEMPLATE").unwrap(), None);
+                assert_eq!(env_parse::<u64>("DEBOUNCE_MS").unwrap(), None);
+            },
+        );
+    }
+
+    #[test]
+    fn reject_invalid_env_vars() {
+        let is_invalid = |result: Result<(), Error>, var: &str| -> bool {
+            matches!(result, Err(Error::InvalidSetting(name, _)) if name == env_name(var))
+        };
+
+        with_env(
+            &[
+                ("SETTLE_DELAY_MS", "soon"),
+                ("PAIR", "yes please"),
+                ("MOUNT_RETRIES", "-1"),
+                ("DIR_MODE", "0758"),
+                ("EXTRA_VENDOR_IDS", "0x05ac,apple"),
+            ],
+            || {
+                assert!(is_invalid(
+                    env_parse::<u64>("SETTLE_DELAY_MS").map(drop),
+                    "SETTLE_DELAY_MS"
+                ));
+                assert!(is_invalid(env_parse::<bool>("PAIR").map(drop), "PAIR"));
+                assert!(is_invalid(
+                    env_parse::<u32>("MOUNT_RETRIES").map(drop),
+                    "MOUNT_RETRIES"
+                ));
+                assert!(is_invalid(env_mode("DIR_MODE").map(drop), "DIR_MODE"));
+                assert!(is_invalid(
+                    env_id_list("EXTRA_VENDOR_IDS").map(drop),
+                    "EXTRA_VENDOR_IDS"
+                ));
+            },
+        );
+
+        // Out of range
+        assert!(is_invalid(
+            resolve("", &[], &[("USB_TIMEOUT_SECS", "0")]).map(drop),
+            "USB_TIMEOUT_SECS"
+        ));
+        // Also with a valid value in the file
+        assert!(is_invalid(
+            resolve("mount_retries = 2", &[], &[("MOUNT_RETRIES", "two")]).map(drop),
+            "MOUNT_RETRIES"
+        ));
+    }
+
+    #[test]
+    fn deny_beats_allow() {
+        let config: Config = resolve(
+            r#"
+            allow = ["00008030-001A2C3E0E38802E", "00008101-000A1B2C3D4E5F60"]
+            deny = ["00008030-001A2C3E0E38802E"]
+            "#,
+            &[],
+            &[],
+        )
+        .unwrap();
+
+        // UDIDs in the config, USB serial numbers from the devices
+        assert!(config.is_denied("00008030001A2C3E0E38802E"));
+        assert!(!config.is_allowed("00008030001A2C3E0E38802E"));
+        assert!(config.is_allowed("00008101000A1B2C3D4E5F60"));
+        // Not in the allow list
+        assert!(!config.is_allowed("000011112222333344445555"));
+
+        // Without an allow list, everything not denied is allowed
+        let config: Config = resolve("deny = [\"00008030-001A2C3E0E38802E\"]", &[], &[]).unwrap();
+        assert!(!config.is_allowed("00008030001A2C3E0E38802E"));
+        assert!(config.is_allowed("000011112222333344445555"));
+
+        // From the environment too
+        let config: Config = resolve("", &[], &[("DENY", "00008101-000A1B2C3D4E5F60")]).unwrap();
+        assert!(config.is_denied("00008101000A1B2C3D4E5F60"));
+    }
+
+    #[test]
+    fn check_mount_options_chars() {
+        let check = |options: &[&str]| -> bool {
+            check_mount_options(
+                "mount_options",
+                options.iter().map(|o| o.to_string()).collect(),
+            )
+            .is_ok()
+        };
+
+        assert!(check(&[]));
+        assert!(check(&["noatime", "uid=1000", "umask=022"]));
+        // One option each
+        assert!(!check(&["ro,allow_other"]));
+        assert!(!check(&[""]));
+        assert!(!check(&["ro allow_other"]));
+        assert!(!check(&["$(reboot)"]));
+        assert!(!check(&["ro\n"]));
+    }
+}
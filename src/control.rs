@@ -0,0 +1,112 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Control socket
+//!
+//! Line-based protocol: the client sends a command, the daemon replies with a JSON object and closes the connection.
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::rescan::Rescanner;
+use crate::state::State;
+use crate::{lock, Error};
+
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: String,
+}
+
+/// Control socket path, one per base path (like the instance lock):
+/// `<runtime-dir>/ifuse-automount-<hash of the base path>.sock`
+#[inline]
+pub fn socket_path(base_path: &Path) -> Result<PathBuf, Error> {
+    lock::instance_file(base_path, "sock")
+}
+
+/// Serve the control socket of the base path in a dedicated thread
+pub fn spawn(base_path: &Path, state: State, rescanner: Rescanner) -> Result<(), Error> {
+    let path: PathBuf = socket_path(base_path)?;
+
+    // Remove the stale socket left by a previous instance
+    if path.exists() {
+        if UnixStream::connect(&path).is_ok() {
+            tracing::warn!(
+                "Control socket {} already in use, control socket disabled",
+                path.display()
+            );
+            return Ok(());
+        }
+
+        fs::remove_file(&path).map_err(Error::fs("remove", &path))?;
+    }
+
+    let listener: UnixListener = UnixListener::bind(&path).map_err(Error::fs("bind", &path))?;
+    tracing::debug!("Control socket listening at {}", path.display());
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_client(stream, &state, &rescanner) {
+                        tracing::warn!("Control socket: {}", e.report());
+                    }
+                }
+                Err(e) => tracing::warn!("Control socket: {e}"),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn handle_client(stream: UnixStream, state: &State, rescanner: &Rescanner) -> Result<(), Error> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    // Read command
+    let mut command: String = String::new();
+    BufReader::new(&stream).read_line(&mut command)?;
+
+    let mut writer: &UnixStream = &stream;
+
+    match command.trim() {
+        "status" => serde_json::to_writer(&mut writer, &state.status())?,
+        "rescan" => match rescanner.rescan() {
+            Ok(result) => serde_json::to_writer(&mut writer, &result)?,
+            Err(e) => serde_json::to_writer(&mut writer, &ErrorResponse { error: e.report() })?,
+        },
+        command => serde_json::to_writer(
+            &mut writer,
+            &ErrorResponse {
+                error: format!("unknown command: {command}"),
+            },
+        )?,
+    }
+
+    writer.write_all(b"\n")?;
+
+    Ok(())
+}
+
+/// Send a command to the daemon running on the base path and return the response
+pub fn request(base_path: &Path, command: &str) -> Result<String, Error> {
+    let path: PathBuf = socket_path(base_path)?;
+    let mut stream: UnixStream = UnixStream::connect(path).map_err(|_| Error::DaemonNotRunning)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+
+    writeln!(stream, "{command}")?;
+
+    let mut response: String = String::new();
+    stream.read_to_string(&mut response)?;
+
+    Ok(response)
+}
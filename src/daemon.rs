@@ -0,0 +1,84 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Daemonization
+
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::{env, io, process};
+
+use crate::{lock, Error};
+
+/// Detach from the terminal: double fork, new session and stdio redirected to `/dev/null`
+///
+/// Only the calling thread survives a fork, so this must be called before spawning any thread
+/// and before creating the libusb context.
+pub fn daemonize() -> Result<(), Error> {
+    // First fork: the child is not a process group leader, so it can create a new session
+    fork_and_exit_parent()?;
+
+    // New session, without controlling terminal
+    if unsafe { libc::setsid() } < 0 {
+        return Err(Error::Io(io::Error::last_os_error()));
+    }
+
+    // Second fork: the child is not a session leader, so it can't acquire a terminal again
+    fork_and_exit_parent()?;
+
+    // Don't keep the working directory busy
+    env::set_current_dir("/")?;
+
+    redirect_stdio()
+}
+
+fn fork_and_exit_parent() -> Result<(), Error> {
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::Io(io::Error::last_os_error())),
+        0 => Ok(()),
+        _ => process::exit(0),
+    }
+}
+
+fn redirect_stdio() -> Result<(), Error> {
+    let null: File = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(Error::Io(io::Error::last_os_error()));
+        }
+    }
+
+    Ok(())
+}
+
+/// PID file, one per base path (like the instance lock): `<runtime-dir>/ifuse-automount-<hash of the base path>.pid`
+///
+/// Removed on drop.
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Write the PID of the current process
+    pub fn create(base_path: &Path) -> Result<Self, Error> {
+        let path: PathBuf = lock::instance_file(base_path, "pid")?;
+
+        fs::write(&path, format!("{}\n", process::id())).map_err(Error::fs("write", &path))?;
+        tracing::debug!("PID file written at {}", path.display());
+
+        Ok(Self { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            tracing::warn!("Can't remove {}: {e}", self.path.display());
+        }
+    }
+}
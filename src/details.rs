@@ -0,0 +1,112 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Device details, with `ideviceinfo` (best-effort)
+
+use std::process::{Command, Output, Stdio};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{child, udid};
+
+/// Short, so that a hung lockdownd doesn't hold the other devices
+const TIMEOUT: Duration = Duration::from_secs(2);
+const BATTERY_DOMAIN: &str = "com.apple.mobile.battery";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DeviceDetails {
+    /// e.g. `iPhone14,5`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_type: Option<String>,
+    /// iOS version
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub product_version: Option<String>,
+    /// Name given by the owner
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_name: Option<String>,
+    /// Battery level, in percent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery: Option<u8>,
+}
+
+impl DeviceDetails {
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    /// Short description, e.g. `iPhone14,5, iOS 17.1, "Yuki's iPhone", battery 80%`
+    pub fn summary(&self) -> String {
+        let mut parts: Vec<String> = Vec::with_capacity(4);
+        if let Some(product_type) = &self.product_type {
+            parts.push(product_type.clone());
+        }
+        if let Some(version) = &self.product_version {
+            parts.push(format!("iOS {version}"));
+        }
+        if let Some(name) = &self.device_name {
+            parts.push(format!("\"{name}\""));
+        }
+        if let Some(battery) = self.battery {
+            parts.push(format!("battery {battery}%"));
+        }
+        parts.join(", ")
+    }
+}
+
+/// Query the details of the device
+///
+/// Failures are only logged: the missing values are left empty.
+pub fn query(serial_number: &str) -> DeviceDetails {
+    // `ideviceinfo -u <udid>`
+    let values: Vec<(String, String)> = ideviceinfo(serial_number, &[]);
+    let value = |key: &str| -> Option<String> {
+        values
+            .iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.clone())
+    };
+
+    // `ideviceinfo -u <udid> -q com.apple.mobile.battery -k BatteryCurrentCapacity`
+    let battery: Option<u8> = ideviceinfo(
+        serial_number,
+        &["-q", BATTERY_DOMAIN, "-k", "BatteryCurrentCapacity"],
+    )
+    .into_iter()
+    .find_map(|(_, value)| value.parse().ok());
+
+    DeviceDetails {
+        product_type: value("ProductType"),
+        product_version: value("ProductVersion"),
+        device_name: value("DeviceName"),
+        battery,
+    }
+}
+
+/// Run `ideviceinfo` and parse its `Key: Value` lines (or the bare value, with `-k`)
+fn ideviceinfo(serial_number: &str, args: &[&str]) -> Vec<(String, String)> {
+    let mut cmd: Command = Command::new("ideviceinfo");
+    cmd.arg("-u")
+        .arg(udid(serial_number))
+        .args(args)
+        .stdout(Stdio::piped());
+
+    let output: Output = match child::output(&mut cmd, TIMEOUT) {
+        Ok(output) if output.status.success() => output,
+        Ok(..) => return Vec::new(),
+        Err(e) => {
+            tracing::debug!("Can't query the details of {serial_number}: {}", e.report());
+            return Vec::new();
+        }
+    };
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| match line.split_once(": ") {
+            Some((key, value)) => Some((key.trim().to_string(), value.trim().to_string())),
+            None if !line.trim().is_empty() => Some((String::new(), line.trim().to_string())),
+            None => None,
+        })
+        .collect()
+}
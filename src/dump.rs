@@ -0,0 +1,116 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! State dump on SIGUSR1, for debugging a wedged daemon
+//!
+//! Built from the shared state rather than by the handler thread, which may be the stuck one, and logged
+//! as a single multi-line entry (kept together by journald).
+
+use std::thread;
+use std::time::Instant;
+
+use signal_hook::consts::SIGUSR1;
+use signal_hook::iterator::Signals;
+
+use crate::cancel::{InFlight, InFlightMount};
+use crate::state::{self, MountedDevice, RecentError, ScheduledRetry, State, Status};
+use crate::status::{counters_summary, format_secs};
+use crate::{join_paths, Error};
+
+/// Log the state dump on SIGUSR1
+pub fn spawn_listener(state: State, in_flight: InFlight) -> Result<(), Error> {
+    let mut signals: Signals = Signals::new([SIGUSR1])?;
+
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            tracing::info!(
+                "Received SIGUSR1, state dump:\n{}",
+                dump(&state, &in_flight)
+            );
+        }
+    });
+
+    Ok(())
+}
+
+fn dump(state: &State, in_flight: &InFlight) -> String {
+    let now: u64 = state::now();
+    let status: Status = state.status();
+    let mounts: Vec<InFlightMount> = in_flight.list();
+    let retries: Vec<ScheduledRetry> = state.retries();
+    let errors: Vec<RecentError> = state.recent_errors();
+    let mut lines: Vec<String> = Vec::new();
+
+    lines.push(format!(
+        "Running for {}",
+        format_secs(now.saturating_sub(status.started_at))
+    ));
+    lines.push(counters_summary(&status.counters));
+
+    lines.push(format!("Mounted devices: {}", status.devices.len()));
+    for device in status.devices.iter() {
+        lines.push(device_line(device, now));
+    }
+
+    lines.push(format!(
+        "Pending unmounts (mountpoint busy): {}",
+        status.pending_unmounts.len()
+    ));
+    for device in status.pending_unmounts.iter() {
+        lines.push(device_line(device, now));
+    }
+
+    lines.push(format!("Mounts in progress: {}", mounts.len()));
+    for mount in mounts.iter() {
+        lines.push(format!(
+            "  {:03}:{:03}: {} (started {} ms ago)",
+            mount.addr.bus,
+            mount.addr.addr,
+            mount.stage,
+            mount.started_at.elapsed().as_millis()
+        ));
+    }
+
+    lines.push(format!("Queued device events: {}", status.queued));
+
+    lines.push(format!("Scheduled retries: {}", retries.len()));
+    for retry in retries.iter() {
+        let due: u128 = retry
+            .due
+            .saturating_duration_since(Instant::now())
+            .as_millis();
+        match retry.trust_pending {
+            true => lines.push(format!(
+                "  {}: waiting for the trust dialog, next attempt in {due} ms",
+                retry.serial_number
+            )),
+            false => lines.push(format!(
+                "  {}: retry {} in {due} ms",
+                retry.serial_number, retry.attempt
+            )),
+        }
+    }
+
+    lines.push(format!("Last errors: {}", errors.len()));
+    for error in errors.iter() {
+        lines.push(format!(
+            "  {} ago: {}",
+            format_secs(now.saturating_sub(error.at)),
+            error.message
+        ));
+    }
+
+    lines.join("\n")
+}
+
+fn device_line(device: &MountedDevice, now: u64) -> String {
+    format!(
+        "  {} ({:03}:{:03}), mounted for {} (at {}): {}",
+        device.serial_number,
+        device.bus,
+        device.address,
+        format_secs(now.saturating_sub(device.mounted_at)),
+        device.mounted_at,
+        join_paths(&device.mountpoints)
+    )
+}
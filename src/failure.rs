@@ -0,0 +1,177 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Classification of the ifuse failures, from the messages of ifuse, libimobiledevice and FUSE
+
+use std::path::Path;
+
+use crate::config::MountMode;
+use crate::Error;
+
+/// Parse the stderr of a failed ifuse run
+///
+/// Unknown messages are kept as they are, in [`Error::CantMount`].
+pub fn classify(serial_number: &str, mode: &MountMode, path: &Path, stderr: &str) -> Error {
+    let message: &str = stderr.trim();
+    let lowercase: String = message.to_lowercase();
+    let serial_number: String = serial_number.to_string();
+
+    // The AFC2 service is installed only on jailbroken devices
+    if *mode == MountMode::Root && lowercase.contains("afc2") {
+        return Error::Afc2NotAvailable;
+    }
+
+    // Stale pairing record (e.g. after a restore)
+    if is_invalid_pairing(&lowercase) {
+        return Error::InvalidPairing(serial_number);
+    }
+
+    // `LOCKDOWN_E_PAIRING_DIALOG_RESPONSE_PENDING`: "Please dismiss the trust dialog on your device and try again."
+    // (i.e. idevicepair not installed or pairing disabled)
+    if lowercase.contains("trust") || lowercase.contains("pairingdialogresponsepending") {
+        return Error::PairingPending(serial_number);
+    }
+
+    // `LOCKDOWN_E_PASSWORD_PROTECTED`: "Please disable the password protection on your device and try again."
+    if lowercase.contains("password protect")
+        || lowercase.contains("passwordprotected")
+        || lowercase.contains("passcode")
+    {
+        return Error::DeviceLocked(serial_number);
+    }
+
+    // "ERROR: Device <udid> is not paired with this host"
+    if lowercase.contains("not paired") || lowercase.contains("pairing record") {
+        return Error::NotPaired(serial_number);
+    }
+
+    // "fuse: missing mountpoint parameter", "fuse: bad mount point `<path>': No such file or directory"
+    if lowercase.contains("missing mountpoint") || lowercase.contains("bad mount point") {
+        return Error::MountpointMissing(path.to_path_buf());
+    }
+
+    // "fuse: device not found, try 'modprobe fuse' first", "fuse: failed to open /dev/fuse: Permission denied",
+    // "fusermount: mountpoint is not empty", ...
+    if lowercase.starts_with("fuse:")
+        || lowercase.starts_with("fusermount")
+        || lowercase.contains("/dev/fuse")
+    {
+        return Error::Fuse(message.to_string());
+    }
+
+    Error::CantMount(message.to_string())
+}
+
+/// Check if the (lowercase) message is about a pairing record rejected by the device
+#[inline]
+pub fn is_invalid_pairing(message: &str) -> bool {
+    message.contains("invalidhostid")
+        || message.contains("invalid_host_id")
+        || message.contains("invalid host id")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SERIAL_NUMBER: &str = "00008030001A2C3E0E38802E";
+    const MOUNTPOINT: &str = "/media/ifuse/00008030001A2C3E0E38802E";
+
+    const AFC2: &str = "Failed to start AFC service 'com.apple.afc2' on the device.
+This service enables access to the root filesystem of your device.
+Your device needs to be jailbroken and have the AFC2 service installed.
+";
+    const INVALID_HOST_ID: &str = "ERROR: Could not connect to lockdownd: InvalidHostID (-21)\n";
+    const TRUST_PENDING: &str = "Please dismiss the trust dialog on your device and try again.
+The device does not allow pairing as long as the dialog has not been accepted.
+";
+    const PASSWORD_PROTECTED: &str =
+        "Please disable the password protection on your device and try again.
+The device does not allow pairing as long as a password has been set.
+You can enable it again after the connection succeeded.
+";
+    const NOT_PAIRED: &str =
+        "ERROR: Device 00008030-001A2C3E0E38802E is not paired with this host\n";
+    const BAD_MOUNT_POINT: &str = "fuse: bad mount point `/media/ifuse/00008030001A2C3E0E38802E': No such file or directory\n";
+    const NO_FUSE_MODULE: &str = "fuse: device not found, try 'modprobe fuse' first\n";
+    const NO_FUSE_ACCESS: &str = "fusermount3: failed to open /dev/fuse: Permission denied\n";
+    const LOCKDOWN_FAILED: &str = "Failed to connect to lockdownd service on the device.
+Try again. If it still fails try rebooting your device.
+";
+
+    fn classify_media(stderr: &str) -> Error {
+        classify(
+            SERIAL_NUMBER,
+            &MountMode::Media,
+            Path::new(MOUNTPOINT),
+            stderr,
+        )
+    }
+
+    #[test]
+    fn afc2_not_available() {
+        let e: Error = classify(SERIAL_NUMBER, &MountMode::Root, Path::new(MOUNTPOINT), AFC2);
+        assert!(matches!(e, Error::Afc2NotAvailable));
+
+        // Only in root mode
+        assert!(matches!(classify_media(AFC2), Error::CantMount(..)));
+    }
+
+    #[test]
+    fn invalid_pairing() {
+        assert!(matches!(
+            classify_media(INVALID_HOST_ID),
+            Error::InvalidPairing(serial_number) if serial_number == SERIAL_NUMBER
+        ));
+    }
+
+    #[test]
+    fn pairing_pending() {
+        assert!(matches!(
+            classify_media(TRUST_PENDING),
+            Error::PairingPending(serial_number) if serial_number == SERIAL_NUMBER
+        ));
+    }
+
+    #[test]
+    fn device_locked() {
+        assert!(matches!(
+            classify_media(PASSWORD_PROTECTED),
+            Error::DeviceLocked(serial_number) if serial_number == SERIAL_NUMBER
+        ));
+    }
+
+    #[test]
+    fn not_paired() {
+        assert!(matches!(
+            classify_media(NOT_PAIRED),
+            Error::NotPaired(serial_number) if serial_number == SERIAL_NUMBER
+        ));
+    }
+
+    #[test]
+    fn mountpoint_missing() {
+        assert!(matches!(
+            classify_media(BAD_MOUNT_POINT),
+            Error::MountpointMissing(path) if path == Path::new(MOUNTPOINT)
+        ));
+    }
+
+    #[test]
+    fn fuse_error() {
+        for stderr in [NO_FUSE_MODULE, NO_FUSE_ACCESS] {
+            assert!(matches!(
+                classify_media(stderr),
+                Error::Fuse(message) if message == stderr.trim()
+            ));
+        }
+    }
+
+    #[test]
+    fn unknown_message() {
+        assert!(matches!(
+            classify_media(LOCKDOWN_FAILED),
+            Error::CantMount(message) if message == LOCKDOWN_FAILED.trim()
+        ));
+    }
+}
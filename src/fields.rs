@@ -0,0 +1,133 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Fields of the events and of their spans, for the log layers writing them as key-value pairs
+
+use std::fmt;
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::{Extensions, LookupSpan, SpanRef};
+
+/// Message and fields of an event, the ones of its spans included
+#[derive(Debug, Default)]
+pub struct EventFields {
+    pub message: String,
+    /// Outermost span first, the event fields last
+    pub fields: Vec<(String, String)>,
+}
+
+impl EventFields {
+    pub fn collect<S>(event: &Event<'_>, ctx: &Context<'_, S>) -> Self
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        let mut fields: Vec<(String, String)> = Vec::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                if let Some(span_fields) = span.extensions().get::<SpanFields>() {
+                    fields.extend(span_fields.0.iter().cloned());
+                }
+            }
+        }
+        event.record(&mut FieldVisitor(&mut fields));
+
+        let message: String = match fields.iter().position(|(name, _)| name == "message") {
+            Some(index) => fields.remove(index).1,
+            None => String::new(),
+        };
+
+        Self { message, fields }
+    }
+
+    /// `<message> key=value ...`
+    pub fn to_line(&self) -> String {
+        let mut line: String = self.message.clone();
+        for (name, value) in self.fields.iter() {
+            line.push_str(&format!(" {name}={value}"));
+        }
+        line
+    }
+}
+
+/// Keep the fields of a new span, for its events
+pub fn on_new_span<S>(attrs: &Attributes<'_>, id: &Id, ctx: &Context<'_, S>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if let Some(span) = ctx.span(id) {
+        let mut fields: SpanFields = SpanFields::default();
+        attrs.record(&mut FieldVisitor(&mut fields.0));
+        span.extensions_mut().insert(fields);
+    }
+}
+
+/// Fields recorded after the creation of the span (e.g. the serial number, once read)
+pub fn on_record<S>(id: &Id, values: &Record<'_>, ctx: &Context<'_, S>)
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    if let Some(span) = ctx.span(id) {
+        if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+            values.record(&mut FieldVisitor(&mut fields.0));
+        }
+    }
+}
+
+/// Value of a field of a span, if recorded
+pub fn span_field<R>(span: &SpanRef<'_, R>, name: &str) -> Option<String>
+where
+    R: for<'a> LookupSpan<'a>,
+{
+    let extensions: Extensions<'_> = span.extensions();
+    let fields: &SpanFields = extensions.get::<SpanFields>()?;
+    fields
+        .0
+        .iter()
+        .find(|(field, _)| field == name)
+        .map(|(_, value)| value.clone())
+}
+
+/// Keep the fields of the spans for a layer that doesn't (e.g. the `fmt` one), see [`span_field`]
+pub struct SpanFieldsLayer;
+
+impl<S> Layer<S> for SpanFieldsLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        on_new_span(attrs, id, &ctx);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        on_record(id, values, &ctx);
+    }
+}
+
+/// Fields of a span
+#[derive(Default)]
+struct SpanFields(Vec<(String, String)>);
+
+struct FieldVisitor<'a>(&'a mut Vec<(String, String)>);
+
+impl FieldVisitor<'_> {
+    /// A field recorded again replaces the previous value
+    fn push(&mut self, field: &Field, value: String) {
+        match self.0.iter_mut().find(|(name, _)| name == field.name()) {
+            Some(entry) => entry.1 = value,
+            None => self.0.push((field.name().to_string(), value)),
+        }
+    }
+}
+
+impl Visit for FieldVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.push(field, value.to_string());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.push(field, format!("{value:?}"));
+    }
+}
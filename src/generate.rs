@@ -0,0 +1,351 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Integration files: systemd service unit, udev rules
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{self, Path, PathBuf};
+use std::process::{Command, Output};
+use std::time::Duration;
+
+use crate::cli::Cli;
+use crate::config::{self, Config};
+use crate::logger::{ColorChoice, LogFormat, LogTarget};
+use crate::{child, Error, APPLE_PRODUCT_IDS, APPLE_VENDOR_ID};
+
+const UNIT_NAME: &str = "ifuse-automount.service";
+const SYSTEM_UNIT_DIR: &str = "/etc/systemd/system";
+
+/// Before `73-seat-late.rules`, which applies the `uaccess` tag
+const UDEV_RULES_PATH: &str = "/etc/udev/rules.d/70-ifuse-automount.rules";
+const UDEVADM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Margin of the systemd watchdog over `command_timeout` (a step of a mount)
+const WATCHDOG_MARGIN: Duration = Duration::from_secs(30);
+
+/// Print the systemd unit running the daemon with the same flags, or install it
+///
+/// A user unit (also with `user_unit`, from `--user`), or a system unit with `--system`.
+pub fn systemd(args: &Cli, user_unit: bool, install: bool) -> Result<(), Error> {
+    // Fails early on an invalid config, as the service would
+    let config: Config = Config::resolve(args)?;
+
+    // `--system` conflicts with `--user`, but system mode may also come from the env or the config file
+    if user_unit && config.system {
+        return Err(Error::InvalidSetting(
+            String::from("system"),
+            String::from("a user unit (`--user`) can't run in system mode"),
+        ));
+    }
+    let unit: String = systemd_unit(args, &config)?;
+
+    if !install {
+        print!("{unit}");
+        return Ok(());
+    }
+
+    let dir: PathBuf = match config.system {
+        true => PathBuf::from(SYSTEM_UNIT_DIR),
+        false => dirs::config_dir()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no config directory"))?
+            .join("systemd")
+            .join("user"),
+    };
+    fs::create_dir_all(&dir).map_err(Error::fs("create", &dir))?;
+    let path: PathBuf = dir.join(UNIT_NAME);
+    fs::write(&path, unit).map_err(Error::fs("write", &path))?;
+
+    let systemctl: &str = match config.system {
+        true => "systemctl",
+        false => "systemctl --user",
+    };
+    println!("Installed {}", path.display());
+    println!(
+        "Enable it with: {systemctl} daemon-reload && {systemctl} enable --now ifuse-automount"
+    );
+
+    Ok(())
+}
+
+fn systemd_unit(args: &Cli, config: &Config) -> Result<String, Error> {
+    let mut exec_start: Vec<String> = vec![env::current_exe()?.display().to_string()];
+    exec_start.extend(daemon_args(args)?);
+    let exec_start: String = exec_start
+        .iter()
+        .map(|arg| quote(arg))
+        .collect::<Vec<String>>()
+        .join(" ");
+
+    let watchdog: u64 = (config.command_timeout + WATCHDOG_MARGIN).as_secs();
+
+    let mut unit: String = String::new();
+    unit.push_str("[Unit]\n");
+    unit.push_str(&format!("Description={}\n", env!("CARGO_PKG_DESCRIPTION")));
+    unit.push_str(&format!("Documentation={}\n", env!("CARGO_PKG_HOMEPAGE")));
+    if config.system {
+        // ifuse runs as the user of the active session
+        unit.push_str("After=systemd-logind.service usbmuxd.service\n");
+    }
+
+    unit.push_str("\n[Service]\n");
+    unit.push_str("Type=notify\n");
+    unit.push_str(&format!("ExecStart={exec_start}\n"));
+    unit.push_str("ExecReload=/bin/kill -HUP $MAINPID\n");
+    unit.push_str("Restart=on-failure\n");
+    unit.push_str("RestartSec=5\n");
+    // Restarting doesn't help: ifuse or the unmount command missing, invalid command line or config
+    unit.push_str("RestartPreventExitStatus=2 3 64 78\n");
+    unit.push_str(&format!(
+        "# Above command_timeout_secs ({} s), so a slow mount isn't mistaken for a hang\n",
+        config.command_timeout.as_secs()
+    ));
+    unit.push_str(&format!("WatchdogSec={watchdog}\n"));
+    if config.system {
+        unit.push_str(concat!(
+            "# Runs as root, mounting under the base path as the user of the active session\n",
+            "# (or `--user`): requires `user_allow_other` in /etc/fuse.conf, so that all the\n",
+            "# users can read the mounts\n",
+        ));
+    }
+
+    unit.push_str("\n[Install]\n");
+    match config.system {
+        true => unit.push_str("WantedBy=multi-user.target\n"),
+        false => unit.push_str("WantedBy=default.target\n"),
+    }
+
+    Ok(unit)
+}
+
+/// Flags given to this command that the daemon understands, with absolute paths
+fn daemon_args(args: &Cli) -> Result<Vec<String>, Error> {
+    let mut daemon_args: Vec<String> = Vec::new();
+
+    if let Some(path) = &args.config {
+        daemon_args.push(String::from("--config"));
+        daemon_args.push(absolute(path)?);
+    }
+    if args.system {
+        daemon_args.push(String::from("--system"));
+    }
+    if let Some(path) = &args.base_path {
+        daemon_args.push(String::from("--base-path"));
+        daemon_args.push(absolute(path)?);
+    }
+    if let Some(template) = &args.mount_template {
+        daemon_args.push(String::from("--mount-template"));
+        daemon_args.push(template.clone());
+    }
+    if let Some(mode) = &args.mount_mode {
+        daemon_args.push(String::from("--mount-mode"));
+        daemon_args.push(mode.to_string());
+    }
+    if let Some(delay) = args.settle_delay_ms {
+        daemon_args.push(String::from("--settle-delay-ms"));
+        daemon_args.push(delay.to_string());
+    }
+    if let Some(timeout) = args.usb_timeout {
+        daemon_args.push(String::from("--usb-timeout"));
+        daemon_args.push(timeout.to_string());
+    }
+    if args.no_reset {
+        daemon_args.push(String::from("--no-reset"));
+    }
+    if let Some(path) = &args.ifuse_path {
+        daemon_args.push(String::from("--ifuse-path"));
+        daemon_args.push(absolute(path)?);
+    }
+    if let Some(user) = &args.user {
+        daemon_args.push(String::from("--user"));
+        daemon_args.push(user.clone());
+    }
+    if let Some(command) = &args.unmount_command {
+        daemon_args.push(String::from("--unmount-command"));
+        daemon_args.push(command.clone());
+    }
+    if !args.mount_options.is_empty() {
+        daemon_args.push(String::from("--mount-option"));
+        daemon_args.push(args.mount_options.join(","));
+    }
+    if args.read_only {
+        daemon_args.push(String::from("--read-only"));
+    }
+    if args.allow_other {
+        daemon_args.push(String::from("--allow-other"));
+    }
+    if let Some(addr) = args.metrics_listen {
+        daemon_args.push(String::from("--metrics-listen"));
+        daemon_args.push(addr.to_string());
+    }
+    if args.metrics_hash_serials {
+        daemon_args.push(String::from("--metrics-hash-serials"));
+    }
+    if args.verbose > 0 {
+        daemon_args.push(format!("-{}", "v".repeat(usize::from(args.verbose))));
+    }
+    if args.quiet {
+        daemon_args.push(String::from("--quiet"));
+    }
+    if let Some(target) = args.log_target {
+        daemon_args.push(String::from("--log-target"));
+        daemon_args.push(target.as_str().to_string());
+    }
+    if args.log_format != LogFormat::Human {
+        daemon_args.push(String::from("--log-format"));
+        daemon_args.push(args.log_format.as_str().to_string());
+    }
+    if args.color != ColorChoice::Auto {
+        daemon_args.push(String::from("--color"));
+        daemon_args.push(args.color.as_str().to_string());
+    }
+    if let Some(path) = &args.log_file {
+        daemon_args.push(String::from("--log-file"));
+        daemon_args.push(absolute(path)?);
+        daemon_args.push(String::from("--log-file-max-size"));
+        daemon_args.push(args.log_file_max_size.to_string());
+        daemon_args.push(String::from("--log-file-max-files"));
+        daemon_args.push(args.log_file_max_files.to_string());
+    }
+    if args.log_target == Some(LogTarget::Syslog) {
+        daemon_args.push(String::from("--syslog-identifier"));
+        daemon_args.push(args.syslog_identifier.clone());
+    }
+    if let Some(level) = args.log_level {
+        daemon_args.push(String::from("--log-level"));
+        daemon_args.push(level.to_string().to_lowercase());
+    }
+
+    Ok(daemon_args)
+}
+
+/// The working directory of the service is `/`
+fn absolute(path: &Path) -> Result<String, Error> {
+    let path: PathBuf = path::absolute(config::expand_tilde(path.to_path_buf()))?;
+    Ok(path.display().to_string())
+}
+
+/// Quote an `ExecStart=` argument, if needed
+///
+/// `%` (specifiers) and `$` (environment variables) are escaped in any case.
+fn quote(arg: &str) -> String {
+    let escaped: String = arg.replace('%', "%%").replace('$', "$$");
+    let needs_quotes: bool = escaped.is_empty()
+        || escaped
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'));
+    if !needs_quotes {
+        return escaped;
+    }
+
+    format!("\"{}\"", escaped.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Print the udev rules giving access to the handled devices, or install them (requires root)
+///
+/// Access for the user of the active session (`uaccess`), or for the members of `group`.
+pub fn udev(
+    args: &Cli,
+    group: Option<&str>,
+    any_product: bool,
+    install: bool,
+) -> Result<(), Error> {
+    if let Some(group) = group {
+        let is_valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+        if group.is_empty() || !group.chars().all(is_valid) {
+            return Err(Error::InvalidSetting(
+                String::from("group"),
+                format!("invalid group name '{group}'"),
+            ));
+        }
+    }
+
+    let config: Config = Config::resolve(args)?;
+    let rules: String = udev_rules(&config, group, any_product);
+
+    if !install {
+        print!("{rules}");
+        return Ok(());
+    }
+
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(Error::Io(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "installing the udev rules requires root",
+        )));
+    }
+
+    fs::write(UDEV_RULES_PATH, rules).map_err(Error::fs("write", Path::new(UDEV_RULES_PATH)))?;
+    println!("Installed {UDEV_RULES_PATH}");
+
+    // Apply them to the devices already connected
+    udevadm(&["control", "--reload-rules"])?;
+    udevadm(&["trigger", "--subsystem-match=usb", "--action=change"])?;
+    println!("udev rules reloaded, replug the devices if they are still not accessible");
+
+    Ok(())
+}
+
+fn udev_rules(config: &Config, group: Option<&str>, any_product: bool) -> String {
+    // Same devices as the daemon: built-in and extra vendor and product IDs
+    let mut vendor_ids: Vec<u16> = vec![APPLE_VENDOR_ID];
+    for vendor_id in config.extra_vendor_ids.iter() {
+        if !vendor_ids.contains(vendor_id) {
+            vendor_ids.push(*vendor_id);
+        }
+    }
+    let mut product_ids: Vec<u16> = APPLE_PRODUCT_IDS.to_vec();
+    for product_id in config.extra_product_ids.iter() {
+        if !product_ids.contains(product_id) {
+            product_ids.push(*product_id);
+        }
+    }
+
+    let access: String = match group {
+        Some(group) => format!("MODE=\"0660\", GROUP=\"{group}\""),
+        None => String::from("TAG+=\"uaccess\""),
+    };
+
+    let mut rules: String = String::new();
+    rules.push_str("# Generated by ifuse-automount: access to the USB devices it mounts\n");
+    match group {
+        Some(group) => rules.push_str(&format!("# for the members of the `{group}` group\n")),
+        None => rules.push_str("# for the user of the active session\n"),
+    }
+    if !any_product {
+        rules.push_str(
+            "# Devices newer than this release: add their product ID to `extra_product_ids`, or use --any-product\n",
+        );
+    }
+
+    for vendor_id in vendor_ids.iter() {
+        rules.push('\n');
+        if any_product {
+            rules.push_str(&format!(
+                "SUBSYSTEM==\"usb\", ENV{{DEVTYPE}}==\"usb_device\", ATTR{{idVendor}}==\"{vendor_id:04x}\", {access}\n"
+            ));
+            continue;
+        }
+
+        for product_id in product_ids.iter() {
+            rules.push_str(&format!(
+                "SUBSYSTEM==\"usb\", ENV{{DEVTYPE}}==\"usb_device\", ATTR{{idVendor}}==\"{vendor_id:04x}\", ATTR{{idProduct}}==\"{product_id:04x}\", {access}\n"
+            ));
+        }
+    }
+
+    rules
+}
+
+fn udevadm(args: &[&str]) -> Result<(), Error> {
+    let output: Output = child::output(Command::new("udevadm").args(args), UDEVADM_TIMEOUT)?;
+    if !output.status.success() {
+        return Err(Error::Io(io::Error::other(format!(
+            "udevadm {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))));
+    }
+    Ok(())
+}
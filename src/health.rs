@@ -0,0 +1,112 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Heartbeat file for the external monitoring (e.g. a file-age check)
+//!
+//! Refreshed by the main loop with the time and the number of mounted devices, only while the
+//! handler thread beats and libusb delivers its events. Removed on drop.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::state::{self, State};
+use crate::watchdog::Heartbeat;
+use crate::Error;
+
+/// Interval between the refreshes
+const HEALTH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub struct HealthFile {
+    path: PathBuf,
+    heartbeat: Heartbeat,
+    state: State,
+    /// Max time between two beats of a healthy handler
+    stale_after: Duration,
+    last_write: Option<Instant>,
+    /// Refreshes skipped
+    unhealthy: bool,
+}
+
+impl HealthFile {
+    /// A step of the handler (e.g. ifuse) lasts up to `command_timeout`
+    pub fn new(
+        path: PathBuf,
+        heartbeat: Heartbeat,
+        state: State,
+        command_timeout: Duration,
+    ) -> Self {
+        Self {
+            path,
+            heartbeat,
+            state,
+            stale_after: command_timeout + HEALTH_INTERVAL,
+            last_write: None,
+            unhealthy: false,
+        }
+    }
+
+    /// Refresh the file every [`HEALTH_INTERVAL`], unless the handler is stuck or `usb_failing`
+    pub fn tick(&mut self, usb_failing: bool) {
+        let due: bool = self
+            .last_write
+            .is_none_or(|last| last.elapsed() >= HEALTH_INTERVAL);
+        if !due {
+            return;
+        }
+
+        let age: Duration = self.heartbeat.age();
+        if age >= self.stale_after || usb_failing {
+            if !self.unhealthy {
+                tracing::warn!(
+                    "Not healthy (handler beat {} s ago, libusb failing: {usb_failing}), no longer refreshing {}",
+                    age.as_secs(),
+                    self.path.display()
+                );
+                self.unhealthy = true;
+            }
+            return;
+        }
+
+        if self.unhealthy {
+            tracing::info!("Healthy again, refreshing {}", self.path.display());
+            self.unhealthy = false;
+        }
+
+        if let Err(e) = write(&self.path, self.state.devices().len()) {
+            tracing::warn!("Can't write {}: {}", self.path.display(), e.report());
+        }
+        self.last_write = Some(Instant::now());
+    }
+}
+
+impl Drop for HealthFile {
+    fn drop(&mut self) {
+        if self.last_write.is_none() {
+            return;
+        }
+
+        if let Err(e) = fs::remove_file(&self.path) {
+            tracing::warn!("Can't remove {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// Write the time and the number of mounted devices
+///
+/// Atomic: written to a temporary file, then renamed.
+fn write(path: &Path, mounted: usize) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(Error::fs("create", parent))?;
+    }
+
+    let tmp: PathBuf = path.with_extension("tmp");
+    fs::write(
+        &tmp,
+        format!("timestamp={}\nmounted={mounted}\n", state::now()),
+    )
+    .map_err(Error::fs("write", &tmp))?;
+    fs::rename(&tmp, path).map_err(Error::fs("rename", &tmp))?;
+    Ok(())
+}
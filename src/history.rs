@@ -0,0 +1,216 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Mount history (`history_file`): one JSON object per mount and unmount, appended and synced
+//!
+//! Default: `~/.local/state/ifuse-automount/history.jsonl`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::mem::MaybeUninit;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::state;
+use crate::status::format_secs;
+use crate::Error;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Event {
+    Mount,
+    Unmount,
+}
+
+impl Event {
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Mount => "mount",
+            Self::Unmount => "unmount",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Ok,
+    Failed,
+}
+
+impl Outcome {
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    /// UNIX timestamp
+    pub timestamp: u64,
+    pub event: Event,
+    pub serial_number: String,
+    pub mountpoints: Vec<PathBuf>,
+    /// Time the device stayed mounted, on the unmounts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mounted_secs: Option<u64>,
+    pub outcome: Outcome,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl Entry {
+    pub fn new(event: Event, serial_number: &str, mountpoints: &[PathBuf]) -> Self {
+        Self {
+            timestamp: state::now(),
+            event,
+            serial_number: serial_number.to_string(),
+            mountpoints: mountpoints.to_vec(),
+            mounted_secs: None,
+            outcome: Outcome::Ok,
+            error: None,
+        }
+    }
+
+    /// Unmount of a device mounted at `mounted_at`
+    pub fn mounted_since(mut self, mounted_at: u64) -> Self {
+        self.mounted_secs = Some(self.timestamp.saturating_sub(mounted_at));
+        self
+    }
+
+    pub fn failed(mut self, e: &Error) -> Self {
+        self.outcome = Outcome::Failed;
+        self.error = Some(e.report());
+        self
+    }
+}
+
+/// Append an entry and sync it to the disk
+pub fn append(path: &Path, entry: &Entry) -> Result<(), Error> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(Error::fs("create", parent))?;
+    }
+
+    let mut line: String = serde_json::to_string(entry)?;
+    line.push('\n');
+
+    let mut file: File = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(Error::fs("open", path))?;
+    file.write_all(line.as_bytes())
+        .map_err(Error::fs("write", path))?;
+    file.sync_data().map_err(Error::fs("sync", path))?;
+    Ok(())
+}
+
+/// Entries of the history file, oldest first (the invalid lines are skipped)
+fn read(path: &Path) -> Result<Vec<Entry>, Error> {
+    let file: File = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::fs("read", path)(e)),
+    };
+
+    let mut entries: Vec<Entry> = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line: String = line.map_err(Error::fs("read", path))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            // e.g. a line cut by a crash
+            Err(e) => tracing::warn!("Invalid entry at {}:{}: {e}", path.display(), index + 1),
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Print the last `count` entries, of the device if `serial_number` is set
+pub fn print(config: &Config, count: usize, serial_number: Option<&str>) -> Result<(), Error> {
+    let path: &Path = match &config.history_file {
+        Some(path) => path,
+        None => {
+            println!("Mount history disabled (history_file)");
+            return Ok(());
+        }
+    };
+
+    let mut entries: Vec<Entry> = read(path)?;
+    if let Some(serial_number) = serial_number {
+        entries.retain(|entry| entry.serial_number == serial_number);
+    }
+    let entries: &[Entry] = &entries[entries.len().saturating_sub(count)..];
+
+    if entries.is_empty() {
+        println!("No history");
+        return Ok(());
+    }
+
+    println!(
+        "{:<19} {:<7} {:<40} {:<12} {:<7} MOUNTPOINT",
+        "TIME", "EVENT", "SERIAL", "MOUNTED FOR", "OUTCOME"
+    );
+    for entry in entries.iter() {
+        let mountpoints: Vec<String> = entry
+            .mountpoints
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+
+        println!(
+            "{:<19} {:<7} {:<40} {:<12} {:<7} {}",
+            local_time(entry.timestamp),
+            entry.event.as_str(),
+            entry.serial_number,
+            entry
+                .mounted_secs
+                .map(format_secs)
+                .as_deref()
+                .unwrap_or("-"),
+            entry.outcome.as_str(),
+            if mountpoints.is_empty() {
+                String::from("-")
+            } else {
+                mountpoints.join(", ")
+            },
+        );
+
+        if let Some(e) = &entry.error {
+            println!("  {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// `YYYY-MM-DD HH:MM:SS`, in the local time zone
+fn local_time(timestamp: u64) -> String {
+    let time: libc::time_t = timestamp as libc::time_t;
+    let mut tm: MaybeUninit<libc::tm> = MaybeUninit::uninit();
+    if unsafe { libc::localtime_r(&time, tm.as_mut_ptr()) }.is_null() {
+        return timestamp.to_string();
+    }
+    let tm: libc::tm = unsafe { tm.assume_init() };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec
+    )
+}
@@ -0,0 +1,108 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! User-defined hook commands
+
+use std::path::Path;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::template::DeviceInfo;
+use crate::user::User;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Run the hook without blocking the caller
+///
+/// The device is exposed with the `IFA_*` environment variables (`IFA_UDID` is the dashed form for the newer devices).
+/// The hook is killed if it doesn't exit within `timeout`. Failures are only logged.
+#[inline]
+pub fn run(
+    name: &str,
+    command: &[String],
+    info: &DeviceInfo,
+    mountpoint: &Path,
+    timeout: Duration,
+) {
+    run_as(name, command, info, mountpoint, timeout, None)
+}
+
+/// Same as [`run`], in the session of `user` if set (i.e. when running as root)
+pub fn run_as(
+    name: &str,
+    command: &[String],
+    info: &DeviceInfo,
+    mountpoint: &Path,
+    timeout: Duration,
+    user: Option<&User>,
+) {
+    let (program, args) = match command.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+
+    let mut cmd: Command = Command::new(program);
+    cmd.args(args)
+        .env("IFA_MOUNTPOINT", mountpoint)
+        .env("IFA_SERIAL", &info.serial_number)
+        .env("IFA_UDID", crate::udid(&info.serial_number))
+        .env("IFA_BUS", info.bus.to_string())
+        .env("IFA_ADDRESS", info.address.to_string())
+        .env("IFA_VENDOR_ID", format!("{:04x}", info.vendor_id))
+        .env("IFA_PRODUCT_ID", format!("{:04x}", info.product_id))
+        .stdin(Stdio::null());
+
+    if let Some(user) = user {
+        user.apply_session(&mut cmd);
+    }
+
+    tracing::debug!("Running {name} hook: {cmd:?}");
+
+    // Spawn here, so the hook starts even if the process exits right after (i.e. manual mount)
+    let child: Child = match cmd.spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            tracing::warn!("Can't run {name} hook: {e}");
+            return;
+        }
+    };
+
+    let name: String = name.to_string();
+    thread::spawn(move || wait(&name, child, timeout));
+}
+
+fn wait(name: &str, mut child: Child, timeout: Duration) {
+    let start: Instant = Instant::now();
+
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) => return log_status(name, status),
+            Ok(None) if start.elapsed() >= timeout => {
+                tracing::warn!(
+                    "{name} hook timed out after {} s, killing it",
+                    timeout.as_secs()
+                );
+                if let Err(e) = child.kill() {
+                    tracing::warn!("Can't kill {name} hook: {e}");
+                }
+                // Reap it
+                let _ = child.wait();
+                return;
+            }
+            Ok(None) => thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                tracing::warn!("Can't wait {name} hook: {e}");
+                return;
+            }
+        }
+    }
+}
+
+fn log_status(name: &str, status: ExitStatus) {
+    if status.success() {
+        tracing::debug!("{name} hook completed");
+    } else {
+        tracing::warn!("{name} hook failed: {status}");
+    }
+}
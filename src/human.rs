@@ -0,0 +1,76 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Human log format: the `fmt` one, with a short device identifier before the device logs
+//!
+//! The identifier is the end of the serial number (the bus and address until it's read), in a
+//! color of its own when colors are enabled, so that the logs of concurrent devices can be told
+//! apart.
+
+use std::fmt;
+
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::format::{Format, Writer};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
+
+use crate::fields;
+
+/// Chars of the serial number in the identifier
+const ID_LEN: usize = 4;
+
+/// ANSI foreground colors of the identifiers: not red nor yellow, used by the levels
+const ID_COLORS: [u8; 8] = [32, 34, 35, 36, 92, 94, 95, 96];
+
+#[derive(Debug, Default)]
+pub struct HumanFormat {
+    inner: Format,
+}
+
+impl<S, N> FormatEvent<S, N> for HumanFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        if let Some(id) = device_id(ctx) {
+            match writer.has_ansi_escapes() {
+                true => write!(writer, "\x1b[1;{}m[{id}]\x1b[0m ", color(&id))?,
+                false => write!(writer, "[{id}] ")?,
+            }
+        }
+
+        self.inner.format_event(ctx, writer, event)
+    }
+}
+
+/// Identifier of the device of the innermost `device` span of the event, if any
+fn device_id<S, N>(ctx: &FmtContext<'_, S, N>) -> Option<String>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    let span: SpanRef<'_, S> = ctx.event_scope()?.find(|span| span.name() == "device")?;
+
+    if let Some(serial) = fields::span_field(&span, "serial") {
+        let chars: Vec<char> = serial.chars().collect();
+        return Some(chars[chars.len().saturating_sub(ID_LEN)..].iter().collect());
+    }
+
+    let bus: String = fields::span_field(&span, "bus")?;
+    let addr: String = fields::span_field(&span, "addr")?;
+    Some(format!("{bus}:{addr}"))
+}
+
+/// Same identifier, same color (FNV-1a)
+fn color(id: &str) -> u8 {
+    let hash: u32 = id.bytes().fold(0x811c9dc5, |hash: u32, byte: u8| {
+        (hash ^ u32::from(byte)).wrapping_mul(0x01000193)
+    });
+    ID_COLORS[hash as usize % ID_COLORS.len()]
+}
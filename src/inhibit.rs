@@ -0,0 +1,192 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Suspend inhibitor of logind (`inhibit_suspend`)
+//!
+//! A "sleep" lock in delay mode is held with `systemd-inhibit` while a device is mounted. On
+//! `PrepareForSleep(true)`, watched with `gdbus monitor`, the handler thread unmounts everything and
+//! releases the lock, letting the system suspend (logind waits up to its `InhibitDelayMaxSec`).
+//! `PrepareForSleep(false)` triggers the check of the mounts after the resume, with or without
+//! the lock.
+
+use std::io::{self, BufRead, BufReader};
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::sync::{mpsc, Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+
+use rusb::Context;
+
+use crate::{Error, Message};
+
+const SYSTEMD_INHIBIT: &str = "systemd-inhibit";
+const GDBUS: &str = "gdbus";
+const LOGIND_DEST: &str = "org.freedesktop.login1";
+const LOGIND_PATH: &str = "/org/freedesktop/login1";
+const WHO: &str = "ifuse-automount";
+const WHY: &str = "Unmounting the Apple devices before suspending";
+
+#[derive(Debug, Default)]
+struct InnerInhibitor {
+    /// `systemd-inhibit`, holding the lock while it runs
+    child: Option<Child>,
+    /// `systemd-inhibit` missing or failing: not tried again
+    disabled: bool,
+}
+
+/// Inhibitor lock, shared with the restarted handlers
+#[derive(Debug, Clone, Default)]
+pub struct Inhibitor {
+    inner: Arc<Mutex<InnerInhibitor>>,
+}
+
+impl Inhibitor {
+    // The child is always left consistent, so it's safe to ignore poisoning
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, InnerInhibitor> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Take the lock, or release it
+    pub fn hold(&self, hold: bool) {
+        let mut inner: MutexGuard<'_, InnerInhibitor> = self.lock();
+        if inner.disabled {
+            return;
+        }
+
+        match (hold, inner.child.take()) {
+            (true, Some(mut child)) => match child.try_wait() {
+                Ok(None) => inner.child = Some(child),
+                Ok(Some(status)) => {
+                    tracing::warn!(
+                        "{SYSTEMD_INHIBIT} exited ({status}), suspend no longer inhibited"
+                    );
+                    inner.disabled = true;
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "Can't check {SYSTEMD_INHIBIT}: {e}, suspend no longer inhibited"
+                    );
+                    release(child);
+                    inner.disabled = true;
+                }
+            },
+            (true, None) => match acquire() {
+                Ok(child) => {
+                    tracing::debug!("Suspend inhibited");
+                    inner.child = Some(child);
+                }
+                Err(e) => {
+                    tracing::warn!("Can't inhibit suspend with {SYSTEMD_INHIBIT}: {e}");
+                    inner.disabled = true;
+                }
+            },
+            (false, Some(child)) => {
+                release(child);
+                tracing::debug!("Suspend no longer inhibited");
+            }
+            (false, None) => {}
+        }
+    }
+}
+
+impl Drop for InnerInhibitor {
+    fn drop(&mut self) {
+        if let Some(child) = self.child.take() {
+            release(child);
+        }
+    }
+}
+
+/// `systemd-inhibit --what=sleep --mode=delay ... cat`
+///
+/// `cat` exits when its stdin is closed, i.e. on release or when the daemon dies.
+fn acquire() -> io::Result<Child> {
+    Command::new(SYSTEMD_INHIBIT)
+        .arg("--what=sleep")
+        .arg("--mode=delay")
+        .arg(format!("--who={WHO}"))
+        .arg(format!("--why={WHY}"))
+        .arg("cat")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+}
+
+fn release(mut child: Child) {
+    // EOF for `cat`
+    drop(child.stdin.take());
+    if let Err(e) = child.wait() {
+        tracing::warn!("Can't wait for {SYSTEMD_INHIBIT}: {e}");
+    }
+}
+
+/// Send [`Message::Suspend`] and [`Message::Resume`] to the handler thread on `PrepareForSleep`
+pub fn spawn_listener(tx: mpsc::Sender<Message<Context>>) -> Result<(), Error> {
+    let mut child: Child = Command::new(GDBUS)
+        .args([
+            "monitor",
+            "--system",
+            "--dest",
+            LOGIND_DEST,
+            "--object-path",
+            LOGIND_PATH,
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|source| Error::Spawn {
+            program: String::from(GDBUS),
+            source,
+        })?;
+    let stdout: ChildStdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| io::Error::other("no stdout"))?;
+
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            let line: String = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    tracing::warn!("Can't read {GDBUS} output: {e}");
+                    break;
+                }
+            };
+
+            let message: Message<Context> = match prepare_for_sleep(&line) {
+                Some(true) => {
+                    tracing::info!("The system is about to suspend");
+                    Message::Suspend
+                }
+                Some(false) => {
+                    tracing::info!("The system resumed");
+                    Message::Resume
+                }
+                None => continue,
+            };
+
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+
+        tracing::warn!("{GDBUS} monitor stopped, no longer watching the suspend");
+        let _ = child.kill();
+        let _ = child.wait();
+    });
+
+    Ok(())
+}
+
+/// Argument of a `PrepareForSleep` signal, e.g.
+/// `/org/freedesktop/login1: org.freedesktop.login1.Manager.PrepareForSleep (true,)`
+fn prepare_for_sleep(line: &str) -> Option<bool> {
+    let (_, args) = line.split_once(".PrepareForSleep ")?;
+    match args.trim() {
+        "(true,)" => Some(true),
+        "(false,)" => Some(false),
+        _ => None,
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Native journald output
+//!
+//! The events are sent to the journal socket with the native protocol: the fields of the event and
+//! of its spans become journal fields (e.g. `SERIAL=`, `MOUNTPOINT=`, `ACTION=`), and the level
+//! becomes `PRIORITY=`. An event that can't be sent is written to stderr instead.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixDatagram;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::fields::{self, EventFields};
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const JOURNAL_STREAM: &str = "JOURNAL_STREAM";
+const IDENTIFIER: &str = "ifuse-automount";
+
+/// Whether stderr is connected to the journal, i.e. `JOURNAL_STREAM` (`<dev>:<inode>`, set by
+/// systemd) matches it
+pub fn is_journal_stream() -> bool {
+    let stream: String = match env::var(JOURNAL_STREAM) {
+        Ok(stream) => stream,
+        Err(..) => return false,
+    };
+    let (dev, ino): (u64, u64) = match stream.split_once(':') {
+        Some((dev, ino)) => match (dev.parse(), ino.parse()) {
+            (Ok(dev), Ok(ino)) => (dev, ino),
+            _ => return false,
+        },
+        None => return false,
+    };
+
+    // Not inherited by a redirected stderr (e.g. `--daemon`)
+    match fs::metadata("/proc/self/fd/2") {
+        Ok(metadata) => metadata.dev() == dev && metadata.ino() == ino,
+        Err(..) => false,
+    }
+}
+
+pub struct JournaldLayer {
+    socket: UnixDatagram,
+    /// Already warned that the journal is unreachable
+    failed: AtomicBool,
+}
+
+impl JournaldLayer {
+    pub fn new() -> io::Result<Self> {
+        let socket: UnixDatagram = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET)?;
+        Ok(Self {
+            socket,
+            failed: AtomicBool::new(false),
+        })
+    }
+}
+
+impl<S> Layer<S> for JournaldLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        fields::on_new_span(attrs, id, &ctx);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        fields::on_record(id, values, &ctx);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let event_fields: EventFields = EventFields::collect(event, &ctx);
+        let level: Level = *event.metadata().level();
+
+        let mut payload: Vec<u8> = Vec::new();
+        put(&mut payload, "PRIORITY", priority(level));
+        put(&mut payload, "SYSLOG_IDENTIFIER", IDENTIFIER);
+        put(&mut payload, "MESSAGE", &event_fields.message);
+        put(&mut payload, "TARGET", event.metadata().target());
+        for (name, value) in event_fields.fields.iter() {
+            if let Some(name) = field_name(name) {
+                put(&mut payload, &name, value);
+            }
+        }
+
+        if let Err(e) = self.socket.send(&payload) {
+            if !self.failed.swap(true, Ordering::Relaxed) {
+                eprintln!("Can't write to the journal ({e}), logging to stderr");
+            }
+            eprintln!("{level:>5} {}", event_fields.to_line());
+        }
+    }
+}
+
+/// syslog priority of the level
+fn priority(level: Level) -> &'static str {
+    match level {
+        Level::ERROR => "3",
+        Level::WARN => "4",
+        Level::INFO => "6",
+        Level::DEBUG | Level::TRACE => "7",
+    }
+}
+
+/// Journal field name: uppercase ASCII letters, digits and `_`, not starting with `_` (reserved)
+/// nor with a digit
+fn field_name(name: &str) -> Option<String> {
+    let name: String = name
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect();
+    let name: &str = name.trim_start_matches('_');
+
+    match name.chars().next() {
+        Some(c) if !c.is_ascii_digit() => Some(name.chars().take(64).collect()),
+        _ => None,
+    }
+}
+
+/// `NAME=value\n`, or `NAME\n<length><value>\n` for a multi-line value
+fn put(payload: &mut Vec<u8>, name: &str, value: &str) {
+    payload.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        payload.push(b'\n');
+        payload.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        payload.push(b'=');
+    }
+    payload.extend_from_slice(value.as_bytes());
+    payload.push(b'\n');
+}
@@ -0,0 +1,107 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! JSON log format: one object per line
+//!
+//! Keys: `timestamp` (RFC 3339, UTC), `level` (e.g. `INFO`), `target`, `message`, then the fields
+//! of the event and of its spans, as strings (e.g. `serial`, `bus`, `addr`, `action`, `mountpoint`,
+//! `error_class`).
+
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{Map, Value};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::fields::{self, EventFields};
+
+/// Keys set by the layer, not overridden by the fields
+const RESERVED_KEYS: [&str; 4] = ["timestamp", "level", "target", "message"];
+
+pub struct JsonLayer<W> {
+    make_writer: W,
+}
+
+impl<W> JsonLayer<W> {
+    pub fn new(make_writer: W) -> Self {
+        Self { make_writer }
+    }
+}
+
+impl<S, W> Layer<S> for JsonLayer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'w> MakeWriter<'w> + 'static,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        fields::on_new_span(attrs, id, &ctx);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        fields::on_record(id, values, &ctx);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let event_fields: EventFields = EventFields::collect(event, &ctx);
+
+        let mut object: Map<String, Value> = Map::new();
+        object.insert(
+            String::from("timestamp"),
+            Value::from(rfc3339(SystemTime::now())),
+        );
+        object.insert(
+            String::from("level"),
+            Value::from(event.metadata().level().as_str()),
+        );
+        object.insert(
+            String::from("target"),
+            Value::from(event.metadata().target()),
+        );
+        object.insert(String::from("message"), Value::from(event_fields.message));
+        for (name, value) in event_fields.fields {
+            if !RESERVED_KEYS.contains(&name.as_str()) {
+                object.insert(name, Value::from(value));
+            }
+        }
+
+        let mut line: String = Value::Object(object).to_string();
+        line.push('\n');
+
+        // Nowhere else to report it
+        let _ = self.make_writer.make_writer().write_all(line.as_bytes());
+    }
+}
+
+/// `2025-01-31T12:34:56.789Z`
+fn rfc3339(time: SystemTime) -> String {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs: u64 = elapsed.as_secs();
+    let (year, month, day) = civil_from_days(secs / 86400);
+    let time_of_day: u64 = secs % 86400;
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60,
+        elapsed.subsec_millis()
+    )
+}
+
+/// Date of a number of days since 1970-01-01 (Howard Hinnant's `civil_from_days`)
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z: u64 = days + 719468;
+    let era: u64 = z / 146097;
+    let doe: u64 = z - era * 146097;
+    let yoe: u64 = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy: u64 = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp: u64 = (5 * doy + 2) / 153;
+    let day: u64 = doy - (153 * mp + 2) / 5 + 1;
+    let month: u64 = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year: u64 = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
@@ -0,0 +1,79 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Friendly-name symlinks: `<base>/by-name/<DeviceName>`
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use crate::template;
+use crate::Error;
+
+const LINKS_DIR: &str = "by-name";
+/// Max numeric suffix tried on name collisions
+const MAX_SUFFIX: usize = 100;
+
+/// Create a symlink named after the device, pointing at `target`
+///
+/// On collision with the link of another device, a numeric suffix is appended (e.g. `iPhone-2`).
+pub fn create(base_path: &Path, name: &str, target: &Path) -> Result<PathBuf, Error> {
+    let dir: PathBuf = base_path.join(LINKS_DIR);
+    fs::create_dir_all(&dir).map_err(Error::fs("create", &dir))?;
+
+    let name: String = template::escape(name);
+
+    for index in 1..=MAX_SUFFIX {
+        let link: PathBuf = match index {
+            1 => dir.join(&name),
+            index => dir.join(format!("{name}-{index}")),
+        };
+
+        match fs::read_link(&link) {
+            // Already there
+            Ok(existing) if existing == target => return Ok(link),
+            // Taken by another device
+            Ok(..) => continue,
+            // Not a symlink
+            Err(..) if link.symlink_metadata().is_ok() => continue,
+            Err(..) => {
+                symlink(target, &link)?;
+                tracing::info!("Created link {} -> {}", link.display(), target.display());
+                return Ok(link);
+            }
+        }
+    }
+
+    Err(Error::CantMount(format!(
+        "too many links named '{name}' in {}",
+        dir.display()
+    )))
+}
+
+/// Remove the symlinks pointing at the directory of these mountpoints
+pub fn remove(base_path: &Path, mountpoints: &[PathBuf]) {
+    let dir: PathBuf = base_path.join(LINKS_DIR);
+
+    let entries: fs::ReadDir = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(..) => return,
+    };
+
+    for entry in entries.flatten() {
+        let link: PathBuf = entry.path();
+        let target: PathBuf = match fs::read_link(&link) {
+            Ok(target) => target,
+            Err(..) => continue,
+        };
+
+        if mountpoints.iter().any(|path| path.starts_with(&target)) {
+            match fs::remove_file(&link) {
+                Ok(()) => tracing::debug!("Removed link {}", link.display()),
+                Err(e) => tracing::warn!("Can't remove {}: {e}", link.display()),
+            }
+        }
+    }
+
+    // Only if empty
+    let _ = fs::remove_dir(&dir);
+}
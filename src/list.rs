@@ -0,0 +1,118 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::path::PathBuf;
+
+use rusb::{Context, DeviceDescriptor, UsbContext};
+use serde::Serialize;
+
+use crate::config::{Config, Naming};
+use crate::mounts::{self, MountEntry};
+use crate::template::DeviceInfo;
+use crate::{is_apple_device, model_name, open_and_read_device_info, products, Error};
+
+#[derive(Debug, Serialize)]
+struct ListedDevice {
+    bus: u8,
+    address: u8,
+    vendor_id: u16,
+    product_id: u16,
+    /// e.g. `iPad 3 (wifi)`, or the IDs if unknown
+    product_name: String,
+    /// `None` if it can't be read (i.e. missing permissions)
+    serial_number: Option<String>,
+    /// Mounted mountpoints under the base path
+    mountpoints: Vec<PathBuf>,
+}
+
+/// List the connected Apple devices
+///
+/// The mount state comes from the mount table, so this works without the daemon running.
+pub fn list(config: &Config, json: bool) -> Result<(), Error> {
+    let context: Context = Context::new()?;
+    let mount_table: Vec<MountEntry> = mounts::read()?;
+    let mut devices: Vec<ListedDevice> = Vec::new();
+
+    for device in context.devices()?.iter() {
+        let descriptor: DeviceDescriptor = device.device_descriptor()?;
+
+        if !is_apple_device(config, &device, &descriptor) {
+            continue;
+        }
+
+        let mut info: Option<DeviceInfo> = match open_and_read_device_info(
+            &device,
+            &descriptor,
+            config.usb_timeout,
+            config.dir_uses_name(),
+        ) {
+            Ok(info) => Some(info),
+            Err(e) => {
+                tracing::warn!(
+                    "Can't read serial number of device {:03}:{:03}: {}",
+                    device.bus_number(),
+                    device.address(),
+                    e.report()
+                );
+                None
+            }
+        };
+
+        if let Some(info) = info.as_mut().filter(|_| config.naming == Naming::Model) {
+            info.model = model_name(info, &[], config.command_timeout);
+        }
+
+        let mountpoints: Vec<PathBuf> = match &info {
+            Some(info) => config
+                .device(&info.serial_number)
+                .mounts
+                .iter()
+                .map(|spec| config.mountpoint(info, spec))
+                .filter(|path| mounts::find(&mount_table, path).is_some())
+                .collect(),
+            None => Vec::new(),
+        };
+
+        devices.push(ListedDevice {
+            bus: device.bus_number(),
+            address: device.address(),
+            vendor_id: descriptor.vendor_id(),
+            product_id: descriptor.product_id(),
+            product_name: products::name(descriptor.vendor_id(), descriptor.product_id())
+                .into_owned(),
+            serial_number: info.map(|info| info.serial_number),
+            mountpoints,
+        });
+    }
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&devices)?);
+        return Ok(());
+    }
+
+    println!(
+        "{:<9} {:<28} {:<40} MOUNTPOINT",
+        "BUS:ADDR", "PRODUCT", "SERIAL"
+    );
+    for device in devices.iter() {
+        let mountpoints: Vec<String> = device
+            .mountpoints
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+
+        println!(
+            "{:<9} {:<28} {:<40} {}",
+            format!("{:03}:{:03}", device.bus, device.address),
+            device.product_name,
+            device.serial_number.as_deref().unwrap_or("?"),
+            if mountpoints.is_empty() {
+                String::from("-")
+            } else {
+                mountpoints.join(", ")
+            },
+        );
+    }
+
+    Ok(())
+}
@@ -0,0 +1,118 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Single instance per base path
+//!
+//! The lock is an exclusive `flock`, so the kernel releases it when the process dies (no stale lock).
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{self, Path, PathBuf};
+use std::process;
+
+use crate::Error;
+
+/// Used by root if the runtime dir is not available (e.g. without a session)
+const ROOT_FALLBACK_DIR: &str = "/run";
+/// Subdirectory of the state dir, used by a user without runtime dir
+const APP_DIR_NAME: &str = "ifuse-automount";
+
+/// Exclusive lock on `<runtime-dir>/ifuse-automount-<hash of the base path>.lock`, released on drop
+#[derive(Debug)]
+pub struct InstanceLock {
+    file: File,
+    path: PathBuf,
+}
+
+impl InstanceLock {
+    /// Lock the base path, or fail with [`Error::AlreadyRunning`] if another instance holds it
+    pub fn acquire(base_path: &Path) -> Result<Self, Error> {
+        let path: PathBuf = lock_path(base_path)?;
+        let mut file: File = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(Error::fs("open", &path))?;
+
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } < 0 {
+            let e: io::Error = io::Error::last_os_error();
+            if e.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(Error::fs("lock", &path)(e));
+            }
+
+            // Written by the holder
+            let mut content: String = String::new();
+            file.read_to_string(&mut content)?;
+            return Err(Error::AlreadyRunning(content.trim().parse().ok()));
+        }
+
+        let mut lock: Self = Self { file, path };
+        lock.write_pid()?;
+        tracing::debug!("Lock acquired at {}", lock.path.display());
+
+        Ok(lock)
+    }
+
+    /// Write the PID of the current process (again after daemonizing)
+    pub fn write_pid(&mut self) -> Result<(), Error> {
+        self.file.set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        writeln!(self.file, "{}", process::id())?;
+        Ok(())
+    }
+}
+
+impl Drop for InstanceLock {
+    fn drop(&mut self) {
+        // Not removed: another instance opening it at the same time would lock a deleted file
+        let _ = self.file.set_len(0);
+        unsafe {
+            libc::flock(self.file.as_raw_fd(), libc::LOCK_UN);
+        }
+    }
+}
+
+/// One lock per base path
+#[inline]
+fn lock_path(base_path: &Path) -> Result<PathBuf, Error> {
+    instance_file(base_path, "lock")
+}
+
+/// File of the instance of the base path: `<runtime-dir>/ifuse-automount-<hash of the base path>.<extension>`
+pub fn instance_file(base_path: &Path, extension: &str) -> Result<PathBuf, Error> {
+    let base_path: PathBuf = path::absolute(base_path)?;
+    let dir: PathBuf = runtime_dir()?;
+    Ok(dir.join(format!(
+        "ifuse-automount-{:016x}.{extension}",
+        fnv1a(base_path.as_os_str().as_encoded_bytes())
+    )))
+}
+
+/// Dir of the instance files: the runtime dir, else `/run` for root, else
+/// `<state-dir>/ifuse-automount` (created), which a user can write
+pub fn runtime_dir() -> Result<PathBuf, Error> {
+    if let Some(dir) = dirs::runtime_dir() {
+        return Ok(dir);
+    }
+
+    if unsafe { libc::geteuid() } == 0 {
+        return Ok(PathBuf::from(ROOT_FALLBACK_DIR));
+    }
+
+    let dir: PathBuf = dirs::state_dir()
+        .unwrap_or_else(env::temp_dir)
+        .join(APP_DIR_NAME);
+    fs::create_dir_all(&dir).map_err(Error::fs("create", &dir))?;
+    Ok(dir)
+}
+
+/// FNV-1a: stable across builds, unlike the std hasher
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -0,0 +1,149 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Log file with size-based rotation (`--log-file`)
+//!
+//! The file is rotated when it reaches the max size: `daemon.log` becomes `daemon.log.1`,
+//! `daemon.log.1` becomes `daemon.log.2`, and so on, the oldest one being removed. Each line is
+//! written as soon as it's complete. If the file can't be written or rotated, the logs go to stderr.
+
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+use tracing_subscriber::fmt::MakeWriter;
+
+#[derive(Debug)]
+struct InnerLogFile {
+    path: PathBuf,
+    /// `None` after a failure: stderr is used instead
+    writer: Option<LineWriter<File>>,
+    size: u64,
+    max_size: u64,
+    /// Rotated files kept
+    max_files: usize,
+}
+
+/// Log file, shared with the writers of the log layer
+#[derive(Debug, Clone)]
+pub struct LogFile {
+    inner: Arc<Mutex<InnerLogFile>>,
+}
+
+impl LogFile {
+    pub fn open(path: PathBuf, max_size: u64, max_files: usize) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file: File = open(&path)?;
+        let size: u64 = file.metadata()?.len();
+
+        Ok(Self {
+            inner: Arc::new(Mutex::new(InnerLogFile {
+                path,
+                writer: Some(LineWriter::new(file)),
+                size,
+                max_size,
+                max_files,
+            })),
+        })
+    }
+
+    // The writer is replaced as a whole, so it's safe to ignore poisoning
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, InnerLogFile> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Write the buffered partial line, if any
+    pub fn flush(&self) {
+        let mut inner: MutexGuard<'_, InnerLogFile> = self.lock();
+        if let Some(writer) = inner.writer.as_mut() {
+            let _ = writer.flush();
+        }
+    }
+}
+
+impl InnerLogFile {
+    fn write(&mut self, buf: &[u8]) {
+        if self.writer.is_some() && self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            if let Err(e) = self.rotate() {
+                self.fail(format!("Can't rotate {}: {e}", self.path.display()));
+            }
+        }
+
+        match self.writer.as_mut() {
+            Some(writer) => match writer.write_all(buf) {
+                Ok(()) => self.size += buf.len() as u64,
+                Err(e) => {
+                    self.fail(format!("Can't write to {}: {e}", self.path.display()));
+                    let _ = io::stderr().write_all(buf);
+                }
+            },
+            None => {
+                let _ = io::stderr().write_all(buf);
+            }
+        }
+    }
+
+    /// `<path>.<n>` to `<path>.<n + 1>`, then `<path>` to `<path>.1` and a new `<path>`
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+        }
+
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let from: PathBuf = rotated(&self.path, n);
+                if from.exists() {
+                    fs::rename(from, rotated(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated(&self.path, 1))?;
+        }
+
+        self.writer = Some(LineWriter::new(open(&self.path)?));
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Log to stderr from now on, with a single warning
+    fn fail(&mut self, e: String) {
+        self.writer = None;
+        eprintln!("{e}, logging to stderr");
+    }
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.lock().write(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        LogFile::flush(self);
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogFile {
+    type Writer = LogFile;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+fn open(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+fn rotated(path: &Path, n: usize) -> PathBuf {
+    let mut rotated: OsString = path.as_os_str().to_os_string();
+    rotated.push(format!(".{n}"));
+    PathBuf::from(rotated)
+}
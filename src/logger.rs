@@ -0,0 +1,250 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+use std::env;
+use std::io;
+use std::panic;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use clap::ValueEnum;
+use tracing::Level;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Layer, Layered, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{fmt, EnvFilter, Registry};
+
+use crate::cli::Cli;
+use crate::config;
+use crate::fields::SpanFieldsLayer;
+use crate::human::HumanFormat;
+use crate::journald::{self, JournaldLayer};
+use crate::json::JsonLayer;
+use crate::logfile::LogFile;
+use crate::syslog::SyslogLayer;
+
+/// Where the logs go
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogTarget {
+    /// Human-readable lines on stderr
+    Stderr,
+    /// systemd journal, with structured fields
+    Journald,
+    /// Local syslog daemon, facility `daemon`
+    Syslog,
+}
+
+impl LogTarget {
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Stderr => "stderr",
+            Self::Journald => "journald",
+            Self::Syslog => "syslog",
+        }
+    }
+}
+
+/// Format of the logs written to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines
+    Human,
+    /// One JSON object per line
+    Json,
+}
+
+impl LogFormat {
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Human => "human",
+            Self::Json => "json",
+        }
+    }
+}
+
+/// When the logs written to stderr are colored, with the human format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// If stderr is a terminal and `NO_COLOR` isn't set
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    #[inline]
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Auto => "auto",
+            Self::Always => "always",
+            Self::Never => "never",
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        match self {
+            Self::Auto => {
+                env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                    && unsafe { libc::isatty(libc::STDERR_FILENO) } == 1
+            }
+            Self::Always => true,
+            Self::Never => false,
+        }
+    }
+}
+
+const MIB: u64 = 1024 * 1024;
+
+type Filtered = Layered<EnvFilter, Registry>;
+
+/// `--log-file`, flushed on exit
+static LOG_FILE: OnceLock<LogFile> = OnceLock::new();
+
+/// Init the logger
+///
+/// `--log-level`, `-v` or `-q` take precedence over `RUST_LOG` (e.g. `ifuse_automount=debug`).
+/// Default level is `INFO`: `quiet` lowers it to `WARN`, each `verbose` raises it by one.
+///
+/// The target is the journal if stderr is connected to it, unless `--log-target` is set.
+/// `--log-file` replaces stderr. `--log-format` applies to both, the journal and syslog have their
+/// own formats.
+pub fn init(args: &Cli) {
+    let level: Option<Level> = match (args.log_level, args.quiet, args.verbose) {
+        (Some(level), ..) => Some(level),
+        (None, true, _) => Some(Level::WARN),
+        (None, false, 0) => None,
+        (None, false, 1) => Some(Level::DEBUG),
+        (None, false, _) => Some(Level::TRACE),
+    };
+
+    let mut invalid_env: Option<String> = None;
+    let filter: EnvFilter = match level {
+        Some(level) => EnvFilter::default().add_directive(LevelFilter::from_level(level).into()),
+        None => match env::var(EnvFilter::DEFAULT_ENV) {
+            Ok(directives) if !directives.is_empty() => EnvFilter::try_new(&directives)
+                .unwrap_or_else(|e| {
+                    invalid_env = Some(e.to_string());
+                    EnvFilter::default().add_directive(LevelFilter::INFO.into())
+                }),
+            _ => EnvFilter::default().add_directive(LevelFilter::INFO.into()),
+        },
+    };
+
+    let target: LogTarget = match args.log_target {
+        Some(target) => target,
+        None if journald::is_journal_stream() => LogTarget::Journald,
+        None => LogTarget::Stderr,
+    };
+
+    let registry = Registry::default().with(filter);
+    let mut unavailable: Option<String> = None;
+
+    if let Some(path) = &args.log_file {
+        let path: PathBuf = config::expand_tilde(path.clone());
+        match LogFile::open(
+            path.clone(),
+            args.log_file_max_size * MIB,
+            args.log_file_max_files,
+        ) {
+            Ok(file) => {
+                let _ = LOG_FILE.set(file.clone());
+                registry.with(file_layer(args.log_format, file)).init();
+
+                // Before the default hook, printing to the redirected stderr
+                let default_hook = panic::take_hook();
+                panic::set_hook(Box::new(move |info| {
+                    flush();
+                    default_hook(info);
+                }));
+            }
+            Err(e) => {
+                unavailable = Some(format!(
+                    "Can't open {} ({e}), logging to stderr",
+                    path.display()
+                ));
+                registry
+                    .with(stderr_layer(args.log_format, args.color))
+                    .init();
+            }
+        }
+        warn(unavailable, invalid_env);
+        return;
+    }
+
+    match target {
+        LogTarget::Stderr => registry
+            .with(stderr_layer(args.log_format, args.color))
+            .init(),
+        LogTarget::Journald => match JournaldLayer::new() {
+            Ok(layer) => registry.with(layer).init(),
+            Err(e) => {
+                unavailable = Some(format!(
+                    "Can't connect to the journal ({e}), logging to stderr"
+                ));
+                registry
+                    .with(stderr_layer(args.log_format, args.color))
+                    .init()
+            }
+        },
+        LogTarget::Syslog => match SyslogLayer::new(args.syslog_identifier.clone()) {
+            Ok(layer) => registry.with(layer).init(),
+            Err(e) => {
+                unavailable = Some(format!("Can't connect to syslog ({e}), logging to stderr"));
+                registry
+                    .with(stderr_layer(args.log_format, args.color))
+                    .init()
+            }
+        },
+    }
+
+    warn(unavailable, invalid_env);
+}
+
+/// Flush the log file, if any
+pub fn flush() {
+    if let Some(file) = LOG_FILE.get() {
+        file.flush();
+    }
+}
+
+fn warn(unavailable: Option<String>, invalid_env: Option<String>) {
+    if let Some(e) = unavailable {
+        tracing::warn!("{e}");
+    }
+    if let Some(e) = invalid_env {
+        tracing::warn!(
+            "Invalid {}, logging at the info level: {e}",
+            EnvFilter::DEFAULT_ENV
+        );
+    }
+}
+
+fn file_layer(format: LogFormat, file: LogFile) -> Box<dyn Layer<Filtered> + Send + Sync> {
+    match format {
+        LogFormat::Human => SpanFieldsLayer
+            .and_then(
+                fmt::layer()
+                    .with_writer(file)
+                    .with_ansi(false)
+                    .event_format(HumanFormat::default()),
+            )
+            .boxed(),
+        LogFormat::Json => JsonLayer::new(file).boxed(),
+    }
+}
+
+fn stderr_layer(format: LogFormat, color: ColorChoice) -> Box<dyn Layer<Filtered> + Send + Sync> {
+    match format {
+        LogFormat::Human => SpanFieldsLayer
+            .and_then(
+                fmt::layer()
+                    .with_writer(io::stderr)
+                    .with_ansi(color.enabled())
+                    .event_format(HumanFormat::default()),
+            )
+            .boxed(),
+        LogFormat::Json => JsonLayer::new(io::stderr).boxed(),
+    }
+}
@@ -1,324 +1,3837 @@
 // Copyright (c) 2025 Yuki Kishimoto
 // Distributed under the MIT software license
 
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
-use std::process::{Command, Output, Stdio};
-use std::sync::mpsc;
-use std::time::Duration;
-use std::{fmt, fs, io, thread};
+use std::any::Any;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{self, Path, PathBuf};
+use std::process::{self, Command, ExitCode, Output, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use std::{env, fs, io, thread};
 
+use clap::Parser;
 use rusb::{
     Context, Device, DeviceDescriptor, DeviceHandle, Hotplug, HotplugBuilder, Language,
     Registration, UsbContext,
 };
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use tracing::{field, span, Span};
 
-const TIMEOUT: Duration = Duration::from_secs(5);
+mod adopt;
+mod cancel;
+mod child;
+mod cli;
+mod config;
+mod control;
+mod daemon;
+mod details;
+mod dump;
+mod failure;
+mod fields;
+mod generate;
+mod health;
+mod history;
+mod hooks;
+mod human;
+mod inhibit;
+mod journald;
+mod json;
+mod links;
+mod list;
+mod lock;
+mod logfile;
+mod logger;
+mod manual;
+mod meta;
+mod metrics;
+mod models;
+mod mounts;
+mod notify;
+mod persist;
+mod poll;
+mod products;
+mod rescan;
+mod resume;
+mod sd_notify;
+mod state;
+mod status;
+mod syslog;
+mod template;
+mod user;
+mod watchdog;
+
+use self::cancel::{CancelToken, InFlight};
+use self::cli::{Cli, Command as CliCommand, GenerateTarget};
+use self::config::{Config, DeviceSettings, MountMode, Naming};
+use self::daemon::PidFile;
+use self::details::DeviceDetails;
+use self::health::HealthFile;
+use self::history::Event as HistoryEvent;
+use self::inhibit::Inhibitor;
+use self::lock::InstanceLock;
+use self::meta::{Metadata, MetadataMount};
+use self::mounts::MountEntry;
+use self::notify::Urgency;
+use self::poll::Poller;
+use self::rescan::Rescanner;
+use self::resume::ResumeDetector;
+use self::state::{MountedDevice, ScheduledRetry, State};
+use self::template::DeviceInfo;
+use self::user::User;
+use self::watchdog::{Heartbeat, Watchdog, HEARTBEAT_INTERVAL};
+
+const FUSE_CONF: &str = "/etc/fuse.conf";
+/// Interval between the mount attempts while the trust dialog is pending
+const TRUST_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+/// Interval between the lockdownd readiness checks
+const LOCKDOWN_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// Longest serial number accepted (UDIDs are 40 chars, or 25 with a dash)
+const MAX_SERIAL_NUMBER_LEN: usize = 64;
+/// Entries of a non-empty mountpoint named in the error
+const MAX_LISTED_ENTRIES: usize = 5;
+/// Max time to wait for the devices to be unmounted on exit
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+/// Time to wait before the lazy unmount of a busy mountpoint
+const LAZY_UNMOUNT_DELAY: Duration = Duration::from_millis(500);
+/// Max time to wait for the mountpoints to answer a `stat`
+const MOUNT_HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
+/// Max time spent in libusb before checking the shutdown flag
+const EVENT_LOOP_TIMEOUT: Duration = Duration::from_millis(250);
+/// Time to wait after a libusb event error
+const USB_ERROR_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Exit codes, by error class (documented in the README)
+const EXIT_FAILURE: u8 = 1;
+const EXIT_IFUSE_NOT_INSTALLED: u8 = 2;
+const EXIT_UNMOUNT_COMMAND_NOT_INSTALLED: u8 = 3;
+const EXIT_USB: u8 = 4;
+const EXIT_BASE_PATH: u8 = 5;
+const EXIT_ALREADY_RUNNING: u8 = 6;
+const EXIT_DAEMON_NOT_RUNNING: u8 = 7;
+/// `EX_USAGE`, from `sysexits.h`
+const EXIT_USAGE: u8 = 64;
+/// `EX_CONFIG`, from `sysexits.h`
+const EXIT_CONFIG: u8 = 78;
 
 const APPLE_VENDOR_ID: u16 = 0x05AC;
 
-const APPLE_PRODUCT_IDS: [u16; 25] = [
-    0x1290, // iPhone
-    0x1291, // iPod Touch 1.Gen
-    0x1292, // iPhone 3G
-    0x1293, // iPod Touch 2.Gen
-    0x1294, // iPhone 3GS
-    0x1296, // iPod Touch 3.Gen (8GB)
-    0x1297, // iPhone 4
-    0x1299, // iPod Touch 3.Gen
-    0x129a, // iPad
-    0x129c, // iPhone 4(CDMA)
-    0x129d, // iPhone
-    0x129e, // iPod Touch 4.Gen
-    0x129f, // iPad 2
-    0x12a0, // iPhone 4S
-    0x12a1, // iPhone
-    0x12a2, // iPad 2 (3G; 64GB)
-    0x12a3, // iPad 2 (CDMA)
-    0x12a4, // iPad 3 (wifi)
-    0x12a5, // iPad 3 (CDMA)
-    0x12a6, // iPad 3 (3G, 16 GB)
-    0x12a8, // iPhone 5/5C/5S/6/SE/7/8/X/XR
-    0x12a9, // iPad 2
-    0x12aa, // iPod Touch 5.Gen [A1421]
-    0x12ab, // iPad
-    0x12ac, // iPhone
+/// Named in [`products`]
+const APPLE_PRODUCT_IDS: [u16; products::COUNT] = products::ids();
+
+/// Apple products never mounted in permissive mode (inclusive ranges)
+const PERMISSIVE_DENIED_PRODUCT_IDS: [(u16, u16); 4] = [
+    (0x0200, 0x03ff), // Keyboards, mice and trackpads
+    (0x1220, 0x122f), // DFU and WTF modes
+    (0x1280, 0x1283), // Recovery mode
+    (0x8000, 0x8fff), // Built-in cameras, Bluetooth and hubs
 ];
 
-#[derive(Debug)]
+/// Interface of the usbmux protocol: vendor-specific class, subclass and protocol
+const USBMUX_INTERFACE: (u8, u8, u8) = (0xff, 0xfe, 2);
+
+#[derive(Debug, thiserror::Error)]
 enum Error {
-    Io(io::Error),
-    Usb(rusb::Error),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    /// File system operation that failed on a path
+    #[error("Can't {op} {}", .path.display())]
+    Fs {
+        /// e.g. `create`, `read`
+        op: &'static str,
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+    /// External command that couldn't be started
+    #[error("Can't run {program}")]
+    Spawn {
+        program: String,
+        #[source]
+        source: io::Error,
+    },
+    #[error(transparent)]
+    Usb(#[from] rusb::Error),
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+    /// ifuse failed
+    #[error("Can't mount device: {0}")]
     CantMount(String),
-    IfuseNotInstalled,
+    /// The unmount command (or `umount2`) failed
+    #[error("Can't unmount device: {0}")]
+    CantUnmount(String),
+    #[error("Base path not writable: {}", .0.display())]
+    BasePathNotWritable(PathBuf),
+    #[error("Invalid config file {}", .0.display())]
+    InvalidConfig(PathBuf, #[source] toml::de::Error),
+    #[error("Invalid `{0}`: {1}")]
+    InvalidSetting(String, String),
+    #[error("ifuse not installed: {}", .0.display())]
+    IfuseNotInstalled(PathBuf),
+    #[error("Unmount command not installed: {0}")]
+    UnmountCommandNotInstalled(String),
+    #[error(
+        "fusermount not installed (neither fusermount3 nor fusermount found, umount is used only as root)"
+    )]
+    FusermountNotInstalled,
+    #[error("Invalid serial number: {0:?}")]
+    InvalidSerialNumber(String),
+    #[error("Device not found")]
     DeviceNotFound,
+    #[error("Daemon not running")]
+    DaemonNotRunning,
+    #[error(
+        "allow_other requires `user_allow_other` in {} (or running as root)",
+        FUSE_CONF
+    )]
+    AllowOtherNotEnabled,
+    #[error("System mode requires root")]
+    SystemModeRequiresRoot,
+    #[error(
+        "AFC2 service not available: root mode requires a jailbroken device with AFC2 installed"
+    )]
+    Afc2NotAvailable,
+    #[error("{0} timed out after {} s", .1.as_secs())]
+    CommandTimeout(String, Duration),
+    #[error("lockdownd not ready on {0}")]
+    LockdownNotReady(String),
+    #[error("Pairing pending: accept the \"Trust This Computer\" dialog on {0}")]
+    PairingPending(String),
+    #[error("Can't pair {0}: unlock the device")]
+    DeviceLocked(String),
+    #[error("Pairing refused: the trust dialog was denied on {0}")]
+    PairingRefused(String),
+    #[error("The trust dialog was not accepted on {0} within {} s", .1.as_secs())]
+    TrustTimeout(String, Duration),
+    #[error("Can't pair {0}: {1}")]
+    CantPair(String, String),
+    /// Pairing record rejected by the device (e.g. after a restore)
+    #[error("Pairing record rejected by {0} (e.g. after a restore or a privacy reset)")]
+    InvalidPairing(String),
+    #[error("{0} is not paired")]
+    NotPaired(String),
+    #[error("Mountpoint missing: {}", .0.display())]
+    MountpointMissing(PathBuf),
+    /// FUSE not available or refusing the mount
+    #[error("FUSE error: {0}")]
+    Fuse(String),
+    #[error("Mountpoint busy: {}", .0.display())]
+    MountpointBusy(PathBuf),
+    #[error("Not mounted: {}", .0.display())]
+    NotMounted(PathBuf),
+    #[error("User not found: {0}")]
+    UserNotFound(String),
+    /// No default base path available, with what was tried
+    #[error("No base path available (tried {}): set one with --base-path", .0.join(", "))]
+    NoBasePath(Vec<String>),
+    /// Another instance holds the lock of the base path, with its PID if known
+    #[error("Already running{}", .0.map(|pid| format!(" (pid {pid})")).unwrap_or_default())]
+    AlreadyRunning(Option<u32>),
+    /// The device left during the mount
+    #[error("Device disconnected, mount canceled")]
+    Canceled,
+    /// Mountpoint with files in it, and some of them
+    #[error("Mountpoint not empty: {} (contains {})", .0.display(), .1.join(", "))]
+    MountpointNotEmpty(PathBuf, Vec<String>),
+    /// The handler thread is gone
+    #[error("The handler thread stopped")]
+    HandlerStopped,
 }
 
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+impl Error {
+    /// Attach the operation and the path to an I/O error, for `map_err`
+    fn fs(op: &'static str, path: &Path) -> impl FnOnce(io::Error) -> Self {
+        let path: PathBuf = path.to_path_buf();
+        move |source| Self::Fs { op, path, source }
+    }
+
+    /// The error followed by its sources, e.g. `Can't create /media/x: Permission denied (os error 13)`
+    fn report(&self) -> String {
+        let mut report: String = self.to_string();
+        let mut source: Option<&dyn std::error::Error> = std::error::Error::source(self);
+        while let Some(e) = source {
+            report.push_str(&format!(": {e}"));
+            source = e.source();
+        }
+        report
+    }
+
+    /// Whether a command failed to start because it's not installed
+    fn is_not_found(&self) -> bool {
+        matches!(self, Self::Spawn { source, .. } if source.kind() == io::ErrorKind::NotFound)
+    }
+
+    /// Class of the error, for the counters (e.g. `pairing`)
+    fn class(&self) -> &'static str {
         match self {
-            Self::Io(e) => write!(f, "{e}"),
-            Self::Usb(e) => write!(f, "{e}"),
-            Self::CantMount(e) => write!(f, "Can't mount device: {e}"),
-            Self::IfuseNotInstalled => write!(f, "ifuse not installed"),
-            Self::DeviceNotFound => write!(f, "Device not found"),
+            Self::Io(..) | Self::Fs { .. } | Self::Spawn { .. } => "io",
+            Self::Usb(..) => "usb",
+            Self::CantMount(..) => "ifuse",
+            Self::CantUnmount(..) => "unmount",
+            Self::CommandTimeout(..) => "timeout",
+            Self::LockdownNotReady(..) => "lockdown",
+            Self::PairingPending(..)
+            | Self::DeviceLocked(..)
+            | Self::PairingRefused(..)
+            | Self::TrustTimeout(..)
+            | Self::CantPair(..)
+            | Self::InvalidPairing(..)
+            | Self::NotPaired(..) => "pairing",
+            Self::Fuse(..) | Self::AllowOtherNotEnabled => "fuse",
+            Self::MountpointMissing(..)
+            | Self::MountpointBusy(..)
+            | Self::MountpointNotEmpty(..)
+            | Self::NotMounted(..) => "mountpoint",
+            Self::Canceled => "canceled",
+            Self::IfuseNotInstalled(..)
+            | Self::UnmountCommandNotInstalled(..)
+            | Self::FusermountNotInstalled => "not_installed",
+            _ => "other",
+        }
+    }
+
+    /// Exit code of the process, when the error stops it
+    fn exit_code(&self) -> u8 {
+        match self {
+            Self::IfuseNotInstalled(..) => EXIT_IFUSE_NOT_INSTALLED,
+            Self::UnmountCommandNotInstalled(..) | Self::FusermountNotInstalled => {
+                EXIT_UNMOUNT_COMMAND_NOT_INSTALLED
+            }
+            // e.g. hotplug unsupported, no access to the USB devices
+            Self::Usb(..) => EXIT_USB,
+            Self::BasePathNotWritable(..) | Self::NoBasePath(..) => EXIT_BASE_PATH,
+            Self::AlreadyRunning(..) => EXIT_ALREADY_RUNNING,
+            Self::DaemonNotRunning => EXIT_DAEMON_NOT_RUNNING,
+            Self::InvalidConfig(..)
+            | Self::InvalidSetting(..)
+            | Self::SystemModeRequiresRoot
+            | Self::UserNotFound(..)
+            | Self::AllowOtherNotEnabled => EXIT_CONFIG,
+            _ => EXIT_FAILURE,
         }
     }
-}
 
-impl From<io::Error> for Error {
-    fn from(e: io::Error) -> Self {
-        Self::Io(e)
+    /// Why the mount failed, if it can be retried
+    fn retry_reason(&self) -> Option<String> {
+        match self {
+            Self::CantMount(e) => Some(e.trim().to_string()),
+            Self::CommandTimeout(..)
+            | Self::LockdownNotReady(..)
+            | Self::PairingPending(..)
+            | Self::DeviceLocked(..) => Some(self.to_string()),
+            _ => None,
+        }
     }
-}
 
-impl From<rusb::Error> for Error {
-    fn from(e: rusb::Error) -> Self {
-        Self::Usb(e)
+    /// What the user can do about it, for the notifications
+    fn hint(&self) -> Option<&'static str> {
+        match self {
+            Self::DeviceLocked(..) => Some("Unlock the device to mount it"),
+            Self::PairingRefused(..) | Self::TrustTimeout(..) => {
+                Some("Plug it again and tap \"Trust\" to mount it")
+            }
+            Self::NotPaired(..) => Some("Pair it with `idevicepair pair`, or enable `pair`"),
+            Self::Fuse(..) => Some("Check that FUSE is installed and /dev/fuse is accessible"),
+            Self::IfuseNotInstalled(..) => Some("Install ifuse"),
+            _ => None,
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Action {
     Mount,
     Unmount,
 }
 
-#[derive(Clone, PartialEq, Eq, Hash)]
+impl Action {
+    #[inline]
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Mount => "mount",
+            Self::Unmount => "unmount",
+        }
+    }
+}
+
+/// Message processed by the handler thread
+enum Message<T>
+where
+    T: UsbContext,
+{
+    Device(Device<T>, Action),
+    /// Replace the active config
+    Reload(Box<Config>),
+    /// Unmount (if enabled) and stop, then notify the sender
+    Shutdown(mpsc::Sender<()>),
+    /// The devices connected at startup were all delivered
+    Enumerated,
+    /// The system is about to suspend: unmount everything, then release the inhibitor lock
+    Suspend,
+    /// The system resumed
+    Resume,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct DeviceAddr {
     bus: u8,
     addr: u8,
 }
 
+/// Unmount delayed after a departure, in case the device re-enumerates (or the mountpoint is busy)
+#[derive(Clone)]
+struct PendingUnmount {
+    info: DeviceInfo,
+    device: MountedDevice,
+    due: Instant,
+    /// Attempts that failed because the mountpoint was busy
+    busy_attempts: u32,
+}
+
+/// Mount scheduled after a failed attempt
+#[derive(Clone)]
+struct PendingRetry {
+    info: DeviceInfo,
+    settings: DeviceSettings,
+    /// Retries already done
+    attempt: u32,
+    due: Instant,
+    /// Errors of the previous attempts
+    errors: Vec<String>,
+    /// When the trust dialog was found pending
+    trust_since: Option<Instant>,
+    /// Already re-paired after an invalid pairing record
+    repaired: bool,
+}
+
+impl PendingRetry {
+    /// First attempt
+    #[inline]
+    fn new(info: DeviceInfo, settings: DeviceSettings) -> Self {
+        Self {
+            info,
+            settings,
+            attempt: 0,
+            due: Instant::now(),
+            errors: Vec::new(),
+            trust_since: None,
+            repaired: false,
+        }
+    }
+}
+
 #[derive(Clone)]
 struct Handler {
-    base_path: PathBuf,
-    /// Devices: bus address and serial number
-    devices: HashMap<DeviceAddr, String>,
+    config: Config,
+    /// Mounted devices, shared with the control socket
+    state: State,
+    /// Pending mount retries
+    retries: HashMap<DeviceAddr, PendingRetry>,
+    /// Devices recently disconnected, not unmounted yet
+    departures: HashMap<DeviceAddr, PendingUnmount>,
+    /// Last arrivals, by address and by serial number (debounce)
+    arrivals: HashMap<DeviceAddr, Instant>,
+    serial_arrivals: HashMap<String, Instant>,
+    /// Unknown devices that failed the lockdown probe, by serial number (permissive mode)
+    probe_failures: HashSet<String>,
+    /// Mounts in progress, canceled by the hotplug callbacks
+    in_flight: InFlight,
+    /// Cancellation of the current mount
+    current: Option<CancelToken>,
+    /// Handling the devices connected at startup
+    enumerating: bool,
+    /// Number of mounted devices last reported to systemd
+    reported_mounts: Option<usize>,
+    /// Liveness, for the systemd watchdog
+    heartbeat: Heartbeat,
+    /// Shutting down: not restarted after a panic
+    stopping: bool,
+    /// Suspend inhibitor lock, held while a device is mounted (`inhibit_suspend`)
+    inhibitor: Option<Inhibitor>,
+    /// Rescan after a resume (the devices that stayed connected fire no hotplug event)
+    rescanner: Option<Rescanner>,
+    /// Between `PrepareForSleep` and the resume: the lock isn't taken again
+    sleeping: bool,
 }
 
 impl Handler {
     #[inline]
-    fn new(base_path: PathBuf) -> Self {
+    fn new(config: Config, state: State) -> Self {
+        state.set_usb_reset(config.usb_reset);
         Self {
-            base_path,
-            devices: HashMap::new(),
+            config,
+            state,
+            retries: HashMap::new(),
+            departures: HashMap::new(),
+            arrivals: HashMap::new(),
+            serial_arrivals: HashMap::new(),
+            probe_failures: HashSet::new(),
+            in_flight: InFlight::default(),
+            current: None,
+            enumerating: false,
+            reported_mounts: None,
+            heartbeat: Heartbeat::default(),
+            stopping: false,
+            inhibitor: None,
+            rescanner: None,
+            sleeping: false,
+        }
+    }
+
+    /// Record the stage of the current mount, for the state dump
+    #[inline]
+    fn enter(&self, stage: &'static str) {
+        if let Some(token) = &self.current {
+            token.enter(stage);
+        }
+    }
+
+    /// Publish the scheduled retries, for the state dump
+    fn sync_retries(&self) {
+        let retries: Vec<ScheduledRetry> = self
+            .retries
+            .values()
+            .map(|retry| ScheduledRetry {
+                serial_number: retry.info.serial_number.clone(),
+                attempt: retry.attempt,
+                due: retry.due,
+                trust_pending: retry.trust_since.is_some(),
+            })
+            .collect();
+        self.state.set_retries(retries);
+    }
+
+    /// Bail out if the device of the current mount left
+    #[inline]
+    fn check_canceled(&self) -> Result<(), Error> {
+        // Between the steps of a mount
+        self.heartbeat.beat();
+
+        match &self.current {
+            Some(token) if token.is_canceled() => Err(Error::Canceled),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remember that the device was just handled
+    fn record_arrival(&mut self, addr: &DeviceAddr, serial_number: &str) {
+        let now: Instant = Instant::now();
+        let debounce: Duration = self.config.debounce;
+
+        // Forget the old ones
+        self.arrivals
+            .retain(|_, at| now.duration_since(*at) < debounce);
+        self.serial_arrivals
+            .retain(|_, at| now.duration_since(*at) < debounce);
+
+        self.arrivals.insert(addr.clone(), now);
+        self.serial_arrivals.insert(serial_number.to_string(), now);
+    }
+
+    #[inline]
+    fn arrived_recently(&self, addr: &DeviceAddr) -> bool {
+        self.arrivals
+            .get(addr)
+            .is_some_and(|at| at.elapsed() < self.config.debounce)
+    }
+
+    /// Duplicate arrival of a device already mounted (or waiting for a retry)
+    fn is_duplicate_arrival(&self, addr: &DeviceAddr) -> bool {
+        self.arrived_recently(addr)
+            && (self.state.contains(addr) || self.retries.contains_key(addr))
+    }
+
+    /// Same as [`Handler::is_duplicate_arrival`], by serial number (e.g. new address after a reset)
+    fn is_duplicate_serial(&self, serial_number: &str) -> bool {
+        let recent: bool = self
+            .serial_arrivals
+            .get(serial_number)
+            .is_some_and(|at| at.elapsed() < self.config.debounce);
+        recent
+            && (self
+                .state
+                .devices()
+                .iter()
+                .any(|device| device.serial_number == serial_number)
+                || self
+                    .retries
+                    .values()
+                    .any(|retry| retry.info.serial_number == serial_number))
+    }
+
+    /// Run the handler in a dedicated thread
+    ///
+    /// A panic is logged and the handler restarts from scratch, keeping the mounted devices.
+    fn spawn(self, rx: mpsc::Receiver<Message<Context>>) -> JoinHandle<()> {
+        thread::spawn(move || {
+            let mut handler: Self = self;
+            loop {
+                match panic::catch_unwind(AssertUnwindSafe(|| handler.run(&rx))) {
+                    Ok(()) => return,
+                    Err(payload) if handler.stopping => {
+                        tracing::error!(
+                            "The handler thread panicked while shutting down: {}",
+                            panic_message(payload.as_ref())
+                        );
+                        return;
+                    }
+                    Err(payload) => {
+                        tracing::error!(
+                            "The handler thread panicked: {}, restarting it",
+                            panic_message(payload.as_ref())
+                        );
+                        handler = handler.restart();
+                    }
+                }
+            }
+        })
+    }
+
+    /// Fresh handler, with the same config and mounted devices
+    ///
+    /// The pending retries and delayed unmounts are dropped: the departed devices are cleaned up as
+    /// untracked ones.
+    fn restart(self) -> Self {
+        if !self.retries.is_empty() || !self.departures.is_empty() {
+            tracing::warn!(
+                "Dropping {} pending retry(ies) and {} pending unmount(s)",
+                self.retries.len(),
+                self.departures.len()
+            );
+        }
+        self.in_flight.clear();
+
+        let mut handler: Self = Self::new(self.config, self.state);
+        handler.in_flight = self.in_flight;
+        handler.heartbeat = self.heartbeat;
+        handler.inhibitor = self.inhibitor;
+        handler.rescanner = self.rescanner;
+        handler.sleeping = self.sleeping;
+        handler.sync_pending_unmounts();
+        handler
+    }
+
+    /// Hold the suspend inhibitor lock while a device is mounted, except right before a suspend
+    #[inline]
+    fn sync_inhibitor(&self) {
+        if let Some(inhibitor) = &self.inhibitor {
+            inhibitor.hold(!self.sleeping && !self.state.devices().is_empty());
         }
     }
 
-    fn spawn(mut self, rx: mpsc::Receiver<(Device<Context>, Action)>) {
-        thread::spawn(move || loop {
-            match rx.recv() {
-                Ok((device, action)) => {
+    /// Process the messages until the shutdown (or until the senders are gone)
+    fn run(&mut self, rx: &mpsc::Receiver<Message<Context>>) {
+        loop {
+            self.heartbeat.beat();
+            self.report_status();
+            self.sync_retries();
+            self.sync_inhibitor();
+
+            // Wait for the next message, or until the next retry (or delayed unmount) is due,
+            // waking up to beat while idle
+            let heartbeat: Instant = Instant::now() + HEARTBEAT_INTERVAL;
+            let due: Instant = match self.next_due() {
+                Some(due) => due.min(heartbeat),
+                None => heartbeat,
+            };
+            let message: Result<Message<Context>, RecvTimeoutError> =
+                rx.recv_timeout(due.saturating_duration_since(Instant::now()));
+
+            match message {
+                Ok(Message::Device(device, action)) => {
+                    self.state.event_received();
+
+                    // Context of the logs of the event, the serial number is recorded once read
+                    let (bus, addr): (u8, u8) = (device.bus_number(), device.address());
+                    let span: Span = tracing::info_span!(
+                        "device",
+                        bus,
+                        addr,
+                        action = action.as_str(),
+                        serial = field::Empty
+                    );
+                    let _entered: span::Entered<'_> = span.enter();
+
                     if let Err(e) = self.handle_device(device, action) {
-                        eprintln!("{e}");
+                        if let Error::Canceled = e {
+                            tracing::info!("{e}");
+                            continue;
+                        }
+
+                        tracing::error!(
+                            error_class = e.class(),
+                            "Can't {} the device at {bus:03}:{addr:03}: {}",
+                            action.as_str(),
+                            e.report()
+                        );
+                        self.state.record_error(format!(
+                            "{} {bus:03}:{addr:03}: {}",
+                            action.as_str(),
+                            e.report()
+                        ));
+
+                        if action == Action::Mount {
+                            self.mount_failed(&e);
+                        }
                     }
                 }
-                Err(e) => eprintln!("{e}"),
+                Ok(Message::Reload(config)) => self.reload(*config),
+                Ok(Message::Enumerated) => self.enumerating = false,
+                // Without the inhibitor lock, the system doesn't wait for the unmounts
+                Ok(Message::Suspend) if self.inhibitor.is_some() => self.prepare_for_sleep(),
+                Ok(Message::Suspend) => {}
+                Ok(Message::Resume) => self.resume(),
+                Ok(Message::Shutdown(done)) => {
+                    self.stopping = true;
+                    self.shutdown();
+                    let _ = done.send(());
+                    return;
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    self.run_due_unmounts();
+                    self.run_due_retries();
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    tracing::error!("No more events to handle, stopping the handler thread");
+                    return;
+                }
             }
-        });
+        }
     }
 
-    fn handle_device<T>(&mut self, device: Device<T>, action: Action) -> Result<(), Error>
-    where
-        T: UsbContext,
-    {
-        // Check again if ifuse is installed
-        if !is_ifuse_installed() {
-            return Err(Error::IfuseNotInstalled);
+    #[inline]
+    fn next_retry(&self) -> Option<Instant> {
+        self.retries.values().map(|retry| retry.due).min()
+    }
+
+    #[inline]
+    fn next_due(&self) -> Option<Instant> {
+        let next_unmount: Option<Instant> = self.departures.values().map(|d| d.due).min();
+        match (self.next_retry(), next_unmount) {
+            (Some(retry), Some(unmount)) => Some(retry.min(unmount)),
+            (retry, unmount) => retry.or(unmount),
         }
+    }
 
-        // Wait a little before proceeding
-        thread::sleep(Duration::from_millis(500));
+    fn run_due_retries(&mut self) {
+        let now: Instant = Instant::now();
+        let due: Vec<DeviceAddr> = self
+            .retries
+            .iter()
+            .filter(|(_, retry)| retry.due <= now)
+            .map(|(addr, _)| addr.clone())
+            .collect();
 
-        // Get device descriptor
-        let descriptor: DeviceDescriptor = device.device_descriptor()?;
+        for addr in due {
+            if let Some(retry) = self.retries.remove(&addr) {
+                let span: Span = tracing::info_span!(
+                    "device",
+                    bus = addr.bus,
+                    addr = addr.addr,
+                    action = Action::Mount.as_str(),
+                    serial = retry.info.serial_number.as_str()
+                );
+                let _entered: span::Entered<'_> = span.enter();
 
-        let vendor_id: u16 = descriptor.vendor_id();
-        let product_id: u16 = descriptor.product_id();
+                self.state.count_retry(&retry.info.serial_number);
+                match retry.trust_since {
+                    Some(..) => tracing::debug!(
+                        "Retrying to mount {}, waiting for the trust dialog",
+                        retry.info.serial_number
+                    ),
+                    None => tracing::info!(
+                        "Retrying to mount {} ({}/{})",
+                        retry.info.serial_number,
+                        retry.attempt,
+                        self.config.mount_retries
+                    ),
+                }
 
-        // Check if it's an apple device
-        if !is_apple_device(vendor_id, product_id) {
-            return Ok(());
+                let retry_serial: String = retry.info.serial_number.clone();
+                self.current = Some(self.in_flight.begin(&addr));
+                let result: Result<(), Error> = self.mount_or_retry(retry);
+                self.in_flight.end(&addr);
+                self.current = None;
+
+                match result {
+                    Ok(()) => {}
+                    Err(e @ Error::Canceled) => tracing::info!("{e}"),
+                    Err(e) => {
+                        tracing::error!(
+                            error_class = e.class(),
+                            "Can't mount {retry_serial}: {}",
+                            e.report()
+                        );
+                        self.state
+                            .record_error(format!("mount {retry_serial}: {}", e.report()));
+                        self.mount_failed(&e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Mount the device, scheduling a retry if ifuse fails
+    ///
+    /// While the trust dialog is pending, the mount is retried at a fixed interval (without consuming the retries),
+    /// until `trust_timeout`. Once the retries are exhausted, the errors of all the attempts are returned.
+    fn mount_or_retry(&mut self, mut retry: PendingRetry) -> Result<(), Error> {
+        self.state.count_mount_attempt();
+        let e: Error = match self.mount(retry.info.clone(), &retry.settings) {
+            Ok(paths) => {
+                self.state.count_mount_success(&retry.info.serial_number);
+                self.record_history(history::Entry::new(
+                    HistoryEvent::Mount,
+                    &retry.info.serial_number,
+                    &paths,
+                ));
+                return Ok(());
+            }
+            Err(e) => e,
+        };
+        self.state
+            .count_mount_failure(&retry.info.serial_number, e.class());
+        if !matches!(e, Error::Canceled) {
+            let paths: Vec<PathBuf> = retry
+                .settings
+                .mounts
+                .iter()
+                .map(|spec| self.config.mountpoint(&retry.info, spec))
+                .collect();
+            self.record_history(
+                history::Entry::new(HistoryEvent::Mount, &retry.info.serial_number, &paths)
+                    .failed(&e),
+            );
         }
 
-        // Get device address
         let addr: DeviceAddr = DeviceAddr {
-            bus: device.bus_number(),
-            addr: device.address(),
+            bus: retry.info.bus,
+            addr: retry.info.address,
         };
 
-        match action {
-            Action::Mount => {
-                println!("Opening device: vendor_id={vendor_id}, product_id={product_id}");
+        // Stale pairing record: pair again, once per plug
+        if matches!(e, Error::InvalidPairing(..)) && !retry.repaired && !self.config.dry_run {
+            tracing::warn!("{e}, pairing again");
+            retry.repaired = true;
+
+            match repair_device(&retry.info.serial_number, self.config.command_timeout) {
+                Ok(()) => {
+                    self.notify(
+                        "Trust this computer again",
+                        format!(
+                            "The pairing of {} was reset: unlock it and tap \"Trust\" to mount it",
+                            display_name(&retry.info)
+                        ),
+                        Urgency::Critical,
+                    );
+                    // Already notified
+                    retry.trust_since = Some(Instant::now());
+                    return self.mount_or_retry(retry);
+                }
+                Err(e) => tracing::warn!(
+                    "Can't pair {} again: {}",
+                    retry.info.serial_number,
+                    e.report()
+                ),
+            }
 
-                let serial_number: String = {
-                    // Open device
-                    let handle: DeviceHandle<T> = device.open()?;
+            return Err(e);
+        }
 
-                    // Reset state
-                    handle.reset()?;
+        // Wait for the user to tap "Trust"
+        if matches!(e, Error::PairingPending(..)) && !self.config.trust_timeout.is_zero() {
+            let since: Instant = match retry.trust_since {
+                Some(since) => since,
+                None => {
+                    // Tell the user what to do, once
+                    tracing::warn!("{e}");
+                    self.notify(
+                        "Trust this computer",
+                        format!(
+                            "Unlock {} and tap \"Trust\" to mount it",
+                            display_name(&retry.info)
+                        ),
+                        Urgency::Critical,
+                    );
+                    Instant::now()
+                }
+            };
 
-                    let languages: Vec<Language> = handle.read_languages(TIMEOUT)?;
+            if since.elapsed() >= self.config.trust_timeout {
+                return Err(Error::TrustTimeout(
+                    retry.info.serial_number,
+                    self.config.trust_timeout,
+                ));
+            }
 
-                    if languages.is_empty() {
-                        return Err(Error::CantMount(String::from("Languages empty")));
-                    }
+            retry.trust_since = Some(since);
+            retry.due = Instant::now() + TRUST_RETRY_INTERVAL;
+            self.retries.insert(addr, retry);
+            return Ok(());
+        }
 
-                    // Read serial number
-                    let language: Language = languages[0];
-                    let serial_number: String =
-                        handle.read_serial_number_string(language, &descriptor, TIMEOUT)?;
+        match e.retry_reason() {
+            Some(reason) if retry.attempt < self.config.mount_retries => {
+                let delay: Duration = self.config.retry_delay(retry.attempt);
+                tracing::warn!(
+                    "Can't mount {}, retrying in {} ms: {reason}",
+                    retry.info.serial_number,
+                    delay.as_millis(),
+                );
+                retry.errors.push(reason);
+                retry.attempt += 1;
+                retry.due = Instant::now() + delay;
+                self.retries.insert(addr, retry);
 
-                    // Return serial number
-                    serial_number
-                };
+                Ok(())
+            }
+            Some(reason) if !retry.errors.is_empty() => {
+                retry.errors.push(reason);
+                Err(Error::CantMount(format!(
+                    "{} failed after {} attempts: {}",
+                    retry.info.serial_number,
+                    retry.errors.len(),
+                    retry.errors.join("; ")
+                )))
+            }
+            _ => Err(e),
+        }
+    }
+
+    fn reload(&mut self, config: Config) {
+        let changes: Vec<String> = self.config.reload(config);
+        self.state.set_usb_reset(self.config.usb_reset);
+
+        if changes.is_empty() {
+            tracing::info!("Config reloaded: nothing changed");
+            return;
+        }
+
+        tracing::info!("Config reloaded:");
+        for change in changes.iter() {
+            tracing::info!("  {change}");
+        }
+    }
+
+    /// Create the mountpoints, mount the device and track it
+    ///
+    /// Paths that already have an ifuse mount (e.g. after a restart) are tracked without mounting again.
+    /// If a mount fails, the previous ones are unmounted.
+    fn mount(
+        &mut self,
+        info: DeviceInfo,
+        settings: &DeviceSettings,
+    ) -> Result<Vec<PathBuf>, Error> {
+        let paths: Vec<PathBuf> = settings
+            .mounts
+            .iter()
+            .map(|spec| self.config.mountpoint(&info, spec))
+            .collect();
+        let serial_number: String = info.serial_number.clone();
+        let user: Option<User> = self.mount_user()?;
+
+        if self.config.dry_run {
+            for path in paths.iter() {
+                tracing::info!("[dry-run] Would mount device at {}", path.display());
+            }
+            self.track(
+                &info,
+                paths.clone(),
+                settings.read_only,
+                None,
+                DeviceDetails::default(),
+            );
+            return Ok(paths);
+        }
 
-                println!("Found an Apple device: serial_number={serial_number}");
+        let options: Vec<String> = settings.ifuse_options();
+        if options.iter().any(|o| o == "allow_other") && !is_allow_other_permitted(user.as_ref()) {
+            return Err(Error::AllowOtherNotEnabled);
+        }
 
-                let path: PathBuf = self.base_path.join(&serial_number);
+        // Pair the device, ifuse fails if it isn't
+        if self.config.pair {
+            self.enter("pairing");
+            pair_device(&serial_number, self.config.command_timeout)?;
+            self.check_canceled()?;
+        }
 
-                // Create directory
-                println!("Creating directory: {}", path.display());
-                fs::create_dir_all(&path)?;
+        // Wait for lockdownd, ifuse fails if it isn't ready yet
+        if !self.config.lockdown_timeout.is_zero() {
+            self.enter("waiting for lockdownd");
+            wait_for_lockdown(
+                &serial_number,
+                self.config.lockdown_timeout,
+                self.config.command_timeout,
+            )?;
+            self.check_canceled()?;
+        }
 
-                // Mount device with ifuse
-                println!("Mounting device at {}", path.display());
-                ifuse_mount(path)?;
+        // Check the mount table, to avoid mounting twice at the same path
+        let mount_table: Vec<MountEntry> = mounts::read().unwrap_or_else(|e| {
+            tracing::warn!("Can't read the mount table: {e}");
+            Vec::new()
+        });
 
-                // TODO: schedule for a retry if `ifuse_mount` fails
+        let mut mounted: Vec<&PathBuf> = Vec::with_capacity(paths.len());
+        self.enter("mounting");
 
-                // Insert into devices
-                self.devices.insert(addr, serial_number);
+        for (spec, path) in settings.mounts.iter().zip(paths.iter()) {
+            if mounts::find(&mount_table, path).is_some_and(MountEntry::is_ifuse) {
+                tracing::info!("Device already mounted at {}", path.display());
+                continue;
             }
-            Action::Unmount => {
-                println!("Unmounting device: vendor_id={vendor_id}, product_id={product_id}");
-                match self.devices.remove(&addr) {
-                    Some(serial_number) => {
-                        let path: PathBuf = self.base_path.join(&serial_number);
-                        println!("Unmounting device from {}", path.display());
-                        ifuse_unmount(path)?;
+
+            // Unplugged meanwhile: roll back
+            let result: Result<(), Error> = self.check_canceled().and_then(|()| {
+                self.mount_one(path, &serial_number, &spec.mode, &options, user.as_ref())
+            });
+            if let Err(e) = result {
+                // Roll back
+                for path in mounted.iter().rev() {
+                    match ifuse_unmount(&self.config, path, user.as_ref()) {
+                        Ok(()) if !self.config.keep_mountpoints => {
+                            remove_mountpoint(&self.config.base_path, path)
+                        }
+                        Ok(()) => {}
+                        Err(e) => {
+                            tracing::warn!("Can't unmount {}: {}", path.display(), e.report())
+                        }
+                    }
+                }
+
+                // Don't leave the directory behind, even if the mount is retried later
+                // (the device may be unplugged before)
+                if !self.config.keep_mountpoints
+                    && path.is_dir()
+                    && !matches!(e, Error::MountpointNotEmpty(..))
+                {
+                    remove_mountpoint(&self.config.base_path, path);
+                }
+
+                return match e {
+                    Error::CantMount(e) if settings.mounts.len() > 1 => Err(Error::CantMount(
+                        format!("{} ({}): {e}", path.display(), spec.mode),
+                    )),
+                    e => Err(e),
+                };
+            }
+
+            mounted.push(path);
+        }
+
+        // Best-effort, once mounted
+        self.enter("querying the device details");
+        let details: DeviceDetails = details::query(&serial_number);
+
+        // For the scripts consuming the mounts
+        let metadata: Metadata = Metadata {
+            serial_number: serial_number.clone(),
+            mounts: settings
+                .mounts
+                .iter()
+                .zip(paths.iter())
+                .map(|(spec, path)| MetadataMount {
+                    mountpoint: path.clone(),
+                    mode: spec.mode.clone(),
+                })
+                .collect(),
+            vendor_id: info.vendor_id,
+            product_id: info.product_id,
+            name: details.device_name.clone().or_else(|| info.name.clone()),
+            mounted_at: state::now(),
+        };
+        if let Err(e) = meta::write(&self.config.base_path, &metadata) {
+            tracing::warn!("Can't write metadata of {serial_number}: {}", e.report());
+        }
+        match details.is_empty() {
+            true => tracing::info!(
+                mountpoint = %join_paths(&paths),
+                "Mounted {serial_number} ({}) at {}",
+                products::name(info.vendor_id, info.product_id),
+                join_paths(&paths)
+            ),
+            false => tracing::info!(
+                mountpoint = %join_paths(&paths),
+                "Mounted {serial_number} ({}) at {} ({})",
+                products::name(info.vendor_id, info.product_id),
+                join_paths(&paths),
+                details.summary()
+            ),
+        }
+
+        // Insert into devices
+        let user: Option<String> = user.map(|user| user.name);
+        self.track(&info, paths.clone(), settings.read_only, user, details);
+
+        // Friendly-name link to the device directory
+        if self.config.friendly_links {
+            let name: Option<String> =
+                device_name(&info.serial_number, self.config.command_timeout)
+                    .or_else(|| info.name.clone());
+            match name {
+                Some(name) => {
+                    let target: PathBuf = self.config.device_dir(&info, &settings.mounts);
+                    if let Err(e) = links::create(&self.config.base_path, &name, &target) {
+                        tracing::warn!(
+                            "Can't create link for {}: {}",
+                            info.serial_number,
+                            e.report()
+                        );
                     }
-                    None => return Err(Error::DeviceNotFound),
                 }
+                None => tracing::warn!("Can't get the name of {}, no link", info.serial_number),
             }
         }
 
-        Ok(())
+        // Run hook (only for the new mounts)
+        for path in mounted.iter() {
+            hooks::run(
+                "on_mount",
+                &self.config.on_mount,
+                &info,
+                path,
+                self.config.hook_timeout,
+            );
+        }
+
+        if !mounted.is_empty() {
+            self.notify(
+                format!("{} mounted", display_name(&info)),
+                format!("Mounted at {}", join_paths(&paths)),
+                Urgency::Normal,
+            );
+
+            // Not at startup: a window for each connected device after every reboot
+            if self.config.open_on_mount && !self.enumerating {
+                let dir: PathBuf = self.config.device_dir(&info, &settings.mounts);
+                let user: Option<User> = self.mount_user().ok().flatten();
+                hooks::run_as(
+                    "open",
+                    &self.config.open_command,
+                    &info,
+                    &dir,
+                    self.config.hook_timeout,
+                    user.as_ref(),
+                );
+            }
+        }
+
+        Ok(paths)
     }
-}
 
-struct HotPlugHandler<T>
-where
-    T: UsbContext,
-{
-    tx: mpsc::Sender<(Device<T>, Action)>,
-}
+    fn mount_one(
+        &self,
+        path: &Path,
+        serial_number: &str,
+        mode: &MountMode,
+        options: &[String],
+        user: Option<&User>,
+    ) -> Result<(), Error> {
+        // Never escape the base path
+        if !is_under_base_path(&self.config.base_path, path) {
+            return Err(Error::CantMount(format!(
+                "{} is not under the base path",
+                path.display()
+            )));
+        }
 
-// Send device and action with the mpsc channel because this method mustn't block.
-// If this method blocks, "resources busy" error will start appearing.
-impl<T> Hotplug<T> for HotPlugHandler<T>
-where
-    T: UsbContext,
-{
-    fn device_arrived(&mut self, device: Device<T>) {
-        if let Err(e) = self.tx.send((device, Action::Mount)) {
-            eprintln!("{e}");
+        // ifuse refuses to mount over files
+        if let Some(entries) = dir_entries(path) {
+            if !self.config.backup_nonempty_mountpoints {
+                return Err(Error::MountpointNotEmpty(path.to_path_buf(), entries));
+            }
+
+            let backup: PathBuf = backup_path(path);
+            tracing::warn!(
+                "{} is not empty, moving it to {}",
+                path.display(),
+                backup.display()
+            );
+            fs::rename(path, &backup).map_err(Error::fs("rename", path))?;
+        }
+
+        // Create directory
+        tracing::info!("Creating directory: {}", path.display());
+        fs::create_dir_all(path).map_err(Error::fs("create", path))?;
+        set_dir_permissions(&self.config, path)?;
+
+        // fusermount requires the user to own the mountpoint
+        if let Some(user) = user {
+            if self.config.dir_uid.is_none() {
+                std::os::unix::fs::chown(path, Some(user.uid), Some(user.gid))
+                    .map_err(Error::fs("change the owner of", path))?;
+            }
+        }
+
+        // Mount device with ifuse
+        match user {
+            Some(user) => tracing::info!(
+                mountpoint = %path.display(),
+                "Mounting device at {} as {}",
+                path.display(),
+                user.name
+            ),
+            None => tracing::info!(
+                mountpoint = %path.display(),
+                "Mounting device at {}",
+                path.display()
+            ),
         }
+        let start: Instant = Instant::now();
+        ifuse_mount(
+            &self.config.ifuse_path,
+            path,
+            serial_number,
+            mode,
+            options,
+            user,
+            self.config.command_timeout,
+        )?;
+
+        // ifuse may exit successfully without mounting (e.g. half-dead usbmuxd)
+        let verified_by: &str = verify_mount(path)?;
+        tracing::debug!(
+            mountpoint = %path.display(),
+            "Mounted {} in {} ms (verified by {verified_by})",
+            path.display(),
+            start.elapsed().as_millis()
+        );
+
+        Ok(())
     }
 
-    fn device_left(&mut self, device: Device<T>) {
-        if let Err(e) = self.tx.send((device, Action::Unmount)) {
-            eprintln!("{e}");
+    #[inline]
+    fn track(
+        &self,
+        info: &DeviceInfo,
+        mountpoints: Vec<PathBuf>,
+        read_only: bool,
+        user: Option<String>,
+        details: DeviceDetails,
+    ) {
+        let addr: DeviceAddr = DeviceAddr {
+            bus: info.bus,
+            addr: info.address,
+        };
+        let device: MountedDevice = MountedDevice {
+            serial_number: info.serial_number.clone(),
+            mountpoints,
+            read_only,
+            bus: info.bus,
+            address: info.address,
+            vendor_id: info.vendor_id,
+            product_id: info.product_id,
+            name: info.name.clone(),
+            model: info.model.clone(),
+            mounted_at: state::now(),
+            user,
+            details,
+        };
+        self.state.insert(addr, device);
+    }
+
+    /// Report the number of mounted devices to systemd, when it changes
+    fn report_status(&mut self) {
+        let count: usize = self.state.devices().len();
+        if self.reported_mounts != Some(count) {
+            self.reported_mounts = Some(count);
+            sd_notify::status(&format!("{count} device(s) mounted"));
         }
     }
-}
 
-#[inline]
-fn is_apple_device(vendor_id: u16, product_id: u16) -> bool {
-    APPLE_VENDOR_ID == vendor_id && APPLE_PRODUCT_IDS.contains(&product_id)
-}
+    /// Count the failure and tell the user
+    fn mount_failed(&self, e: &Error) {
+        self.state.record_mount_failure();
 
-fn is_ifuse_installed() -> bool {
-    let output = Command::new("ifuse")
-        .arg("--version")
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .status();
-    matches!(output, Ok(status) if status.success())
-}
+        let body: String = match e.hint() {
+            Some(hint) => format!("{}\n{hint}", e.report()),
+            None => e.report(),
+        };
+        self.notify("Can't mount device", body, Urgency::Critical);
+    }
 
-fn ifuse_mount<P>(path: P) -> Result<(), Error>
-where
-    P: AsRef<Path>,
-{
-    // Run command
-    // `ifuse /path/where/to/mount`
-    let output: Output = Command::new("ifuse")
-        .arg(path.as_ref())
-        .stdout(Stdio::null())
-        .output()?;
+    /// Append to the mount history, if enabled
+    fn record_history(&self, entry: history::Entry) {
+        let path: &Path = match &self.config.history_file {
+            Some(path) if !self.config.dry_run => path,
+            _ => return,
+        };
 
-    // Check status
-    if !output.status.success() {
-        let err = String::from_utf8_lossy(&output.stderr);
-        return Err(Error::CantMount(err.to_string()));
+        if let Err(e) = history::append(path, &entry) {
+            tracing::warn!(
+                "Can't write the mount history to {}: {}",
+                path.display(),
+                e.report()
+            );
+        }
     }
 
-    Ok(())
-}
+    /// Marketing name of the device, made unique among the mounted ones
+    fn model_name(&self, info: &DeviceInfo) -> Option<String> {
+        let mut others: Vec<MountedDevice> = self.state.devices();
+        others.extend(
+            self.departures
+                .values()
+                .map(|pending| pending.device.clone()),
+        );
+        model_name(info, &others, self.config.command_timeout)
+    }
 
-fn ifuse_unmount<P>(path: P) -> Result<(), Error>
-where
-    P: AsRef<Path>,
-{
-    // Run command
-    // `fusermount -u /path/to/mounted/device`
-    let output: Output = Command::new("fusermount")
-        .arg("-u")
-        .arg(path.as_ref())
-        .stdout(Stdio::null())
-        .output()?;
+    /// Show a desktop notification, if enabled
+    fn notify<S, B>(&self, summary: S, body: B, urgency: Urgency)
+    where
+        S: Into<String>,
+        B: Into<String>,
+    {
+        if !self.config.notifications || self.config.dry_run {
+            return;
+        }
 
-    // Check status
-    if !output.status.success() {
-        let err = String::from_utf8_lossy(&output.stderr);
-        return Err(Error::CantMount(err.to_string()));
+        // The session of the mount user, when running as root
+        let user: Option<User> = self.mount_user().ok().flatten();
+        notify::send(summary, body, urgency, user);
     }
 
-    Ok(())
-}
+    /// User running ifuse: the configured one, or the active session's user in system mode
+    ///
+    /// `None` to run it as the daemon's user.
+    fn mount_user(&self) -> Result<Option<User>, Error> {
+        match &self.config.user {
+            Some(name) => user::lookup(name).map(Some),
+            None if self.config.system => Ok(user::active()),
+            None => Ok(None),
+        }
+    }
 
-fn main() -> Result<(), Error> {
-    // Check if supported
-    if !rusb::has_hotplug() {
-        panic!("libusb hotplug api unsupported");
+    /// Unmount all the mountpoints of the device, in reverse order
+    ///
+    /// The busy mountpoints are unmounted lazily.
+    #[inline]
+    fn unmount_device(&self, info: &DeviceInfo, device: &MountedDevice) -> Result<(), Error> {
+        self.unmount_paths(info, device, true).map(|_| ())
     }
 
-    // Check if ifuse is installed
-    if !is_ifuse_installed() {
-        return Err(Error::IfuseNotInstalled);
+    /// Unmount the device, or retry later if a mountpoint is busy (e.g. a thumbnailer has files
+    /// open), up to `busy_unmount_retries` times before unmounting it lazily
+    fn unmount_or_defer(
+        &mut self,
+        addr: DeviceAddr,
+        info: DeviceInfo,
+        mut device: MountedDevice,
+        busy_attempts: u32,
+    ) -> Result<(), Error> {
+        let lazy: bool = busy_attempts >= self.config.busy_unmount_retries;
+        let busy: Vec<PathBuf> = self.unmount_paths(&info, &device, lazy)?;
+
+        if !busy.is_empty() {
+            let busy_attempts: u32 = busy_attempts + 1;
+            tracing::warn!(
+                "{} busy, retrying to unmount in {} ms ({busy_attempts}/{})",
+                busy.iter()
+                    .map(|path| path.display().to_string())
+                    .collect::<Vec<String>>()
+                    .join(", "),
+                self.config.busy_unmount_interval.as_millis(),
+                self.config.busy_unmount_retries
+            );
+
+            device.mountpoints = busy;
+            self.departures.insert(
+                addr,
+                PendingUnmount {
+                    info,
+                    device,
+                    due: Instant::now() + self.config.busy_unmount_interval,
+                    busy_attempts,
+                },
+            );
+        }
+
+        self.sync_pending_unmounts();
+        Ok(())
     }
 
-    // Compose path
-    let runtime_dir: PathBuf = dirs::runtime_dir().expect("home dir not found");
-    let base_path: PathBuf = runtime_dir.join("ifuse-automount");
+    /// Publish the devices stuck on a busy mountpoint, for the status
+    fn sync_pending_unmounts(&self) {
+        let pending: Vec<MountedDevice> = self
+            .departures
+            .values()
+            .filter(|departure| departure.busy_attempts > 0)
+            .map(|departure| departure.device.clone())
+            .collect();
+        self.state.set_pending_unmounts(pending);
+    }
 
-    let (tx, rx) = mpsc::channel();
-    let hotplug_handler = HotPlugHandler { tx };
+    /// Unmount the mountpoints of the device, in reverse order
+    ///
+    /// Returns the busy mountpoints, if not unmounted `lazy`-ly.
+    fn unmount_paths(
+        &self,
+        info: &DeviceInfo,
+        device: &MountedDevice,
+        lazy: bool,
+    ) -> Result<Vec<PathBuf>, Error> {
+        // Same identity as the mount
+        let user: Option<User> = device
+            .user
+            .as_deref()
+            .and_then(|name| match user::lookup(name) {
+                Ok(user) => Some(user),
+                Err(e) => {
+                    tracing::warn!("{}, unmounting as the current user", e.report());
+                    None
+                }
+            });
 
-    // Opens a new libusb context
-    let context: Context = Context::new()?;
+        let mut busy: Vec<PathBuf> = Vec::new();
+        let mut failed: Vec<String> = Vec::new();
+        for path in device.mountpoints.iter().rev() {
+            if self.config.dry_run {
+                tracing::info!("[dry-run] Would unmount device from {}", path.display());
+                continue;
+            }
 
-    // Build handler and spawn it
-    Handler::new(base_path).spawn(rx);
+            tracing::info!(
+                mountpoint = %path.display(),
+                "Unmounting device from {} (using {})",
+                path.display(),
+                self.config.unmount_backend()
+            );
+            let start: Instant = Instant::now();
+            let result: Result<(), Error> = if lazy {
+                ifuse_unmount(&self.config, path, user.as_ref())
+            } else {
+                try_unmount(&self.config, path, user.as_ref())
+            };
+            match result {
+                Ok(()) => {
+                    tracing::debug!(
+                        mountpoint = %path.display(),
+                        "Unmounted {} in {} ms",
+                        path.display(),
+                        start.elapsed().as_millis()
+                    );
 
-    // The registration is canceled on drop
-    let _guard: Registration<Context> = HotplugBuilder::new()
-        .enumerate(true)
-        .register(&context, Box::new(hotplug_handler))?;
+                    if !self.config.keep_mountpoints {
+                        remove_mountpoint(&self.config.base_path, path);
+                    }
 
-    // Wait for events
-    loop {
-        context.handle_events(None)?;
+                    // Run hook
+                    hooks::run(
+                        "on_unmount",
+                        &self.config.on_unmount,
+                        info,
+                        path,
+                        self.config.hook_timeout,
+                    );
+                }
+                Err(Error::NotMounted(..)) => {
+                    tracing::warn!("{} was already unmounted", path.display());
+                }
+                Err(Error::MountpointBusy(path)) if !lazy => busy.push(path),
+                Err(e) => failed.push(format!("{}: {}", path.display(), e.report())),
+            }
+        }
+
+        // Mounted order
+        busy.reverse();
+
+        if self.config.friendly_links && !self.config.dry_run && busy.is_empty() {
+            links::remove(&self.config.base_path, &device.mountpoints);
+        }
+
+        if !self.config.dry_run && busy.is_empty() && failed.is_empty() {
+            meta::remove(&self.config.base_path, &device.serial_number);
+        }
+
+        if !failed.is_empty() {
+            let e: Error = Error::CantUnmount(failed.join("; "));
+            self.record_history(
+                history::Entry::new(
+                    HistoryEvent::Unmount,
+                    &device.serial_number,
+                    &device.mountpoints,
+                )
+                .mounted_since(device.mounted_at)
+                .failed(&e),
+            );
+            return Err(e);
+        }
+
+        if busy.is_empty() {
+            self.state.count_unmount(&device.serial_number);
+            self.record_history(
+                history::Entry::new(
+                    HistoryEvent::Unmount,
+                    &device.serial_number,
+                    &device.mountpoints,
+                )
+                .mounted_since(device.mounted_at),
+            );
+            self.notify(
+                format!("{} unmounted", display_name(info)),
+                format!("Unmounted from {}", join_paths(&device.mountpoints)),
+                Urgency::Normal,
+            );
+        }
+
+        Ok(busy)
+    }
+
+    /// Drop the pending retries and unmount all the devices before the system suspends, then
+    /// release the inhibitor lock (the mounts wouldn't survive the suspend)
+    fn prepare_for_sleep(&mut self) {
+        self.sleeping = true;
+        self.retries.clear();
+
+        let mut devices: Vec<(DeviceInfo, MountedDevice)> = self
+            .departures
+            .drain()
+            .map(|(_, departure)| (departure.info, departure.device))
+            .collect();
+        for device in self.state.devices() {
+            let addr: DeviceAddr = DeviceAddr {
+                bus: device.bus,
+                addr: device.address,
+            };
+            if let Some(device) = self.state.remove(&addr) {
+                devices.push((device.info(), device));
+            }
+        }
+        self.sync_pending_unmounts();
+
+        for (info, device) in devices.iter() {
+            tracing::info!("Unmounting {} before suspending", info.serial_number);
+            if let Err(e) = self.unmount_device(info, device) {
+                tracing::error!("{}", e.report());
+                self.state.record_error(e.report());
+            }
+        }
+
+        self.sync_inhibitor();
+    }
+
+    /// Unmount the mounts that died during the suspend, then rescan to mount again the devices
+    /// still connected (no hotplug event is fired for them)
+    fn resume(&mut self) {
+        self.sleeping = false;
+
+        for device in self.state.devices() {
+            if is_mount_healthy(&device.mountpoints) {
+                continue;
+            }
+
+            tracing::info!(
+                "The mount of {} died during the suspend: remounting",
+                device.serial_number
+            );
+            self.state.remove(&DeviceAddr {
+                bus: device.bus,
+                addr: device.address,
+            });
+            if let Err(e) = self.unmount_device(&device.info(), &device) {
+                tracing::error!("{}", e.report());
+                self.state.record_error(e.report());
+            }
+        }
+
+        if let Some(rescanner) = &self.rescanner {
+            if let Err(e) = rescanner.rescan() {
+                tracing::error!("Can't rescan the devices: {}", e.report());
+            }
+        }
+    }
+
+    /// Drop the pending retries and unmount all the devices (unless `unmount_on_exit` is disabled)
+    ///
+    /// Failures are logged, they don't prevent the exit.
+    fn shutdown(&mut self) {
+        self.retries.clear();
+
+        let mut devices: Vec<(DeviceInfo, MountedDevice)> = self
+            .departures
+            .drain()
+            .map(|(_, departure)| (departure.info, departure.device))
+            .collect();
+        devices.extend(
+            self.state
+                .drain()
+                .into_iter()
+                .map(|device| (device.info(), device)),
+        );
+
+        if !self.config.unmount_on_exit {
+            if !devices.is_empty() {
+                tracing::info!("Keeping {} device(s) mounted on exit", devices.len());
+            }
+            return;
+        }
+
+        for (info, device) in devices.iter() {
+            tracing::info!("Unmounting {} before exiting", info.serial_number);
+            if let Err(e) = self.unmount_device(info, device) {
+                tracing::error!("{}", e.report());
+            }
+        }
+    }
+
+    /// Best-effort cleanup when the departed device isn't tracked (e.g. the daemon was restarted
+    /// while it was mounted): unmount the dead mounts under the base path
+    fn unmount_untracked(&self) -> Result<(), Error> {
+        if self.config.dry_run {
+            return Err(Error::DeviceNotFound);
+        }
+
+        tracing::debug!("Device not tracked, looking for dead mounts");
+
+        // Still in their grace period
+        let pending: Vec<PathBuf> = self
+            .departures
+            .values()
+            .flat_map(|departure| departure.device.mountpoints.iter().cloned())
+            .collect();
+
+        match cleanup_stale_mounts(&self.config, &pending)? {
+            0 => Err(Error::DeviceNotFound),
+            _ => Ok(()),
+        }
+    }
+
+    /// Unmount the devices that didn't come back in time
+    fn run_due_unmounts(&mut self) {
+        let now: Instant = Instant::now();
+        let due: Vec<DeviceAddr> = self
+            .departures
+            .iter()
+            .filter(|(_, departure)| departure.due <= now)
+            .map(|(addr, _)| addr.clone())
+            .collect();
+
+        for addr in due {
+            if let Some(departure) = self.departures.remove(&addr) {
+                let PendingUnmount {
+                    info,
+                    device,
+                    busy_attempts,
+                    ..
+                } = departure;
+                if let Err(e) = self.unmount_or_defer(addr, info, device, busy_attempts) {
+                    tracing::error!("{}", e.report());
+                    self.state.record_error(e.report());
+                }
+            }
+        }
+
+        self.sync_pending_unmounts();
+    }
+
+    /// Handle the return of a device that just left with another address
+    ///
+    /// Returns `true` if its mounts are still healthy and were kept.
+    fn handle_return(&mut self, addr: &DeviceAddr, serial_number: &str) -> Result<bool, Error> {
+        let old_addr: DeviceAddr = match self
+            .departures
+            .iter()
+            .find(|(_, departure)| departure.device.serial_number == serial_number)
+        {
+            Some((old_addr, ..)) => old_addr.clone(),
+            None => return Ok(false),
+        };
+        let departure: PendingUnmount = match self.departures.remove(&old_addr) {
+            Some(departure) => departure,
+            None => return Ok(false),
+        };
+
+        let is_healthy: bool = is_mount_healthy(&departure.device.mountpoints);
+
+        // Partially unmounted already
+        if departure.busy_attempts > 0 {
+            tracing::info!("{serial_number} came back while being unmounted: remounting");
+            self.unmount_device(&departure.info, &departure.device)?;
+            self.sync_pending_unmounts();
+            return Ok(false);
+        }
+
+        if !is_healthy {
+            tracing::info!("{serial_number} came back, but its mount is dead: remounting");
+            self.unmount_device(&departure.info, &departure.device)?;
+            return Ok(false);
+        }
+
+        tracing::info!("{serial_number} came back, keeping its mount");
+        let mut device: MountedDevice = departure.device;
+        device.bus = addr.bus;
+        device.address = addr.addr;
+        self.state.insert(addr.clone(), device);
+
+        Ok(true)
+    }
+
+    /// Lockdown probe of a device accepted in permissive mode, remembered if failed
+    fn probe(&mut self, serial_number: &str, product_id: u16) -> bool {
+        if self.probe_failures.contains(serial_number) {
+            tracing::debug!("{serial_number} already failed the lockdown probe, skipping.");
+            return false;
+        }
+
+        match lockdown_value(serial_number, "ProductType", self.config.command_timeout) {
+            Some(product_type) => {
+                tracing::warn!(
+                    "Accepted {serial_number} ({product_type}, product ID {product_id:#06x}) via accept_unknown_apple, please report this product ID"
+                );
+                true
+            }
+            None => {
+                tracing::info!(
+                    "{serial_number} (product ID {product_id:#06x}) doesn't answer to lockdown, skipping."
+                );
+                self.probe_failures.insert(serial_number.to_string());
+                false
+            }
+        }
+    }
+
+    /// Move the entry of the device to its new address if it's still mounted from the old one
+    /// (e.g. after a bus reset)
+    ///
+    /// Returns `true` if the mount was kept.
+    fn rebind(&mut self, addr: &DeviceAddr, serial_number: &str) -> Result<bool, Error> {
+        let old: Option<MountedDevice> = self
+            .state
+            .devices()
+            .into_iter()
+            .find(|device| device.serial_number == serial_number);
+        let mut device: MountedDevice = match old {
+            Some(device) => device,
+            None => return Ok(false),
+        };
+
+        let old_addr: DeviceAddr = DeviceAddr {
+            bus: device.bus,
+            addr: device.address,
+        };
+        if old_addr == *addr {
+            return Ok(false);
+        }
+        self.state.remove(&old_addr);
+
+        if !is_mount_healthy(&device.mountpoints) {
+            tracing::info!(
+                "{serial_number} moved to a new address, but its mount is dead: remounting"
+            );
+            self.unmount_device(&device.info(), &device)?;
+            return Ok(false);
+        }
+
+        tracing::info!(
+            "{serial_number} moved from {:03}:{:03} to {:03}:{:03}, keeping its mount",
+            old_addr.bus,
+            old_addr.addr,
+            addr.bus,
+            addr.addr
+        );
+        device.bus = addr.bus;
+        device.address = addr.addr;
+        self.state.insert(addr.clone(), device);
+
+        Ok(true)
+    }
+
+    /// Arrival of an Apple device: read its serial number and mount it
+    fn handle_arrival<T>(
+        &mut self,
+        device: &Device<T>,
+        descriptor: &DeviceDescriptor,
+        addr: &DeviceAddr,
+        permissive: bool,
+    ) -> Result<(), Error>
+    where
+        T: UsbContext,
+    {
+        let vendor_id: u16 = descriptor.vendor_id();
+        let product_id: u16 = descriptor.product_id();
+
+        // Departure immediately followed by an arrival (e.g. after a reset): keep the mount
+        let departed: Option<String> = self
+            .departures
+            .get(addr)
+            .map(|departure| departure.info.serial_number.clone());
+        if let Some(serial_number) = departed {
+            Span::current().record("serial", serial_number.as_str());
+            if self.handle_return(addr, &serial_number)? {
+                self.record_arrival(addr, &serial_number);
+                return Ok(());
+            }
+        }
+
+        // Repeated arrival events for one plug
+        if self.is_duplicate_arrival(addr) {
+            tracing::debug!(
+                "Duplicate arrival of {:03}:{:03}, ignored",
+                addr.bus,
+                addr.addr
+            );
+            return Ok(());
+        }
+
+        // Wait a little before proceeding
+        if !self.config.settle_delay.is_zero() {
+            self.enter("settling");
+            thread::sleep(self.config.settle_delay);
+        }
+        self.check_canceled()?;
+
+        tracing::info!(
+            "Opening {} at {:03}:{:03}",
+            products::name(vendor_id, product_id),
+            addr.bus,
+            addr.addr
+        );
+        self.enter("reading the serial number");
+
+        let mut info: DeviceInfo = {
+            // Open device
+            let handle: DeviceHandle<T> = device.open()?;
+
+            // Reset state
+            if self.config.usb_reset {
+                handle.reset()?;
+            }
+
+            // Read serial number (and name, if needed)
+            read_device_info(
+                device,
+                &handle,
+                descriptor,
+                self.config.usb_timeout,
+                self.config.needs_name(),
+            )?
+        };
+        let serial_number: &str = &info.serial_number;
+        Span::current().record("serial", serial_number);
+
+        tracing::info!("Found an Apple device: serial_number={serial_number}");
+        self.check_canceled()?;
+
+        if self.is_duplicate_serial(serial_number) {
+            tracing::debug!("Duplicate arrival of {serial_number}, ignored");
+            return Ok(());
+        }
+
+        // Check if denied or not allowed
+        if self.config.is_denied(serial_number) {
+            tracing::debug!("{serial_number} is in the deny list, skipping.");
+            return Ok(());
+        }
+
+        if !self.config.is_allowed(serial_number) {
+            tracing::info!("{serial_number} is not in the allow list, skipping.");
+            return Ok(());
+        }
+
+        // Unknown product: check that it talks lockdown before mounting
+        if permissive && !self.probe(serial_number, product_id) {
+            return Ok(());
+        }
+
+        // Get device settings
+        let settings: DeviceSettings = self.config.device(serial_number);
+
+        if !settings.mount {
+            tracing::info!("Mount disabled for {serial_number}, skipping.");
+            return Ok(());
+        }
+
+        // Wait the extra per-device delay, if any
+        let extra_delay: Duration = settings
+            .settle_delay
+            .saturating_sub(self.config.settle_delay);
+        if !extra_delay.is_zero() {
+            thread::sleep(extra_delay);
+            self.check_canceled()?;
+        }
+
+        // Re-enumerated (e.g. cable wiggle), with a healthy mount
+        if self.handle_return(addr, serial_number)? {
+            return Ok(());
+        }
+
+        // Same device, already mounted from its previous address
+        if self.rebind(addr, serial_number)? {
+            return Ok(());
+        }
+
+        // A new arrival supersedes the pending retry, if any
+        self.retries.remove(addr);
+
+        let serial_number: String = serial_number.to_string();
+
+        // Last, since it's an extra lockdown request
+        if self.config.naming == Naming::Model {
+            info.model = self.model_name(&info);
+        }
+
+        let result: Result<(), Error> = self.mount_or_retry(PendingRetry::new(info, settings));
+        // After the mount, so that the events queued meanwhile are coalesced
+        self.record_arrival(addr, &serial_number);
+        result
+    }
+
+    fn handle_device<T>(&mut self, device: Device<T>, action: Action) -> Result<(), Error>
+    where
+        T: UsbContext,
+    {
+        // Check again if ifuse is installed
+        if !self.config.dry_run
+            && !is_ifuse_installed(&self.config.ifuse_path, self.config.command_timeout)
+        {
+            return Err(Error::IfuseNotInstalled(self.config.ifuse_path.clone()));
+        }
+
+        // Get device descriptor
+        let descriptor: DeviceDescriptor = device.device_descriptor()?;
+
+        tracing::trace!("Device descriptor: {descriptor:?}");
+
+        let vendor_id: u16 = descriptor.vendor_id();
+        let product_id: u16 = descriptor.product_id();
+
+        // Check if it's an apple device (or possibly one, in permissive mode)
+        let permissive: bool = match device_kind(&self.config, vendor_id, product_id, || {
+            interface_classes(&device, &descriptor)
+        }) {
+            DeviceKind::Ios => false,
+            DeviceKind::Permissive => true,
+            DeviceKind::Other => return Ok(()),
+        };
+
+        // Get device address
+        let addr: DeviceAddr = DeviceAddr {
+            bus: device.bus_number(),
+            addr: device.address(),
+        };
+
+        match action {
+            Action::Mount => {
+                self.state.count_arrival();
+
+                // Canceled if the device leaves meanwhile
+                self.current = Some(self.in_flight.begin(&addr));
+                let result: Result<(), Error> =
+                    self.handle_arrival(&device, &descriptor, &addr, permissive);
+                self.in_flight.end(&addr);
+                self.current = None;
+                result?;
+            }
+            Action::Unmount => {
+                // The device was never mounted: just cancel the pending retry
+                if let Some(retry) = self.retries.remove(&addr) {
+                    Span::current().record("serial", retry.info.serial_number.as_str());
+                    tracing::info!(
+                        "{} disconnected, mount retry canceled",
+                        retry.info.serial_number
+                    );
+                    return Ok(());
+                }
+
+                tracing::info!(
+                    "Unmounting {} at {:03}:{:03}",
+                    products::name(vendor_id, product_id),
+                    addr.bus,
+                    addr.addr
+                );
+                let device: MountedDevice = match self.state.remove(&addr) {
+                    Some(device) => device,
+                    None => return self.unmount_untracked(),
+                };
+                Span::current().record("serial", device.serial_number.as_str());
+
+                let info: DeviceInfo = DeviceInfo {
+                    serial_number: device.serial_number.clone(),
+                    bus: addr.bus,
+                    address: addr.addr,
+                    vendor_id,
+                    product_id,
+                    name: None,
+                    model: device.model.clone(),
+                };
+
+                // Right after the arrival, it's likely to come back at once (e.g. after a reset)
+                let grace: Duration =
+                    if self.config.reenumerate_grace.is_zero() && self.arrived_recently(&addr) {
+                        self.config.debounce
+                    } else {
+                        self.config.reenumerate_grace
+                    };
+
+                if grace.is_zero() {
+                    return self.unmount_or_defer(addr, info, device, 0);
+                }
+
+                // Wait a little: the device may come back with another address (e.g. cable wiggle)
+                tracing::info!(
+                    "{} disconnected, unmounting in {} ms unless it comes back",
+                    info.serial_number,
+                    grace.as_millis()
+                );
+                self.departures.insert(
+                    addr,
+                    PendingUnmount {
+                        info,
+                        device,
+                        due: Instant::now() + grace,
+                        busy_attempts: 0,
+                    },
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone)]
+struct HotPlugHandler<T>
+where
+    T: UsbContext,
+{
+    tx: mpsc::Sender<Message<T>>,
+    /// Mounts to cancel when their device leaves
+    in_flight: InFlight,
+    /// Depth of the queue, for the state dump
+    state: State,
+}
+
+// Send device and action with the mpsc channel because this method mustn't block.
+// If this method blocks, "resources busy" error will start appearing.
+impl<T> Hotplug<T> for HotPlugHandler<T>
+where
+    T: UsbContext,
+{
+    fn device_arrived(&mut self, device: Device<T>) {
+        match self.tx.send(Message::Device(device, Action::Mount)) {
+            Ok(()) => self.state.event_queued(),
+            Err(e) => tracing::error!("{e}"),
+        }
+    }
+
+    fn device_left(&mut self, device: Device<T>) {
+        // Right away: the handler thread is busy with the mount
+        let addr: DeviceAddr = DeviceAddr {
+            bus: device.bus_number(),
+            addr: device.address(),
+        };
+        if self.in_flight.cancel(&addr) {
+            tracing::info!(
+                "{:03}:{:03} left during its mount, canceling",
+                addr.bus,
+                addr.addr
+            );
+        }
+
+        match self.tx.send(Message::Device(device, Action::Unmount)) {
+            Ok(()) => self.state.event_queued(),
+            Err(e) => tracing::error!("{e}"),
+        }
+    }
+}
+
+/// Open the device and read its info, without resetting it
+fn open_and_read_device_info<T>(
+    device: &Device<T>,
+    descriptor: &DeviceDescriptor,
+    timeout: Duration,
+    with_name: bool,
+) -> Result<DeviceInfo, Error>
+where
+    T: UsbContext,
+{
+    let handle: DeviceHandle<T> = device.open()?;
+    read_device_info(device, &handle, descriptor, timeout, with_name)
+}
+
+/// Read the serial number and, if `with_name` is set, the product string
+fn read_device_info<T>(
+    device: &Device<T>,
+    handle: &DeviceHandle<T>,
+    descriptor: &DeviceDescriptor,
+    timeout: Duration,
+    with_name: bool,
+) -> Result<DeviceInfo, Error>
+where
+    T: UsbContext,
+{
+    let languages: Vec<Language> = handle.read_languages(timeout)?;
+
+    if languages.is_empty() {
+        return Err(Error::CantMount(String::from("Languages empty")));
+    }
+
+    // Read serial number
+    let language: Language = languages[0];
+    let serial_number: String = handle.read_serial_number_string(language, descriptor, timeout)?;
+    check_serial_number(&serial_number)?;
+
+    // Read name (optional)
+    let name: Option<String> = if with_name {
+        match handle.read_product_string(language, descriptor, timeout) {
+            Ok(name) => Some(name),
+            Err(e) => {
+                tracing::debug!("Can't read product string of {serial_number}: {e}");
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    Ok(DeviceInfo {
+        serial_number,
+        bus: device.bus_number(),
+        address: device.address(),
+        vendor_id: descriptor.vendor_id(),
+        product_id: descriptor.product_id(),
+        name,
+        model: None,
+    })
+}
+
+/// Reject the serial numbers that can't be a UDID (e.g. corrupted or hostile descriptors)
+///
+/// The serial number ends up in paths and in the args of external tools.
+fn check_serial_number(serial_number: &str) -> Result<(), Error> {
+    // Not starting with `-`, not to be taken for an option
+    let is_valid: bool = serial_number
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_ascii_alphanumeric())
+        && serial_number.len() <= MAX_SERIAL_NUMBER_LEN
+        && serial_number
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-');
+
+    if !is_valid {
+        return Err(Error::InvalidSerialNumber(serial_number.to_string()));
+    }
+
+    Ok(())
+}
+
+/// Check that the mountpoint is strictly under the base path, without `..`
+fn is_under_base_path(base_path: &Path, path: &Path) -> bool {
+    match path.strip_prefix(base_path) {
+        Ok(relative) => {
+            relative.components().next().is_some()
+                && relative
+                    .components()
+                    .all(|component| matches!(component, path::Component::Normal(..)))
+        }
+        Err(..) => false,
+    }
+}
+
+/// Convert the USB serial number to the UDID expected by libimobiledevice
+///
+/// Newer devices (iPhone XS onward) report 24 hex chars, while the UDID has a dash after the 8th.
+fn udid(serial_number: &str) -> String {
+    if serial_number.len() == 24 && serial_number.chars().all(|c| c.is_ascii_hexdigit()) {
+        let (chip_id, ecid) = serial_number.split_at(8);
+        return format!("{chip_id}-{ecid}");
+    }
+
+    serial_number.to_string()
+}
+
+/// Convert a UDID back to the USB serial number (the inverse of [`udid`])
+///
+/// The serial number stays the key for the directory names and the config.
+fn serial_number_from_udid(udid: &str) -> String {
+    match udid.split_once('-') {
+        Some((chip_id, ecid))
+            if chip_id.len() == 8
+                && ecid.len() == 16
+                && udid
+                    .chars()
+                    .filter(|c| *c != '-')
+                    .all(|c| c.is_ascii_hexdigit()) =>
+        {
+            format!("{chip_id}{ecid}")
+        }
+        _ => udid.to_string(),
+    }
+}
+
+/// Name of the device for the user: its product string (e.g. `iPhone`), or its serial number
+#[inline]
+fn display_name(info: &DeviceInfo) -> &str {
+    info.name.as_deref().unwrap_or(&info.serial_number)
+}
+
+/// Message of a panic payload (`panic!` with a literal or a format string)
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    match payload.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.as_str(),
+            None => "unknown payload",
+        },
+    }
+}
+
+#[inline]
+fn join_paths(paths: &[PathBuf]) -> String {
+    paths
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+/// How a device is handled, from its descriptors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DeviceKind {
+    /// iOS device
+    Ios,
+    /// Apple device that may be an iOS device (`accept_unknown_apple`)
+    Permissive,
+    Other,
+}
+
+/// Check if it's an iOS device: known product ID, or any device exposing the usbmux interface
+/// (e.g. newer than the product ID list)
+fn is_apple_device<T>(config: &Config, device: &Device<T>, descriptor: &DeviceDescriptor) -> bool
+where
+    T: UsbContext,
+{
+    let kind: DeviceKind = device_kind(
+        config,
+        descriptor.vendor_id(),
+        descriptor.product_id(),
+        || interface_classes(device, descriptor),
+    );
+    kind == DeviceKind::Ios
+}
+
+/// Kind of the device, from the IDs of its device descriptor and the (class, subclass, protocol) of
+/// its interfaces (read only if the product ID is unknown)
+fn device_kind<F>(config: &Config, vendor_id: u16, product_id: u16, interfaces: F) -> DeviceKind
+where
+    F: FnOnce() -> Vec<(u8, u8, u8)>,
+{
+    if !is_handled_vendor(config, vendor_id) {
+        return DeviceKind::Other;
+    }
+
+    if is_known_product(config, product_id) {
+        return DeviceKind::Ios;
+    }
+
+    if interfaces().contains(&USBMUX_INTERFACE) {
+        tracing::debug!("Unknown product ID {product_id:#06x}, but it has a usbmux interface");
+        return DeviceKind::Ios;
+    }
+
+    if config.accept_unknown_apple && is_permissive_candidate(vendor_id, product_id) {
+        return DeviceKind::Permissive;
+    }
+
+    DeviceKind::Other
+}
+
+/// Apple, or one of the `extra_vendor_ids`
+#[inline]
+fn is_handled_vendor(config: &Config, vendor_id: u16) -> bool {
+    vendor_id == APPLE_VENDOR_ID || config.extra_vendor_ids.contains(&vendor_id)
+}
+
+/// Known iOS product ID, or one of the `extra_product_ids`
+#[inline]
+fn is_known_product(config: &Config, product_id: u16) -> bool {
+    APPLE_PRODUCT_IDS.contains(&product_id) || config.extra_product_ids.contains(&product_id)
+}
+
+/// Apple device that may be an iOS device, in permissive mode
+#[inline]
+fn is_permissive_candidate(vendor_id: u16, product_id: u16) -> bool {
+    vendor_id == APPLE_VENDOR_ID
+        && !PERMISSIVE_DENIED_PRODUCT_IDS
+            .iter()
+            .any(|(first, last)| (*first..=*last).contains(&product_id))
+}
+
+/// (class, subclass, protocol) of the interfaces, in all the configurations (the usbmux one is not
+/// always in the active one)
+fn interface_classes<T>(device: &Device<T>, descriptor: &DeviceDescriptor) -> Vec<(u8, u8, u8)>
+where
+    T: UsbContext,
+{
+    (0..descriptor.num_configurations())
+        .filter_map(|index| device.config_descriptor(index).ok())
+        .flat_map(|config| {
+            config
+                .interfaces()
+                .flat_map(|interface| {
+                    interface
+                        .descriptors()
+                        .map(|alt| (alt.class_code(), alt.sub_class_code(), alt.protocol_code()))
+                        .collect::<Vec<(u8, u8, u8)>>()
+                })
+                .collect::<Vec<(u8, u8, u8)>>()
+        })
+        .collect()
+}
+
+fn is_ifuse_installed(ifuse: &Path, timeout: Duration) -> bool {
+    let mut cmd: Command = Command::new(ifuse);
+    cmd.arg("--version").stdout(Stdio::null());
+    matches!(child::output(&mut cmd, timeout), Ok(output) if output.status.success())
+}
+
+/// Check if FUSE lets the user (the current one if `None`) mount with `allow_other`
+fn is_allow_other_permitted(user: Option<&User>) -> bool {
+    // Root is always allowed
+    if user.is_none() && unsafe { libc::geteuid() } == 0 {
+        return true;
+    }
+
+    match fs::read_to_string(FUSE_CONF) {
+        Ok(content) => content
+            .lines()
+            .any(|line| line.split('#').next().unwrap_or_default().trim() == "user_allow_other"),
+        Err(..) => false,
+    }
+}
+
+/// Check if the program exists (in `PATH`, if it's not a path)
+fn is_program_installed(program: &str) -> bool {
+    let is_executable = |path: &Path| -> bool {
+        fs::metadata(path)
+            .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    };
+
+    if program.contains('/') {
+        return is_executable(Path::new(program));
+    }
+
+    match env::var_os("PATH") {
+        Some(paths) => env::split_paths(&paths).any(|dir| is_executable(&dir.join(program))),
+        None => false,
+    }
+}
+
+/// Check that the program of the unmount command exists
+fn check_unmount_command(command: &[String]) -> Result<(), Error> {
+    match command.first() {
+        Some(program) if is_program_installed(program) => Ok(()),
+        Some(..) if is_fusermount(command) => Err(Error::FusermountNotInstalled),
+        Some(program) => Err(Error::UnmountCommandNotInstalled(program.clone())),
+        None => Err(Error::UnmountCommandNotInstalled(String::new())),
+    }
+}
+
+/// Pair the device with `idevicepair`, if it's not already
+///
+/// Skipped if `idevicepair` is not installed.
+fn pair_device(serial_number: &str, timeout: Duration) -> Result<(), Error> {
+    // `idevicepair -u <udid> validate`
+    let output: Output = match idevicepair(serial_number, "validate", timeout) {
+        Ok(output) => output,
+        Err(e) if e.is_not_found() => {
+            tracing::debug!("idevicepair not installed, not pairing");
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+
+    if output.status.success() {
+        tracing::debug!("{serial_number} already paired");
+        return Ok(());
+    }
+
+    let err: String = pairing_message(&output);
+    if !err.to_lowercase().contains("not paired") {
+        return Err(pairing_error(serial_number, err));
+    }
+
+    // `idevicepair -u <udid> pair`
+    tracing::info!("Pairing {serial_number}");
+    let output: Output = idevicepair(serial_number, "pair", timeout)?;
+
+    if !output.status.success() {
+        return Err(pairing_error(serial_number, pairing_message(&output)));
+    }
+
+    tracing::info!("Paired with {serial_number}");
+    Ok(())
+}
+
+/// Drop the pairing record and pair again (`idevicepair unpair`, then `pair`)
+///
+/// A pending trust dialog is not an error: the mount waits for it.
+fn repair_device(serial_number: &str, timeout: Duration) -> Result<(), Error> {
+    // `idevicepair -u <udid> unpair`
+    let output: Output = idevicepair(serial_number, "unpair", timeout)?;
+    if !output.status.success() {
+        // Also removes the local record, even if the device rejects the request
+        tracing::debug!("Can't unpair {serial_number}: {}", pairing_message(&output));
+    }
+
+    // `idevicepair -u <udid> pair`
+    let output: Output = idevicepair(serial_number, "pair", timeout)?;
+    if output.status.success() {
+        tracing::info!("Paired with {serial_number}");
+        return Ok(());
+    }
+
+    match pairing_error(serial_number, pairing_message(&output)) {
+        Error::PairingPending(..) => Ok(()),
+        e => Err(e),
+    }
+}
+
+#[inline]
+fn idevicepair(serial_number: &str, subcommand: &str, timeout: Duration) -> Result<Output, Error> {
+    let mut cmd: Command = Command::new("idevicepair");
+    cmd.arg("-u")
+        .arg(udid(serial_number))
+        .arg(subcommand)
+        .stdout(Stdio::piped());
+    child::output(&mut cmd, timeout)
+}
+
+/// idevicepair reports the errors on stdout
+fn pairing_message(output: &Output) -> String {
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    format!("{} {}", stdout.trim(), stderr.trim())
+        .trim()
+        .to_string()
+}
+
+/// Classify the idevicepair error
+fn pairing_error(serial_number: &str, message: String) -> Error {
+    let lowercase: String = message.to_lowercase();
+    let serial_number: String = serial_number.to_string();
+
+    if failure::is_invalid_pairing(&lowercase) {
+        Error::InvalidPairing(serial_number)
+    } else if lowercase.contains("denied") {
+        Error::PairingRefused(serial_number)
+    } else if lowercase.contains("trust dialog") || lowercase.contains("pending") {
+        Error::PairingPending(serial_number)
+    } else if lowercase.contains("passcode") || lowercase.contains("locked") {
+        Error::DeviceLocked(serial_number)
+    } else {
+        Error::CantPair(serial_number, message)
+    }
+}
+
+/// Get the name given to the device by its owner (e.g. "Yuki's iPhone"), with `ideviceinfo`
+#[inline]
+fn device_name(serial_number: &str, timeout: Duration) -> Option<String> {
+    lockdown_value(serial_number, "DeviceName", timeout)
+}
+
+/// Marketing name of the device (e.g. `iPhone 13`), from its `ProductType` or its USB product string
+///
+/// If another device of the same model is in `others`, the last 4 characters of the serial number are appended,
+/// so the directories don't collide.
+fn model_name(info: &DeviceInfo, others: &[MountedDevice], timeout: Duration) -> Option<String> {
+    let serial_number: &str = &info.serial_number;
+    let model: String = match lockdown_value(serial_number, "ProductType", timeout) {
+        Some(product_type) => match models::marketing_name(&product_type) {
+            Some(name) => name.to_string(),
+            None => {
+                tracing::debug!("Unknown product type {product_type} of {serial_number}");
+                info.name.clone()?
+            }
+        },
+        None => info.name.clone()?,
+    };
+
+    let taken: bool = others.iter().any(|device| {
+        device.serial_number != *serial_number && device.model.as_deref() == Some(model.as_str())
+    });
+    if !taken {
+        return Some(model);
+    }
+
+    let start: usize = serial_number.len().saturating_sub(4);
+    Some(format!("{model} ({})", &serial_number[start..]))
+}
+
+/// Read a lockdown value of the device with `ideviceinfo` (e.g. `ProductType`)
+fn lockdown_value(serial_number: &str, key: &str, timeout: Duration) -> Option<String> {
+    // `ideviceinfo -u <udid> -k <key>`
+    let mut cmd: Command = Command::new("ideviceinfo");
+    cmd.arg("-u")
+        .arg(udid(serial_number))
+        .arg("-k")
+        .arg(key)
+        .stdout(Stdio::piped());
+
+    match child::output(&mut cmd, timeout) {
+        Ok(output) if output.status.success() => {
+            let value = String::from_utf8_lossy(&output.stdout);
+            let value: &str = value.trim();
+            (!value.is_empty()).then(|| value.to_string())
+        }
+        Ok(..) => None,
+        Err(e) => {
+            tracing::debug!("Can't get {key} of {serial_number}: {e}");
+            None
+        }
+    }
+}
+
+/// Poll lockdownd with `ideviceinfo` until it answers, or `deadline` elapses
+///
+/// Skipped if `ideviceinfo` is not installed.
+fn wait_for_lockdown(
+    serial_number: &str,
+    deadline: Duration,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let start: Instant = Instant::now();
+
+    loop {
+        // `ideviceinfo -u <udid> -k DeviceName`
+        let mut cmd: Command = Command::new("ideviceinfo");
+        cmd.arg("-u")
+            .arg(udid(serial_number))
+            .arg("-k")
+            .arg("DeviceName")
+            .stdout(Stdio::null());
+
+        match child::output(&mut cmd, timeout.min(deadline)) {
+            Ok(output) if output.status.success() => {
+                tracing::debug!(
+                    "lockdownd ready on {serial_number} after {} ms",
+                    start.elapsed().as_millis()
+                );
+                return Ok(());
+            }
+            Ok(..) | Err(Error::CommandTimeout(..)) => {}
+            Err(e) if e.is_not_found() => {
+                tracing::debug!("ideviceinfo not installed, not waiting for lockdownd");
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        }
+
+        if start.elapsed() >= deadline {
+            return Err(Error::LockdownNotReady(serial_number.to_string()));
+        }
+
+        thread::sleep(LOCKDOWN_POLL_INTERVAL);
+    }
+}
+
+fn ifuse_mount<P>(
+    ifuse: &Path,
+    path: P,
+    serial_number: &str,
+    mode: &MountMode,
+    options: &[String],
+    user: Option<&User>,
+    timeout: Duration,
+) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    // Build command
+    let mut cmd: Command = Command::new(ifuse);
+    cmd.args(ifuse_args(path.as_ref(), serial_number, mode, options));
+
+    if let Some(user) = user {
+        user.apply(&mut cmd);
+    }
+
+    // Run command
+    tracing::debug!("Running {cmd:?}");
+    let output: Output = match child::output(cmd.stdout(Stdio::null()), timeout) {
+        Ok(output) => output,
+        Err(e) if e.is_not_found() => return Err(Error::IfuseNotInstalled(ifuse.to_path_buf())),
+        Err(e) => return Err(e),
+    };
+
+    // Check status
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr);
+        return Err(failure::classify(serial_number, mode, path.as_ref(), &err));
+    }
+
+    Ok(())
+}
+
+/// Args of ifuse:
+/// `/path/where/to/mount -u <udid> [--documents <bundle-id> | --root] [-o opt1,opt2]`
+fn ifuse_args(
+    path: &Path,
+    serial_number: &str,
+    mode: &MountMode,
+    options: &[String],
+) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec![
+        path.as_os_str().to_os_string(),
+        OsString::from("-u"),
+        OsString::from(udid(serial_number)),
+    ];
+
+    match mode {
+        MountMode::Media => {}
+        MountMode::Documents(bundle_id) => {
+            args.push(OsString::from("--documents"));
+            args.push(OsString::from(bundle_id));
+        }
+        MountMode::Root => args.push(OsString::from("--root")),
+    }
+
+    if !options.is_empty() {
+        args.push(OsString::from("-o"));
+        args.push(OsString::from(options.join(",")));
+    }
+
+    args
+}
+
+/// Check that the mountpoints are still alive
+///
+/// A dead FUSE mount fails with `ENOTCONN`, and a hung one may block forever: the check runs in
+/// its own thread, so that a hung one is reported as dead after [`MOUNT_HEALTH_TIMEOUT`].
+fn is_mount_healthy(mountpoints: &[PathBuf]) -> bool {
+    let mountpoints: Vec<PathBuf> = mountpoints.to_vec();
+    let (tx, rx) = mpsc::sync_channel(1);
+    thread::spawn(move || {
+        let is_healthy: bool = mountpoints.iter().all(|path| fs::metadata(path).is_ok());
+        let _ = tx.send(is_healthy);
+    });
+
+    rx.recv_timeout(MOUNT_HEALTH_TIMEOUT).unwrap_or(false)
+}
+
+/// Check that the path is an ifuse mount, in the mount table (or by its device ID, if unreadable)
+///
+/// Returns how it was verified.
+fn verify_mount(path: &Path) -> Result<&'static str, Error> {
+    let mount_table: Vec<MountEntry> = match mounts::read() {
+        Ok(mount_table) => mount_table,
+        Err(e) => {
+            tracing::debug!("Can't read the mount table ({e}), comparing device IDs");
+            let parent: &Path = path.parent().unwrap_or(path);
+            let dev: u64 = fs::metadata(path).map_err(Error::fs("stat", path))?.dev();
+            if dev
+                != fs::metadata(parent)
+                    .map_err(Error::fs("stat", parent))?
+                    .dev()
+            {
+                return Ok("device ID");
+            }
+            return Err(Error::CantMount(format!(
+                "ifuse succeeded, but {} is not a mountpoint",
+                path.display()
+            )));
+        }
+    };
+
+    match mounts::find(&mount_table, path) {
+        Some(entry) if entry.is_ifuse() => Ok("mount table"),
+        Some(entry) => Err(Error::CantMount(format!(
+            "ifuse succeeded, but {} is mounted as {}",
+            path.display(),
+            entry.fstype
+        ))),
+        None => Err(Error::CantMount(format!(
+            "ifuse succeeded, but {} is not in the mount table",
+            path.display()
+        ))),
+    }
+}
+
+/// Unmount the device
+///
+/// If the mountpoint is busy (e.g. still open in a file manager) and the unmount command is fusermount
+/// (or `umount2` is used), retry with a lazy unmount, so the mount doesn't outlive the device.
+///
+/// The unmount command runs as `user`, if set (the user who mounted).
+fn ifuse_unmount<P>(config: &Config, path: P, user: Option<&User>) -> Result<(), Error>
+where
+    P: AsRef<Path>,
+{
+    let path: &Path = path.as_ref();
+
+    let command: &[String] = &config.unmount_command;
+    let timeout: Duration = config.command_timeout;
+    match try_unmount(config, path, user) {
+        Err(Error::MountpointBusy(..)) if config.native_unmount => {
+            tracing::warn!("{} is busy, retrying with a lazy unmount", path.display());
+            thread::sleep(LAZY_UNMOUNT_DELAY);
+            native_unmount(path, libc::MNT_DETACH)
+        }
+        Err(Error::MountpointBusy(..)) if is_fusermount(command) => {
+            tracing::warn!("{} is busy, retrying with a lazy unmount", path.display());
+            thread::sleep(LAZY_UNMOUNT_DELAY);
+            run_unmount_command(&lazy_unmount_command(command), path, user, timeout)
+        }
+        res => res,
+    }
+}
+
+/// Unmount the device, without falling back to a lazy unmount if busy
+fn try_unmount(config: &Config, path: &Path, user: Option<&User>) -> Result<(), Error> {
+    if config.native_unmount {
+        return native_unmount(path, 0);
+    }
+
+    run_unmount_command(&config.unmount_command, path, user, config.command_timeout)
+}
+
+/// Unmount with the `umount2` syscall (requires `CAP_SYS_ADMIN`)
+///
+/// `flags` is `0` for a regular unmount, or `MNT_DETACH` for a lazy one.
+fn native_unmount(path: &Path, flags: libc::c_int) -> Result<(), Error> {
+    let target: CString = CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| Error::CantUnmount(format!("{}: path contains a NUL byte", path.display())))?;
+
+    let flags_name: &str = match flags {
+        libc::MNT_DETACH => "MNT_DETACH",
+        _ => "0",
+    };
+    tracing::debug!("Calling umount2({}, {flags_name})", path.display());
+    if unsafe { libc::umount2(target.as_ptr(), flags) } < 0 {
+        let e: io::Error = io::Error::last_os_error();
+        return Err(match e.raw_os_error() {
+            Some(libc::EBUSY) => Error::MountpointBusy(path.to_path_buf()),
+            // Not a mountpoint
+            Some(libc::EINVAL) => Error::NotMounted(path.to_path_buf()),
+            _ => Error::CantUnmount(format!("{}: {e}", path.display())),
+        });
+    }
+
+    Ok(())
+}
+
+/// Check if the process has `CAP_SYS_ADMIN` in its effective set (needed for `umount2`)
+fn has_cap_sys_admin() -> bool {
+    const CAP_SYS_ADMIN: u32 = 21;
+
+    let status: String = match fs::read_to_string("/proc/self/status") {
+        Ok(status) => status,
+        Err(..) => return unsafe { libc::geteuid() } == 0,
+    };
+
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .is_some_and(|caps| caps & (1 << CAP_SYS_ADMIN) != 0)
+}
+
+/// Check if the unmount program is `fusermount` (or `fusermount3`)
+fn is_fusermount(command: &[String]) -> bool {
+    command
+        .first()
+        .and_then(|program| Path::new(program).file_name())
+        .is_some_and(|name| name.to_string_lossy().starts_with("fusermount"))
+}
+
+/// `fusermount -u -z`
+#[inline]
+fn lazy_unmount_command(command: &[String]) -> Vec<String> {
+    let mut lazy: Vec<String> = command.to_vec();
+    lazy.push(String::from("-z"));
+    lazy
+}
+
+fn run_unmount_command(
+    command: &[String],
+    path: &Path,
+    user: Option<&User>,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let (program, args) = match command.split_first() {
+        Some(split) => split,
+        None => return Err(Error::UnmountCommandNotInstalled(String::new())),
+    };
+
+    // Build command
+    // `<unmount command> /path/to/mounted/device` (default: `fusermount -u`)
+    let mut cmd: Command = Command::new(program);
+    cmd.args(args).arg(path);
+
+    if let Some(user) = user {
+        user.apply(&mut cmd);
+    }
+
+    // Run command
+    tracing::debug!("Running {cmd:?}");
+    let output: Output = match child::output(cmd.stdout(Stdio::null()), timeout) {
+        Ok(output) => output,
+        Err(e) if e.is_not_found() => {
+            return Err(Error::UnmountCommandNotInstalled(program.clone()))
+        }
+        Err(e) => return Err(e),
+    };
+
+    // Check status
+    if !output.status.success() {
+        let err = String::from_utf8_lossy(&output.stderr);
+        let lowercase: String = err.to_lowercase();
+
+        // fusermount: "Device or resource busy", umount: "target is busy"
+        if lowercase.contains("busy") {
+            return Err(Error::MountpointBusy(path.to_path_buf()));
+        }
+
+        // fusermount: "entry for <path> not found in /etc/mtab", umount: "not mounted"
+        if lowercase.contains("not found in") || lowercase.contains("not mounted") {
+            return Err(Error::NotMounted(path.to_path_buf()));
+        }
+
+        return Err(Error::CantUnmount(err.trim().to_string()));
+    }
+
+    Ok(())
+}
+
+/// Create the base path if missing and check that it's writable
+fn prepare_base_path(config: &Config) -> Result<(), Error> {
+    let path: &Path = &config.base_path;
+
+    // Create directory
+    fs::create_dir_all(path).map_err(Error::fs("create", path))?;
+    set_dir_permissions(config, path)?;
+
+    // Try to create (and remove) a file to check if the directory is writable
+    let probe: PathBuf = path.join(".ifuse-automount-probe");
+    match fs::File::create(&probe) {
+        Ok(..) => {
+            fs::remove_file(&probe).map_err(Error::fs("remove", &probe))?;
+            Ok(())
+        }
+        Err(..) => Err(Error::BasePathNotWritable(path.to_path_buf())),
+    }
+}
+
+/// Set the permissions and ownership of a directory created by the daemon
+///
+/// The ownership was checked at startup, so failing to apply it is only logged.
+fn set_dir_permissions(config: &Config, path: &Path) -> Result<(), Error> {
+    if let Some(mode) = config.dir_mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(Error::fs("set the permissions of", path))?;
+    }
+
+    if config.dir_uid.is_some() || config.dir_gid.is_some() {
+        if let Err(e) = std::os::unix::fs::chown(path, config.dir_uid, config.dir_gid) {
+            tracing::warn!("Can't change owner of {}: {e}", path.display());
+        }
+    }
+
+    Ok(())
+}
+
+/// Check if the current user is a member of the group
+fn is_member_of(gid: u32) -> bool {
+    if unsafe { libc::getegid() } == gid {
+        return true;
+    }
+
+    // Supplementary groups
+    let len: libc::c_int = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    let mut groups: Vec<libc::gid_t> = vec![0; usize::try_from(len).unwrap_or_default()];
+    let len: libc::c_int = unsafe { libc::getgroups(len, groups.as_mut_ptr()) };
+    groups.truncate(usize::try_from(len).unwrap_or_default());
+    groups.contains(&gid)
+}
+
+/// First entries of the directory, if it exists and isn't empty
+fn dir_entries(path: &Path) -> Option<Vec<String>> {
+    let mut entries: Vec<String> = fs::read_dir(path)
+        .ok()?
+        .flatten()
+        .take(MAX_LISTED_ENTRIES + 1)
+        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+        .collect();
+
+    if entries.is_empty() {
+        return None;
+    }
+
+    if entries.len() > MAX_LISTED_ENTRIES {
+        entries.truncate(MAX_LISTED_ENTRIES);
+        entries.push(String::from("..."));
+    }
+
+    Some(entries)
+}
+
+/// `<path>.bak-<timestamp>`
+fn backup_path(path: &Path) -> PathBuf {
+    let mut name: std::ffi::OsString = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".bak-{}", state::now()));
+    path.with_file_name(name)
+}
+
+/// Remove the mountpoint directory, and its parents up to the base path if they are empty
+///
+/// Not recursive: never removes data if something went wrong.
+fn remove_mountpoint(base_path: &Path, path: &Path) {
+    if let Err(e) = fs::remove_dir(path) {
+        tracing::warn!("Can't remove {}: {e}", path.display());
+        return;
+    }
+
+    // Parents of nested mounts (e.g. `<serial>/apps/<bundle-id>`)
+    for parent in path.ancestors().skip(1) {
+        if parent == base_path || !parent.starts_with(base_path) || fs::remove_dir(parent).is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Unmount the dead ifuse mounts under the base path (e.g. left by an unclean shutdown)
+///
+/// A mount is dead if accessing it fails with `ENOTCONN` ("Transport endpoint is not connected"),
+/// or with `EIO` (ifuse still running, but the device is gone). The mounts in `exclude` are skipped.
+///
+/// Returns the number of unmounted mounts.
+fn cleanup_stale_mounts(config: &Config, exclude: &[PathBuf]) -> Result<usize, Error> {
+    let mount_table: Vec<MountEntry> = mounts::read()?;
+    let mut cleaned: usize = 0;
+    let mut failed: usize = 0;
+
+    // Reverse order: nested mounts first
+    for entry in mount_table
+        .iter()
+        .rev()
+        .filter(|entry| entry.is_ifuse() && entry.target.starts_with(&config.base_path))
+        .filter(|entry| !exclude.contains(&entry.target))
+    {
+        let is_stale: bool = matches!(
+            fs::metadata(&entry.target),
+            Err(e) if matches!(e.raw_os_error(), Some(libc::ENOTCONN | libc::EIO))
+        );
+        if !is_stale {
+            continue;
+        }
+
+        tracing::info!("Cleaning up stale mount at {}", entry.target.display());
+
+        match unmount_stale(config, &entry.target) {
+            Ok(()) => cleaned += 1,
+            Err(e) => {
+                tracing::warn!("Can't unmount {}: {}", entry.target.display(), e.report());
+                failed += 1;
+            }
+        }
+    }
+
+    if cleaned > 0 || failed > 0 {
+        tracing::info!("Cleaned up {cleaned} stale mounts ({failed} failed)");
+    }
+
+    Ok(cleaned)
+}
+
+/// Unmount a dead mount, lazily if the regular unmount fails (fusermount only)
+fn unmount_stale(config: &Config, path: &Path) -> Result<(), Error> {
+    let command: &[String] = &config.unmount_command;
+
+    match ifuse_unmount(config, path, None) {
+        Ok(()) => {}
+        // Already retried lazily
+        Err(e @ Error::MountpointBusy(..)) => return Err(e),
+        Err(e) if !config.native_unmount && is_fusermount(command) => {
+            tracing::debug!("Regular unmount failed ({}), retrying lazily", e.report());
+            run_unmount_command(
+                &lazy_unmount_command(command),
+                path,
+                None,
+                config.command_timeout,
+            )?;
+        }
+        Err(e) => return Err(e),
+    }
+
+    if config.friendly_links {
+        links::remove(&config.base_path, &[path.to_path_buf()]);
+    }
+
+    if !config.keep_mountpoints {
+        remove_mountpoint(&config.base_path, path);
+    }
+
+    Ok(())
+}
+
+/// Re-resolve the config on SIGHUP and send it to the handler thread
+fn spawn_reload_listener(args: Cli, tx: mpsc::Sender<Message<Context>>) -> Result<(), Error> {
+    let mut signals: Signals = Signals::new([SIGHUP])?;
+
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            tracing::info!("Received SIGHUP, reloading config");
+
+            match Config::resolve(&args) {
+                Ok(config) => {
+                    if let Err(e) = tx.send(Message::Reload(Box::new(config))) {
+                        tracing::error!("{e}");
+                    }
+                }
+                Err(e) => tracing::error!("Can't reload config: {}", e.report()),
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Register the hotplug callbacks, one per vendor (libusb filters on a single one)
+///
+/// The registrations are canceled on drop.
+fn register_hotplug(
+    context: &Context,
+    vendor_ids: &[u16],
+    enumerate: bool,
+    handler: &HotPlugHandler<Context>,
+) -> Result<Vec<Registration<Context>>, rusb::Error> {
+    vendor_ids
+        .iter()
+        .map(|vendor_id| {
+            HotplugBuilder::new()
+                .vendor_id(*vendor_id)
+                .enumerate(enumerate)
+                .register(context, Box::new(handler.clone()))
+        })
+        .collect()
+}
+
+/// Set the returned flag on SIGTERM/SIGINT
+///
+/// A second signal exits at once (e.g. a hung unmount command).
+fn register_shutdown_signals() -> Result<Arc<AtomicBool>, Error> {
+    let shutdown: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+
+    for signal in [SIGTERM, SIGINT] {
+        // Checked before the flag is set
+        signal_hook::flag::register_conditional_shutdown(signal, 1, shutdown.clone())?;
+        signal_hook::flag::register(signal, shutdown.clone())?;
+    }
+
+    Ok(shutdown)
+}
+
+/// Let the handler thread process its queue and unmount the devices, then join it
+///
+/// The exit isn't blocked for more than [`SHUTDOWN_TIMEOUT`] (e.g. a hung unmount command).
+fn stop_handler(tx: &mpsc::Sender<Message<Context>>, handler: JoinHandle<()>) {
+    let (done_tx, done_rx) = mpsc::channel();
+    if let Err(e) = tx.send(Message::Shutdown(done_tx)) {
+        tracing::error!("{e}");
+        return;
+    }
+
+    match done_rx.recv_timeout(SHUTDOWN_TIMEOUT) {
+        Err(RecvTimeoutError::Timeout) => tracing::warn!(
+            "Devices not unmounted within {} s, exiting anyway",
+            SHUTDOWN_TIMEOUT.as_secs()
+        ),
+        // Done, or the thread is gone
+        Ok(()) | Err(RecvTimeoutError::Disconnected) => {
+            if handler.join().is_err() {
+                tracing::error!("The handler thread panicked");
+            }
+        }
+    }
+}
+
+/// Mount the currently connected devices and print their mountpoints
+///
+/// Return `false` if any mount failed.
+fn oneshot_mount(config: Config) -> Result<bool, Error> {
+    let context: Context = Context::new()?;
+    let mut handler: Handler = Handler::new(config, State::new());
+    let mut success: bool = true;
+
+    for device in context.devices()?.iter() {
+        if let Err(e) = handler.handle_device(device, Action::Mount) {
+            tracing::error!("{}", e.report());
+            success = false;
+        }
+    }
+
+    // Wait for the pending retries
+    while let Some(due) = handler.next_retry() {
+        thread::sleep(due.saturating_duration_since(Instant::now()));
+        handler.run_due_retries();
+    }
+
+    if handler.state.status().mount_failures > 0 {
+        success = false;
+    }
+
+    for device in handler.state.devices() {
+        for path in device.mountpoints.iter() {
+            println!("{}", path.display());
+        }
+    }
+
+    Ok(success)
+}
+
+/// Validate the configuration and the environment, then print the effective configuration
+fn check_config(args: &Cli) -> Result<(), Error> {
+    let config: Config = Config::resolve(args)?;
+
+    prepare_base_path(&config)?;
+
+    if !is_ifuse_installed(&config.ifuse_path, config.command_timeout) {
+        return Err(Error::IfuseNotInstalled(config.ifuse_path));
+    }
+
+    if !config.native_unmount {
+        check_unmount_command(&config.unmount_command)?;
+    }
+
+    let allow_other: bool = is_allow_other_permitted(None);
+    tracing::info!(
+        "allow_other: {}",
+        if allow_other {
+            "permitted"
+        } else {
+            "not permitted"
+        }
+    );
+    if config.allow_other && !allow_other {
+        return Err(Error::AllowOtherNotEnabled);
+    }
+
+    print!("{config}");
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    // Parse CLI args (`--help` and `--version` exit successfully)
+    let args: Cli = match Cli::try_parse() {
+        Ok(args) => args,
+        Err(e) => {
+            let _ = e.print();
+            return match e.use_stderr() {
+                true => ExitCode::from(EXIT_USAGE),
+                false => ExitCode::SUCCESS,
+            };
+        }
+    };
+
+    // Init logger
+    logger::init(&args);
+
+    let code: ExitCode = match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            tracing::error!("{}", e.report());
+            ExitCode::from(e.exit_code())
+        }
+    };
+
+    logger::flush();
+    code
+}
+
+fn run(args: Cli) -> Result<(), Error> {
+    // Run subcommand, if any
+    if let Some(command) = &args.command {
+        let result: Result<(), Error> = match command {
+            CliCommand::CheckConfig => check_config(&args),
+            CliCommand::List { json } => {
+                Config::resolve(&args).and_then(|config| list::list(&config, *json))
+            }
+            CliCommand::Mount {
+                serial_number,
+                mode,
+            } => Config::resolve(&args).and_then(|config| {
+                manual::mount(
+                    config,
+                    &serial_number_from_udid(serial_number),
+                    mode.clone(),
+                )
+            }),
+            CliCommand::Status { json } => {
+                Config::resolve(&args).and_then(|config| status::status(&config, *json))
+            }
+            CliCommand::History { count, serial } => Config::resolve(&args).and_then(|config| {
+                let serial: Option<String> = serial.as_deref().map(serial_number_from_udid);
+                history::print(&config, *count, serial.as_deref())
+            }),
+            CliCommand::Rescan => {
+                Config::resolve(&args).and_then(|config| rescan::request(&config))
+            }
+            CliCommand::Generate { target } => match target {
+                GenerateTarget::Systemd { user_unit, install } => {
+                    generate::systemd(&args, *user_unit, *install)
+                }
+                GenerateTarget::Udev {
+                    group,
+                    any_product,
+                    install,
+                } => generate::udev(&args, group.as_deref(), *any_product, *install),
+            },
+            CliCommand::Completions { shell } => {
+                cli::print_completions(*shell);
+                Ok(())
+            }
+            CliCommand::CompleteSerials => Config::resolve(&args).and_then(|config| {
+                for serial_number in meta::mounted_serials(&config.base_path)? {
+                    println!("{serial_number}");
+                }
+                Ok(())
+            }),
+            CliCommand::Unmount {
+                serial_number,
+                mode,
+                all,
+            } => Config::resolve(&args).and_then(|config| match serial_number {
+                Some(serial_number) if !all => manual::unmount(
+                    &config,
+                    &serial_number_from_udid(serial_number),
+                    mode.clone(),
+                ),
+                _ => match manual::unmount_all(&config)? {
+                    true => Ok(()),
+                    false => Err(Error::CantUnmount(String::from(
+                        "some devices couldn't be unmounted",
+                    ))),
+                },
+            }),
+        };
+
+        return result;
+    }
+
+    // Resolve configuration
+    let mut config: Config = Config::resolve(&args)?;
+
+    // One instance per base path, before touching its mounts
+    let mut lock: Option<InstanceLock> = match config.dry_run {
+        true => None,
+        false => Some(InstanceLock::acquire(&config.base_path)?),
+    };
+
+    if config.dry_run {
+        tracing::warn!("Dry-run mode: nothing will be mounted");
+    } else {
+        // Check if ifuse is installed
+        if !is_ifuse_installed(&config.ifuse_path, config.command_timeout) {
+            return Err(Error::IfuseNotInstalled(config.ifuse_path));
+        }
+
+        // Check if the unmount command is installed
+        if !config.native_unmount {
+            check_unmount_command(&config.unmount_command)?;
+        }
+
+        // Make sure the base path is usable
+        prepare_base_path(&config)?;
+
+        // Remove the leftovers of an unclean shutdown
+        if let Err(e) = cleanup_stale_mounts(&config, &[]) {
+            tracing::warn!("Can't clean up stale mounts: {}", e.report());
+        }
+        if let Err(e) = meta::cleanup(&config.base_path) {
+            tracing::warn!("Can't clean up stale metadata: {}", e.report());
+        }
+    }
+
+    // One-shot modes
+    if args.oneshot || args.oneshot_unmount {
+        let success: bool = if args.oneshot {
+            oneshot_mount(config)?
+        } else {
+            manual::unmount_all(&config)?
+        };
+
+        if !success {
+            logger::flush();
+            process::exit(i32::from(EXIT_FAILURE));
+        }
+
+        return Ok(());
+    }
+
+    // Detach from the terminal, before spawning threads and creating the libusb context
+    let pid_file: Option<PidFile> = if args.daemon {
+        // The working directory changes to `/`
+        config.base_path = path::absolute(&config.base_path)?;
+        daemon::daemonize()?;
+
+        // New PID
+        if let Some(lock) = lock.as_mut() {
+            lock.write_pid()?;
+        }
+
+        Some(PidFile::create(&config.base_path)?)
+    } else {
+        None
+    };
+
+    // Opens a new libusb context, before `rusb::has_hotplug` which panics if libusb is unusable
+    let mut context: Context = Context::new()?;
+
+    // Otherwise, poll the device list
+    let hotplug: bool = rusb::has_hotplug();
+    if !hotplug {
+        tracing::warn!(
+            "libusb hotplug unsupported, polling the USB devices every {} ms",
+            config.poll_interval.as_millis()
+        );
+    }
+
+    let (tx, rx) = mpsc::channel();
+
+    tracing::info!(
+        "Watching for Apple devices, mounting under {} (from {}, enumerate on start: {})",
+        config.base_path.display(),
+        config.base_path_source,
+        config.enumerate_on_start
+    );
+    let enumerate: bool = config.enumerate_on_start;
+    let poll_interval: Duration = config.poll_interval;
+    let usb_error_limit: u32 = config.usb_error_limit;
+
+    // Non-Apple devices never wake the handler thread
+    let mut vendor_ids: Vec<u16> = vec![APPLE_VENDOR_ID];
+    for vendor_id in config.extra_vendor_ids.iter() {
+        if !vendor_ids.contains(vendor_id) {
+            vendor_ids.push(*vendor_id);
+        }
+    }
+
+    // Build handler and spawn it, with the devices mounted before a restart
+    let state: State = match config.dry_run {
+        true => State::new(),
+        false => State::persistent(persist::path(&config.base_path)?),
+    };
+    if !config.dry_run {
+        if let Err(e) = adopt::adopt(&config, &context, &state) {
+            tracing::warn!("Can't adopt the mounted devices: {}", e.report());
+        }
+    }
+    let base_path: PathBuf = config.base_path.clone();
+    let health_file: Option<PathBuf> = config.health_file.clone();
+    let command_timeout: Duration = config.command_timeout;
+    let inhibit_suspend: bool = config.inhibit_suspend && !config.dry_run;
+    let mut handler: Handler = Handler::new(config, state.clone());
+    handler.enumerating = enumerate;
+    let hotplug_handler = HotPlugHandler {
+        tx: tx.clone(),
+        in_flight: handler.in_flight.clone(),
+        state: state.clone(),
+    };
+    let in_flight: InFlight = handler.in_flight.clone();
+    let mut watchdog: Option<Watchdog> = Watchdog::from_env(handler.heartbeat.clone());
+    let mut health: Option<HealthFile> = health_file.map(|path| {
+        HealthFile::new(
+            path,
+            handler.heartbeat.clone(),
+            state.clone(),
+            command_timeout,
+        )
+    });
+
+    // Rescan on SIGUSR2 (or with the control socket, or after a resume)
+    let rescanner: Rescanner = Rescanner::new(
+        context.clone(),
+        vendor_ids.clone(),
+        tx.clone(),
+        state.clone(),
+    );
+    handler.rescanner = Some(rescanner.clone());
+
+    // Suspend and resume, from logind's `PrepareForSleep` (or from the clock, without logind)
+    let mut resume_detector: Option<ResumeDetector> = match inhibit::spawn_listener(tx.clone()) {
+        Ok(()) => {
+            // Unmount before suspending
+            if inhibit_suspend {
+                handler.inhibitor = Some(Inhibitor::default());
+            }
+            None
+        }
+        Err(e) => {
+            tracing::warn!(
+                "Can't watch the suspend with logind ({}), detecting the resume from the clock",
+                e.report()
+            );
+            if inhibit_suspend {
+                tracing::warn!("Not inhibiting the suspend");
+            }
+            Some(ResumeDetector::new())
+        }
+    };
+    let handler: JoinHandle<()> = handler.spawn(rx);
+
+    // Dump the state on SIGUSR1
+    dump::spawn_listener(state.clone(), in_flight)?;
+
+    // Rescan on SIGUSR2
+    rescan::spawn_listener(rescanner.clone())?;
+
+    // Serve the metrics, if enabled
+    if let Some(addr) = args.metrics_listen {
+        metrics::spawn(addr, state.clone(), args.metrics_hash_serials)?;
+    }
+
+    // Serve the control socket
+    control::spawn(&base_path, state, rescanner.clone())?;
+
+    // Unmount and exit on SIGTERM/SIGINT
+    let shutdown: Arc<AtomicBool> = register_shutdown_signals()?;
+
+    // Reload config on SIGHUP
+    spawn_reload_listener(args, tx.clone())?;
+
+    // Hotplug callbacks, or polling
+    let mut poller: Option<Poller<Context, HotPlugHandler<Context>>> =
+        (!hotplug).then(|| Poller::new(poll_interval, vendor_ids.clone(), hotplug_handler.clone()));
+    let mut registrations: Vec<Registration<Context>> = if hotplug {
+        register_hotplug(&context, &vendor_ids, enumerate, &hotplug_handler)?
+    } else {
+        Vec::new()
+    };
+    if let Some(poller) = poller.as_mut() {
+        poller.start(&context, enumerate);
+    }
+
+    // The connected devices are delivered during the registration (or the first scan)
+    if let Err(e) = tx.send(Message::Enumerated) {
+        tracing::error!("{e}");
+    }
+
+    sd_notify::ready();
+
+    // Consecutive libusb errors, and whether the context was already rebuilt since the last success
+    let mut usb_errors: u32 = 0;
+    let mut rebuilt: bool = false;
+
+    // Wait for events, until SIGTERM/SIGINT
+    while !shutdown.load(Ordering::SeqCst) {
+        if resume_detector
+            .as_mut()
+            .is_some_and(ResumeDetector::resumed)
+        {
+            tracing::info!("The system resumed");
+            if let Err(e) = tx.send(Message::Resume) {
+                tracing::error!("{e}");
+            }
+        }
+
+        match poller.as_mut() {
+            Some(poller) => {
+                thread::sleep(EVENT_LOOP_TIMEOUT);
+                poller.tick(&context);
+            }
+            None => match context.handle_events(Some(EVENT_LOOP_TIMEOUT)) {
+                Ok(()) | Err(rusb::Error::Interrupted) => {
+                    if usb_errors > 0 {
+                        tracing::info!("libusb events handled again after {usb_errors} error(s)");
+                    }
+                    usb_errors = 0;
+                    rebuilt = false;
+                }
+                // Transient (e.g. during suspend/resume): back off and keep going
+                Err(e) if usb_errors + 1 < usb_error_limit => {
+                    usb_errors += 1;
+                    tracing::warn!(
+                        "Can't handle libusb events ({usb_errors}/{usb_error_limit}): {e}"
+                    );
+                    thread::sleep(USB_ERROR_BACKOFF);
+                }
+                Err(e) if rebuilt => {
+                    tracing::error!(
+                        "Can't handle libusb events, even with a new libusb context: {e}"
+                    );
+                    drop(registrations);
+                    stop_handler(&tx, handler);
+                    return Err(Error::Usb(e));
+                }
+                Err(e) => {
+                    tracing::error!(
+                        "Can't handle libusb events ({usb_error_limit} errors in a row): {e}, rebuilding the libusb context"
+                    );
+
+                    // The registrations belong to the old context
+                    drop(registrations);
+                    let result: Result<(Context, Vec<Registration<Context>>), rusb::Error> =
+                        Context::new().and_then(|new| {
+                            // Devices plugged meanwhile are delivered again, the mounted ones are kept
+                            let new_registrations: Vec<Registration<Context>> =
+                                register_hotplug(&new, &vendor_ids, true, &hotplug_handler)?;
+                            Ok((new, new_registrations))
+                        });
+                    match result {
+                        Ok((new, new_registrations)) => {
+                            rescanner.set_context(new.clone());
+                            context = new;
+                            registrations = new_registrations;
+                            usb_errors = 0;
+                            rebuilt = true;
+                        }
+                        Err(e) => {
+                            tracing::error!("Can't rebuild the libusb context: {e}");
+                            stop_handler(&tx, handler);
+                            return Err(Error::Usb(e));
+                        }
+                    }
+                }
+            },
+        }
+
+        // Nothing would be mounted anymore
+        if handler.is_finished() {
+            tracing::error!("The handler thread stopped unexpectedly, exiting");
+            drop(registrations);
+            return Err(Error::HandlerStopped);
+        }
+
+        if let Some(watchdog) = watchdog.as_mut() {
+            watchdog.tick();
+        }
+
+        if let Some(health) = health.as_mut() {
+            health.tick(usb_errors > 0);
+        }
+    }
+
+    tracing::info!("Shutting down");
+    sd_notify::stopping();
+
+    // No more events
+    drop(registrations);
+    stop_handler(&tx, handler);
+
+    drop(health);
+    drop(pid_file);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ifuse_argv(
+        path: &str,
+        serial_number: &str,
+        mode: MountMode,
+        options: &[&str],
+    ) -> Vec<String> {
+        let options: Vec<String> = options.iter().map(|o| o.to_string()).collect();
+        ifuse_args(Path::new(path), serial_number, &mode, &options)
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn ifuse_argv_per_mode_and_options() {
+        assert_eq!(
+            ifuse_argv(
+                "/media/a",
+                "00008030001A2C3E0E38802E",
+                MountMode::Media,
+                &[]
+            ),
+            ["/media/a", "-u", "00008030-001A2C3E0E38802E"]
+        );
+        assert_eq!(
+            ifuse_argv(
+                "/media/a-com.example.app",
+                "0123456789abcdef0123456789abcdef01234567",
+                MountMode::Documents(String::from("com.example.app")),
+                &["ro"]
+            ),
+            [
+                "/media/a-com.example.app",
+                "-u",
+                "0123456789abcdef0123456789abcdef01234567",
+                "--documents",
+                "com.example.app",
+                "-o",
+                "ro"
+            ]
+        );
+        assert_eq!(
+            ifuse_argv(
+                "/media/a-root",
+                "00008030001A2C3E0E38802E",
+                MountMode::Root,
+                &["noatime", "ro", "allow_other"]
+            ),
+            [
+                "/media/a-root",
+                "-u",
+                "00008030-001A2C3E0E38802E",
+                "--root",
+                "-o",
+                "noatime,ro,allow_other"
+            ]
+        );
+    }
+
+    #[test]
+    fn ifuse_options_of_the_settings() {
+        let settings = |mount_options: &[&str], read_only: bool, allow_other: bool| {
+            DeviceSettings {
+                mount: true,
+                settle_delay: Duration::ZERO,
+                mount_options: mount_options.iter().map(|o| o.to_string()).collect(),
+                read_only,
+                allow_other,
+                mounts: Vec::new(),
+            }
+            .ifuse_options()
+        };
+
+        assert!(settings(&[], false, false).is_empty());
+        assert_eq!(settings(&[], true, true), ["ro", "allow_other"]);
+        // Not repeated
+        assert_eq!(settings(&["ro", "noatime"], true, false), ["ro", "noatime"]);
+        assert_eq!(
+            settings(&["allow_other"], true, true),
+            ["allow_other", "ro"]
+        );
+    }
+
+    #[test]
+    fn apple_device_ids() {
+        let mut config: Config = config::tests::default_config();
+
+        // iPhone, iPad, iPod touch
+        for product_id in [0x12a8, 0x12ab, 0x12aa] {
+            assert!(is_known_product(&config, product_id));
+        }
+        assert!(is_handled_vendor(&config, APPLE_VENDOR_ID));
+
+        // Apple keyboard, other vendor
+        assert!(!is_known_product(&config, 0x024f));
+        assert!(!is_handled_vendor(&config, 0x1d6b));
+
+        config.extra_vendor_ids = vec![0x1d6b];
+        config.extra_product_ids = vec![0x024f];
+        assert!(is_known_product(&config, 0x024f));
+        assert!(is_handled_vendor(&config, 0x1d6b));
+        // Built-in ones still known
+        assert!(is_known_product(&config, 0x12a8));
+        assert!(is_handled_vendor(&config, APPLE_VENDOR_ID));
+    }
+
+    /// Descriptor fields of real devices: vendor ID, product ID and interface classes
+    type Fixture = (u16, u16, &'static [(u8, u8, u8)]);
+
+    /// iPhone 13: PTP, usbmux, tethering
+    const IPHONE: Fixture = (0x05ac, 0x12a8, &[(6, 1, 1), (255, 254, 2), (255, 253, 1)]);
+    /// iPhone with a product ID newer than the list
+    const NEW_IPHONE: Fixture = (0x05ac, 0x12f0, &[(6, 1, 1), (255, 254, 2)]);
+    /// iPad in recovery mode
+    const RECOVERY: Fixture = (0x05ac, 0x1281, &[(255, 255, 81)]);
+    /// Magic Keyboard
+    const KEYBOARD: Fixture = (0x05ac, 0x0267, &[(3, 1, 1), (3, 0, 0)]);
+    /// Apple device without usbmux (e.g. a display)
+    const DISPLAY: Fixture = (0x05ac, 0x1114, &[(3, 0, 0), (1, 1, 0)]);
+    /// Android phone (MTP, ADB)
+    const ANDROID: Fixture = (0x18d1, 0x4ee2, &[(6, 1, 1), (255, 66, 1)]);
+
+    fn kind_of(config: &Config, (vendor_id, product_id, interfaces): Fixture) -> DeviceKind {
+        device_kind(config, vendor_id, product_id, || interfaces.to_vec())
+    }
+
+    #[test]
+    fn device_kind_of_the_descriptors() {
+        let mut config: Config = config::tests::default_config();
+
+        assert_eq!(kind_of(&config, IPHONE), DeviceKind::Ios);
+        assert_eq!(kind_of(&config, NEW_IPHONE), DeviceKind::Ios);
+        assert_eq!(kind_of(&config, RECOVERY), DeviceKind::Other);
+        assert_eq!(kind_of(&config, KEYBOARD), DeviceKind::Other);
+        assert_eq!(kind_of(&config, DISPLAY), DeviceKind::Other);
+        assert_eq!(kind_of(&config, ANDROID), DeviceKind::Other);
+
+        // Known product ID: the interfaces are not read
+        let kind: DeviceKind = device_kind(&config, 0x05ac, 0x12a8, || unreachable!());
+        assert_eq!(kind, DeviceKind::Ios);
+
+        // Permissive: Apple devices only, except the denied ranges
+        config.accept_unknown_apple = true;
+        assert_eq!(kind_of(&config, DISPLAY), DeviceKind::Permissive);
+        assert_eq!(kind_of(&config, RECOVERY), DeviceKind::Other);
+        assert_eq!(kind_of(&config, KEYBOARD), DeviceKind::Other);
+        assert_eq!(kind_of(&config, ANDROID), DeviceKind::Other);
+        assert_eq!(kind_of(&config, IPHONE), DeviceKind::Ios);
+
+        // Another vendor: with usbmux only
+        config.extra_vendor_ids = vec![0x18d1];
+        assert_eq!(kind_of(&config, ANDROID), DeviceKind::Other);
+        assert_eq!(
+            kind_of(&config, (0x18d1, 0x4ee2, &[(255, 254, 2)])),
+            DeviceKind::Ios
+        );
+    }
+
+    #[test]
+    fn reject_hostile_serial_numbers() {
+        for serial_number in [
+            "00008030-001A2C3E0E38802E",
+            "00008030001A2C3E0E38802E",
+            "0123456789abcdef0123456789abcdef01234567",
+        ] {
+            assert!(
+                check_serial_number(serial_number).is_ok(),
+                "{serial_number}"
+            );
+        }
+
+        let overlong: String = "A".repeat(MAX_SERIAL_NUMBER_LEN + 1);
+        for serial_number in [
+            "",
+            "..",
+            "../../etc",
+            "/",
+            "/etc/passwd",
+            "a/b",
+            "abc\0def",
+            "abc def",
+            "abc\n",
+            "-o",
+            "$(reboot)",
+            "iPhone\u{e9}",
+            overlong.as_str(),
+        ] {
+            assert!(
+                matches!(
+                    check_serial_number(serial_number),
+                    Err(Error::InvalidSerialNumber(..))
+                ),
+                "{serial_number:?}"
+            );
+        }
+        assert!(check_serial_number(&overlong[1..]).is_ok());
+    }
+
+    #[test]
+    fn mountpoint_under_base_path() {
+        let base_path: &Path = Path::new("/media/ifuse");
+
+        assert!(is_under_base_path(base_path, Path::new("/media/ifuse/x")));
+        assert!(is_under_base_path(
+            base_path,
+            Path::new("/media/ifuse/x/media")
+        ));
+
+        assert!(!is_under_base_path(base_path, Path::new("/media/ifuse")));
+        assert!(!is_under_base_path(base_path, Path::new("/media/ifuse/")));
+        assert!(!is_under_base_path(base_path, Path::new("/media/ifuse/..")));
+        assert!(!is_under_base_path(
+            base_path,
+            Path::new("/media/ifuse/../../etc")
+        ));
+        assert!(!is_under_base_path(
+            base_path,
+            Path::new("/media/ifuse/x/../..")
+        ));
+        assert!(!is_under_base_path(
+            base_path,
+            Path::new("/media/ifuse-other/x")
+        ));
+        assert!(!is_under_base_path(base_path, Path::new("/etc")));
+        assert!(!is_under_base_path(base_path, Path::new("media/ifuse/x")));
+    }
+
+    #[test]
+    fn udid_of_the_serial_number() {
+        // iPhone XS onward: 24 hex chars, dash after the 8th
+        assert_eq!(
+            udid("00008030001A2C3E0E38802E"),
+            "00008030-001A2C3E0E38802E"
+        );
+        assert_eq!(
+            serial_number_from_udid("00008030-001A2C3E0E38802E"),
+            "00008030001A2C3E0E38802E"
+        );
+
+        // Older devices: 40 hex chars, unchanged
+        let serial_number: &str = "0123456789abcdef0123456789abcdef01234567";
+        assert_eq!(udid(serial_number), serial_number);
+        assert_eq!(serial_number_from_udid(serial_number), serial_number);
+
+        // Round trip
+        for serial_number in ["00008101000A1B2C3D4E5F60", serial_number] {
+            assert_eq!(serial_number_from_udid(&udid(serial_number)), serial_number);
+        }
+
+        // Already a UDID (25 chars), not hex or not 24 chars: unchanged
+        for serial_number in [
+            "00008030-001A2C3E0E38802E",
+            "00008030001A2C3E0E38802G",
+            "00008030001A2C3E0E38802",
+        ] {
+            assert_eq!(udid(serial_number), serial_number);
+        }
+        for udid in [
+            "0000803-0001A2C3E0E38802E",
+            "00008030-001A2C3E0E38802G",
+            "00008030-001A2C3E-0E38802E",
+        ] {
+            assert_eq!(serial_number_from_udid(udid), udid);
+        }
+    }
+
+    #[test]
+    fn mount_health() {
+        let dir: PathBuf =
+            env::temp_dir().join(format!("ifuse-automount-health-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        assert!(is_mount_healthy(&[]));
+        assert!(is_mount_healthy(&[dir.clone()]));
+        assert!(!is_mount_healthy(&[dir.clone(), dir.join("missing")]));
+
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn report_walks_the_source_chain() {
+        let e: Error = Error::fs("create", Path::new("/media/x"))(io::Error::from(
+            io::ErrorKind::PermissionDenied,
+        ));
+        assert_eq!(e.report(), "Can't create /media/x: permission denied");
+
+        // Transparent: the source of the wrapped error is reported too
+        let inner: Error =
+            Error::fs("read", Path::new("/etc/passwd"))(io::Error::from(io::ErrorKind::NotFound));
+        let e: Error = Error::Io(io::Error::other(inner));
+        assert_eq!(e.report(), "Can't read /etc/passwd: entity not found");
+
+        let e: Error = Error::Usb(rusb::Error::Busy);
+        assert_eq!(e.report(), rusb::Error::Busy.to_string());
+    }
+
+    #[test]
+    fn report_includes_the_config_error() {
+        let source: toml::de::Error = toml::from_str::<toml::Table>("base_path =").unwrap_err();
+        let e: Error = Error::InvalidConfig(PathBuf::from("/etc/x.toml"), source.clone());
+        assert_eq!(
+            e.report(),
+            format!("Invalid config file /etc/x.toml: {source}")
+        );
     }
 }
@@ -0,0 +1,236 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Manual mount/unmount
+
+use std::path::{Path, PathBuf};
+
+use rusb::{Context, DeviceDescriptor, UsbContext};
+
+use crate::config::{Config, DeviceSettings, MountMode, MountSpec, Naming};
+use crate::links;
+use crate::meta;
+use crate::mounts::{self, MountEntry};
+use crate::state::{MountedDevice, State, Status};
+use crate::template::DeviceInfo;
+use crate::user::{self, User};
+use crate::{
+    control, ifuse_unmount, is_apple_device, is_ifuse_installed, model_name,
+    open_and_read_device_info, remove_mountpoint, Error, Handler,
+};
+
+/// Mount the device with the given serial number and print the mountpoint
+///
+/// `mode` overrides the mount mode of the device config.
+pub fn mount(config: Config, serial_number: &str, mode: Option<MountMode>) -> Result<(), Error> {
+    if !config.dry_run && !is_ifuse_installed(&config.ifuse_path, config.command_timeout) {
+        return Err(Error::IfuseNotInstalled(config.ifuse_path));
+    }
+
+    // Search the device on the bus
+    let context: Context = Context::new()?;
+    let mut info: DeviceInfo = find_device(&context, serial_number, &config)?;
+    if config.naming == Naming::Model {
+        info.model = model_name(&info, &daemon_devices(&config), config.command_timeout);
+    }
+
+    // Get device settings
+    let mut settings: DeviceSettings = config.device(serial_number);
+    if let Some(mode) = mode {
+        settings.mounts = vec![MountSpec { mode, path: None }];
+    }
+
+    let paths: Vec<PathBuf> = settings
+        .mounts
+        .iter()
+        .map(|spec| config.mountpoint(&info, spec))
+        .collect();
+
+    // Check if already mounted
+    let mount_table: Vec<MountEntry> = mounts::read()?;
+    if paths
+        .iter()
+        .all(|path| mounts::find(&mount_table, path).is_some())
+    {
+        tracing::info!("{serial_number} is already mounted");
+        for path in paths.iter() {
+            println!("{}", path.display());
+        }
+        return Ok(());
+    }
+
+    // Mount
+    let mut handler: Handler = Handler::new(config, State::new());
+    for path in handler.mount(info, &settings)?.iter() {
+        println!("{}", path.display());
+    }
+
+    Ok(())
+}
+
+/// Unmount the device with the given serial number and print the mountpoint
+///
+/// The mount state comes from the mount table, so this works also for devices mounted by the daemon.
+/// The directory name is the one used by the daemon, if it mounted the device. Otherwise, if it depends
+/// on more than the serial number (mount template or `naming = "model"`), the device must be connected.
+pub fn unmount(config: &Config, serial_number: &str, mode: Option<MountMode>) -> Result<(), Error> {
+    let specs: Vec<MountSpec> = match mode {
+        Some(mode) => vec![MountSpec { mode, path: None }],
+        None => config.device(serial_number).mounts,
+    };
+
+    let daemon_device: Option<MountedDevice> = daemon_devices(config)
+        .into_iter()
+        .find(|device| device.serial_number == serial_number);
+
+    let name: String = match daemon_device {
+        Some(device) => config.dir_name(&device.info()),
+        None => match config.dir_name_serial_only(serial_number) {
+            Some(name) => name,
+            None => {
+                let context: Context = Context::new()?;
+                let mut info: DeviceInfo = find_device(&context, serial_number, config)?;
+                if config.naming == Naming::Model {
+                    info.model = model_name(&info, &[], config.command_timeout);
+                }
+                config.dir_name(&info)
+            }
+        },
+    };
+
+    // Unmount the mounted ones, in reverse order
+    let mount_table: Vec<MountEntry> = mounts::read()?;
+    let mut found: bool = false;
+
+    for spec in specs.iter().rev() {
+        let path: PathBuf = spec.mountpoint(&config.base_path, name.clone());
+
+        if mounts::find(&mount_table, &path).is_none() {
+            tracing::warn!("Nothing mounted at {}", path.display());
+            continue;
+        }
+
+        found = true;
+        unmount_path(config, &path)?;
+        println!("{}", path.display());
+    }
+
+    if !found {
+        tracing::warn!("{serial_number} is not mounted");
+    } else if !config.dry_run {
+        meta::remove(&config.base_path, serial_number);
+    }
+
+    Ok(())
+}
+
+/// Unmount all the devices mounted under the base path and print their mountpoints
+///
+/// Return `false` if any unmount failed.
+pub fn unmount_all(config: &Config) -> Result<bool, Error> {
+    let mount_table: Vec<MountEntry> = mounts::read()?;
+    let mut found: bool = false;
+    let mut success: bool = true;
+
+    // Reverse order: nested mounts first
+    for entry in mount_table.iter().rev().filter(|entry| {
+        entry.target.starts_with(&config.base_path) && entry.target != config.base_path
+    }) {
+        found = true;
+
+        match unmount_path(config, &entry.target) {
+            Ok(()) => println!("{}", entry.target.display()),
+            Err(e) => {
+                tracing::error!("Can't unmount {}: {}", entry.target.display(), e.report());
+                success = false;
+            }
+        }
+    }
+
+    if !found {
+        tracing::warn!("Nothing mounted under {}", config.base_path.display());
+    }
+
+    // Metadata of the unmounted devices
+    if !config.dry_run {
+        meta::cleanup(&config.base_path)?;
+    }
+
+    Ok(success)
+}
+
+/// Unmount and remove the mountpoint
+fn unmount_path(config: &Config, path: &Path) -> Result<(), Error> {
+    if config.dry_run {
+        tracing::info!("[dry-run] Would unmount device from {}", path.display());
+        return Ok(());
+    }
+
+    tracing::info!(
+        "Unmounting device from {} (using {})",
+        path.display(),
+        config.unmount_backend()
+    );
+    // As the configured user, if any (root can unmount anyway)
+    let user: Option<User> = match &config.user {
+        Some(name) => Some(user::lookup(name)?),
+        None => None,
+    };
+    ifuse_unmount(config, path, user.as_ref())?;
+
+    if config.friendly_links {
+        links::remove(&config.base_path, &[path.to_path_buf()]);
+    }
+
+    if !config.keep_mountpoints {
+        remove_mountpoint(&config.base_path, path);
+    }
+
+    Ok(())
+}
+
+/// Devices mounted by the daemon running on the base path, if any
+fn daemon_devices(config: &Config) -> Vec<MountedDevice> {
+    match control::request(&config.base_path, "status")
+        .map(|response| serde_json::from_str::<Status>(&response))
+    {
+        Ok(Ok(status)) => status.devices,
+        Ok(Err(e)) => {
+            tracing::debug!("Can't parse the daemon status: {e}");
+            Vec::new()
+        }
+        Err(..) => Vec::new(),
+    }
+}
+
+/// Find the connected Apple device with the given serial number
+fn find_device<T>(context: &T, serial_number: &str, config: &Config) -> Result<DeviceInfo, Error>
+where
+    T: UsbContext,
+{
+    for device in context.devices()?.iter() {
+        let descriptor: DeviceDescriptor = device.device_descriptor()?;
+
+        if !is_apple_device(config, &device, &descriptor) {
+            continue;
+        }
+
+        match open_and_read_device_info(
+            &device,
+            &descriptor,
+            config.usb_timeout,
+            config.needs_name(),
+        ) {
+            Ok(info) if info.serial_number == serial_number => return Ok(info),
+            Ok(..) => continue,
+            Err(e) => tracing::warn!(
+                "Can't read serial number of device {:03}:{:03}: {}",
+                device.bus_number(),
+                device.address(),
+                e.report()
+            ),
+        }
+    }
+
+    Err(Error::DeviceNotFound)
+}
@@ -0,0 +1,159 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Per-device metadata files: `<base>/.meta/<serial>.json`
+//!
+//! For the scripts consuming the mounts, to find which directory is which device without querying USB.
+
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::MountMode;
+use crate::mounts::{self, MountEntry};
+use crate::Error;
+
+const META_DIR: &str = ".meta";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metadata {
+    pub serial_number: String,
+    pub mounts: Vec<MetadataMount>,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Name given by the owner, or USB product string
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// UNIX timestamp
+    pub mounted_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MetadataMount {
+    pub mountpoint: PathBuf,
+    pub mode: MountMode,
+}
+
+#[inline]
+fn dir(base_path: &Path) -> PathBuf {
+    base_path.join(META_DIR)
+}
+
+#[inline]
+fn path(base_path: &Path, serial_number: &str) -> PathBuf {
+    dir(base_path).join(format!("{serial_number}.json"))
+}
+
+/// Write the metadata file of the device
+///
+/// Atomic: written to a temporary file, then renamed.
+pub fn write(base_path: &Path, metadata: &Metadata) -> Result<(), Error> {
+    let dir: PathBuf = dir(base_path);
+    fs::create_dir_all(&dir).map_err(Error::fs("create", &dir))?;
+
+    let path: PathBuf = path(base_path, &metadata.serial_number);
+    let tmp: PathBuf = dir.join(format!(".{}.json.tmp", metadata.serial_number));
+
+    let mut file: fs::File = fs::File::create(&tmp).map_err(Error::fs("create", &tmp))?;
+    serde_json::to_writer_pretty(&mut file, metadata)?;
+    file.write_all(b"\n").map_err(Error::fs("write", &tmp))?;
+    file.sync_all().map_err(Error::fs("sync", &tmp))?;
+
+    fs::rename(&tmp, &path).map_err(Error::fs("rename", &tmp))?;
+    Ok(())
+}
+
+/// Read the metadata file of the device, if any (and valid)
+pub fn read(base_path: &Path, serial_number: &str) -> Option<Metadata> {
+    let content: String = fs::read_to_string(path(base_path, serial_number)).ok()?;
+    serde_json::from_str(&content).ok()
+}
+
+/// Remove the metadata file of the device, if any
+pub fn remove(base_path: &Path, serial_number: &str) {
+    match fs::remove_file(path(base_path, serial_number)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => tracing::warn!("Can't remove metadata of {serial_number}: {e}"),
+    }
+}
+
+/// Remove the metadata files whose mountpoints are no longer mounted (e.g. left by an unclean shutdown)
+pub fn cleanup(base_path: &Path) -> Result<(), Error> {
+    let dir: PathBuf = dir(base_path);
+    let entries: fs::ReadDir = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Error::fs("read", &dir)(e)),
+    };
+    let mount_table: Vec<MountEntry> = mounts::read()?;
+
+    for entry in entries {
+        let path: PathBuf = entry.map_err(Error::fs("read", &dir))?.path();
+        let name: String = path
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+
+        // Interrupted writes
+        if name.ends_with(".json.tmp") {
+            let _ = fs::remove_file(&path);
+            continue;
+        }
+
+        if !name.ends_with(".json") {
+            continue;
+        }
+
+        let is_mounted: bool = match fs::read_to_string(&path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<Metadata>(&content).ok())
+        {
+            Some(metadata) => is_mounted(&metadata, &mount_table),
+            // Unreadable
+            None => false,
+        };
+
+        if !is_mounted {
+            tracing::debug!("Removing stale metadata {}", path.display());
+            if let Err(e) = fs::remove_file(&path) {
+                tracing::warn!("Can't remove {}: {e}", path.display());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Serial numbers of the devices mounted under the base path, sorted (e.g. for the shell completions)
+pub fn mounted_serials(base_path: &Path) -> Result<Vec<String>, Error> {
+    let dir: PathBuf = dir(base_path);
+    let entries: fs::ReadDir = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(Error::fs("read", &dir)(e)),
+    };
+    let mount_table: Vec<MountEntry> = mounts::read()?;
+
+    let mut serials: Vec<String> = entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".json"))
+        .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+        .filter_map(|content| serde_json::from_str::<Metadata>(&content).ok())
+        .filter(|metadata| is_mounted(metadata, &mount_table))
+        .map(|metadata| metadata.serial_number)
+        .collect();
+    serials.sort();
+    Ok(serials)
+}
+
+/// Check if any mountpoint of the device is in the mount table
+fn is_mounted(metadata: &Metadata, mount_table: &[MountEntry]) -> bool {
+    metadata
+        .mounts
+        .iter()
+        .any(|mount| mounts::find(mount_table, &mount.mountpoint).is_some())
+}
@@ -0,0 +1,294 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Prometheus metrics endpoint (`--metrics-listen`)
+//!
+//! A minimal HTTP server, on its own thread, answering `GET /metrics` with the text exposition
+//! format. The counters come from the shared [`State`], so scraping never waits for the handler.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::str::SplitWhitespace;
+use std::thread;
+use std::time::Duration;
+
+use crate::state::{DeviceCounters, State, Status};
+use crate::{lock, Error};
+
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Serve the metrics on `addr`, in a dedicated thread
+///
+/// With `hash_serials`, the `serial` labels are hashes of the serial numbers.
+pub fn spawn(addr: SocketAddr, state: State, hash_serials: bool) -> Result<(), Error> {
+    let listener: TcpListener = TcpListener::bind(addr)?;
+    tracing::info!("Serving the metrics at http://{addr}/metrics");
+
+    serve(listener, state, hash_serials);
+    Ok(())
+}
+
+/// Answer the clients of the listener, in a dedicated thread
+fn serve(listener: TcpListener, state: State, hash_serials: bool) {
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = handle_client(stream, &state, hash_serials) {
+                        tracing::debug!("Metrics endpoint: {e}");
+                    }
+                }
+                Err(e) => tracing::warn!("Metrics endpoint: {e}"),
+            }
+        }
+    });
+}
+
+fn handle_client(stream: TcpStream, state: &State, hash_serials: bool) -> Result<(), Error> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    // Request line, then the headers until the empty line (ignored)
+    let mut reader: BufReader<&TcpStream> = BufReader::new(&stream);
+    let mut request_line: String = String::new();
+    reader.read_line(&mut request_line)?;
+    loop {
+        let mut header: String = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts: SplitWhitespace<'_> = request_line.split_whitespace();
+    let (status, body): (&str, String) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            render(&state.status(), &state.device_counters(), hash_serials),
+        ),
+        (Some("GET"), Some(..)) => ("404 Not Found", String::from("Not found\n")),
+        _ => (
+            "405 Method Not Allowed",
+            String::from("Method not allowed\n"),
+        ),
+    };
+
+    let mut stream: &TcpStream = &stream;
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: {CONTENT_TYPE}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Text exposition format
+fn render(
+    status: &Status,
+    devices: &BTreeMap<String, DeviceCounters>,
+    hash_serials: bool,
+) -> String {
+    let mut out: String = String::new();
+
+    header(
+        &mut out,
+        "ifuse_automount_mounted_devices",
+        "gauge",
+        "Devices currently mounted",
+    );
+    let _ = writeln!(
+        out,
+        "ifuse_automount_mounted_devices {}",
+        status.devices.len()
+    );
+
+    header(
+        &mut out,
+        "ifuse_automount_queued_events",
+        "gauge",
+        "Device events waiting for the handler thread",
+    );
+    let _ = writeln!(out, "ifuse_automount_queued_events {}", status.queued);
+
+    let serials: Vec<(String, &DeviceCounters)> = devices
+        .iter()
+        .map(|(serial_number, counters)| (serial_label(serial_number, hash_serials), counters))
+        .collect();
+
+    header(
+        &mut out,
+        "ifuse_automount_mounts_total",
+        "counter",
+        "Successful mounts",
+    );
+    for (serial, counters) in serials.iter() {
+        let _ = writeln!(
+            out,
+            "ifuse_automount_mounts_total{{serial=\"{serial}\"}} {}",
+            counters.mounts_succeeded
+        );
+    }
+
+    header(
+        &mut out,
+        "ifuse_automount_unmounts_total",
+        "counter",
+        "Unmounts",
+    );
+    for (serial, counters) in serials.iter() {
+        let _ = writeln!(
+            out,
+            "ifuse_automount_unmounts_total{{serial=\"{serial}\"}} {}",
+            counters.unmounts
+        );
+    }
+
+    header(
+        &mut out,
+        "ifuse_automount_mount_failures_total",
+        "counter",
+        "Failed mount attempts, by error class",
+    );
+    for (serial, counters) in serials.iter() {
+        for (class, count) in counters.mounts_failed.iter() {
+            let _ = writeln!(
+                out,
+                "ifuse_automount_mount_failures_total{{serial=\"{serial}\",class=\"{class}\"}} {count}"
+            );
+        }
+    }
+
+    header(
+        &mut out,
+        "ifuse_automount_retries_total",
+        "counter",
+        "Mount retries performed",
+    );
+    for (serial, counters) in serials.iter() {
+        let _ = writeln!(
+            out,
+            "ifuse_automount_retries_total{{serial=\"{serial}\"}} {}",
+            counters.retries
+        );
+    }
+
+    out
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Value of a `serial` label, escaped
+fn serial_label(serial_number: &str, hash_serials: bool) -> String {
+    if hash_serials {
+        return format!("{:016x}", lock::fnv1a(serial_number.as_bytes()));
+    }
+
+    serial_number
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    const IPHONE: &str = "00008030001A2C3E0E38802E";
+    const IPAD: &str = "00008101000A1B2C3D4E5F60";
+
+    /// Serve the state on a free port
+    fn serve_on_free_port(state: State, hash_serials: bool) -> SocketAddr {
+        let listener: TcpListener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr: SocketAddr = listener.local_addr().unwrap();
+        serve(listener, state, hash_serials);
+        addr
+    }
+
+    /// Raw HTTP response
+    fn get(addr: SocketAddr, request_line: &str) -> String {
+        let mut stream: TcpStream = TcpStream::connect(addr).unwrap();
+        write!(stream, "{request_line}\r\nHost: {addr}\r\n\r\n").unwrap();
+        let mut response: String = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    /// Body of the response, checking the status and the content type
+    fn scrape(addr: SocketAddr) -> String {
+        let response: String = get(addr, "GET /metrics HTTP/1.1");
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{head}");
+        assert!(head.contains(&format!("Content-Type: {CONTENT_TYPE}")));
+        assert!(head.contains(&format!("Content-Length: {}", body.len())));
+        body.to_string()
+    }
+
+    #[test]
+    fn scrape_after_events() {
+        let state: State = State::new();
+        let addr: SocketAddr = serve_on_free_port(state.clone(), false);
+
+        // Nothing yet
+        let body: String = scrape(addr);
+        assert!(body.contains("# TYPE ifuse_automount_mounted_devices gauge\n"));
+        assert!(body.contains("\nifuse_automount_mounted_devices 0\n"));
+        assert!(body.contains("\nifuse_automount_queued_events 0\n"));
+        assert!(body.contains("# TYPE ifuse_automount_mounts_total counter\n"));
+        assert!(!body.contains("serial="));
+
+        // Locked, retried, mounted and unmounted; the other one fails
+        state.event_queued();
+        state.count_mount_failure(IPHONE, "pairing");
+        state.count_retry(IPHONE);
+        state.count_mount_success(IPHONE);
+        state.count_unmount(IPHONE);
+        state.count_mount_failure(IPAD, "fuse");
+        state.count_mount_failure(IPAD, "fuse");
+
+        let body: String = scrape(addr);
+        for line in [
+            "ifuse_automount_queued_events 1",
+            "ifuse_automount_mounts_total{serial=\"00008030001A2C3E0E38802E\"} 1",
+            "ifuse_automount_mounts_total{serial=\"00008101000A1B2C3D4E5F60\"} 0",
+            "ifuse_automount_unmounts_total{serial=\"00008030001A2C3E0E38802E\"} 1",
+            "ifuse_automount_mount_failures_total{serial=\"00008030001A2C3E0E38802E\",class=\"pairing\"} 1",
+            "ifuse_automount_mount_failures_total{serial=\"00008101000A1B2C3D4E5F60\",class=\"fuse\"} 2",
+            "ifuse_automount_retries_total{serial=\"00008030001A2C3E0E38802E\"} 1",
+            "ifuse_automount_retries_total{serial=\"00008101000A1B2C3D4E5F60\"} 0",
+        ] {
+            assert!(body.lines().any(|l| l == line), "{line} missing in:\n{body}");
+        }
+    }
+
+    #[test]
+    fn scrape_with_hashed_serials() {
+        let state: State = State::new();
+        state.count_mount_success(IPHONE);
+        let addr: SocketAddr = serve_on_free_port(state, true);
+
+        let body: String = scrape(addr);
+        assert!(!body.contains(IPHONE));
+        assert!(body.lines().any(|l| l
+            == format!(
+                "ifuse_automount_mounts_total{{serial=\"{:016x}\"}} 1",
+                lock::fnv1a(IPHONE.as_bytes())
+            )));
+    }
+
+    #[test]
+    fn other_requests() {
+        let addr: SocketAddr = serve_on_free_port(State::new(), false);
+        assert!(get(addr, "GET / HTTP/1.1").starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(
+            get(addr, "POST /metrics HTTP/1.1").starts_with("HTTP/1.1 405 Method Not Allowed\r\n")
+        );
+    }
+}
@@ -0,0 +1,163 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Mount table
+
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+const MOUNTS: &str = "/proc/self/mounts";
+const IFUSE_FSTYPE: &str = "fuse.ifuse";
+
+/// Mount table entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MountEntry {
+    pub source: String,
+    pub target: PathBuf,
+    pub fstype: String,
+}
+
+impl MountEntry {
+    /// Check if the entry is an ifuse mount
+    #[inline]
+    pub fn is_ifuse(&self) -> bool {
+        self.fstype == IFUSE_FSTYPE
+    }
+}
+
+/// Read the mount table
+pub fn read() -> io::Result<Vec<MountEntry>> {
+    let content: String = fs::read_to_string(MOUNTS)?;
+    Ok(parse(&content))
+}
+
+/// Parse the mount table (`/proc/self/mounts` format)
+pub fn parse(content: &str) -> Vec<MountEntry> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let source: OsString = unescape(fields.next()?);
+            let target: OsString = unescape(fields.next()?);
+            let fstype: OsString = unescape(fields.next()?);
+            Some(MountEntry {
+                source: source.to_string_lossy().into_owned(),
+                target: PathBuf::from(target),
+                fstype: fstype.to_string_lossy().into_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Find the entry mounted at `path`
+///
+/// If `path` is mounted multiple times, the last (topmost) entry is returned.
+pub fn find<'a>(entries: &'a [MountEntry], path: &Path) -> Option<&'a MountEntry> {
+    entries.iter().rev().find(|entry| entry.target == path)
+}
+
+/// Decode the octal escapes used in the mount table (e.g. `\040` for a space)
+fn unescape(field: &str) -> OsString {
+    let bytes: &[u8] = field.as_bytes();
+    let mut decoded: Vec<u8> = Vec::with_capacity(bytes.len());
+    let mut i: usize = 0;
+
+    while i < bytes.len() {
+        let escaped: Option<u8> = match bytes.get(i..i + 4) {
+            Some([b'\\', digits @ ..]) => octal(digits),
+            _ => None,
+        };
+
+        match escaped {
+            Some(value) => {
+                decoded.push(value);
+                i += 4;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+
+    OsString::from_vec(decoded)
+}
+
+/// Parse 3 octal digits
+fn octal(digits: &[u8]) -> Option<u8> {
+    let mut value: u32 = 0;
+    for digit in digits.iter() {
+        if !(b'0'..=b'7').contains(digit) {
+            return None;
+        }
+        value = value * 8 + u32::from(digit - b'0');
+    }
+    u8::try_from(value).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNT_TABLE: &str = "\
+proc /proc proc rw,nosuid,nodev,noexec,relatime 0 0
+/dev/nvme0n1p2 / ext4 rw,relatime 0 0
+ifuse /run/user/1000/ifuse-automount/00008030001A2C3E0E38802E fuse.ifuse rw,nosuid,nodev,relatime,user_id=1000,group_id=1000 0 0
+ifuse /media/ifuse/Yuki\\040iPhone\\011tab fuse.ifuse rw,nosuid,nodev,relatime,user_id=0,group_id=0,allow_other 0 0
+sshfs#host:/data /media/ifuse/other fuse.sshfs rw,nosuid,nodev,relatime 0 0
+/dev/sdb1 /media/USB\\134stick vfat rw,relatime 0 0
+";
+
+    #[test]
+    fn parse_mount_table() {
+        let entries: Vec<MountEntry> = parse(MOUNT_TABLE);
+        assert_eq!(entries.len(), 6);
+
+        assert_eq!(
+            entries[2],
+            MountEntry {
+                source: String::from("ifuse"),
+                target: PathBuf::from("/run/user/1000/ifuse-automount/00008030001A2C3E0E38802E"),
+                fstype: String::from("fuse.ifuse"),
+            }
+        );
+        assert!(entries[2].is_ifuse());
+
+        // Escaped space, tab and backslash
+        assert_eq!(
+            entries[3].target,
+            PathBuf::from("/media/ifuse/Yuki iPhone\ttab")
+        );
+        assert!(entries[3].is_ifuse());
+        assert_eq!(entries[5].target, PathBuf::from("/media/USB\\stick"));
+
+        // Other filesystems, FUSE or not
+        assert!(!entries[0].is_ifuse());
+        assert!(!entries[1].is_ifuse());
+        assert!(!entries[4].is_ifuse());
+        assert!(!entries[5].is_ifuse());
+    }
+
+    #[test]
+    fn parse_malformed_lines() {
+        let entries: Vec<MountEntry> = parse("\nifuse /media/x\nifuse /media/y fuse.ifuse\n");
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].target, PathBuf::from("/media/y"));
+
+        // Not an escape: kept as is
+        assert_eq!(unescape("a\\09b\\0"), OsString::from("a\\09b\\0"));
+        // Out of the byte range
+        assert_eq!(unescape("\\777"), OsString::from("\\777"));
+    }
+
+    #[test]
+    fn find_the_topmost_entry() {
+        let entries: Vec<MountEntry> =
+            parse("tmpfs /media/ifuse/x tmpfs rw 0 0\nifuse /media/ifuse/x fuse.ifuse rw 0 0\n");
+        let entry: &MountEntry = find(&entries, Path::new("/media/ifuse/x")).unwrap();
+        assert!(entry.is_ifuse());
+        assert!(find(&entries, Path::new("/media/ifuse")).is_none());
+    }
+}
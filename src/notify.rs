@@ -0,0 +1,76 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Desktop notifications, with `notify-send`
+
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::child;
+use crate::user::User;
+
+const NOTIFY_SEND: &str = "notify-send";
+const APP_NAME: &str = "ifuse-automount";
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Warn about a missing `notify-send` only once
+static MISSING_WARNED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Urgency {
+    Normal,
+    Critical,
+}
+
+impl Urgency {
+    #[inline]
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Critical => "critical",
+        }
+    }
+}
+
+/// Show a notification in the background, as `user` if set (i.e. when running as root)
+///
+/// Never blocks: failures (no notification daemon, `notify-send` not installed) are only logged.
+pub fn send<S, B>(summary: S, body: B, urgency: Urgency, user: Option<User>)
+where
+    S: Into<String>,
+    B: Into<String>,
+{
+    let summary: String = summary.into();
+    let body: String = body.into();
+
+    thread::spawn(move || {
+        // `notify-send --app-name=<app> --urgency=<urgency> <summary> <body>`
+        let mut cmd: Command = Command::new(NOTIFY_SEND);
+        cmd.arg(format!("--app-name={APP_NAME}"))
+            .arg(format!("--urgency={}", urgency.as_str()))
+            .arg(&summary)
+            .arg(&body)
+            .stdout(Stdio::null());
+
+        // Session bus of the user
+        if let Some(user) = &user {
+            user.apply_session(&mut cmd);
+        }
+
+        match child::output(&mut cmd, TIMEOUT) {
+            Ok(output) if output.status.success() => {}
+            Ok(output) => tracing::debug!(
+                "Can't show notification: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ),
+            Err(e) if e.is_not_found() => {
+                if !MISSING_WARNED.swap(true, Ordering::Relaxed) {
+                    tracing::warn!("{NOTIFY_SEND} not installed, notifications disabled");
+                }
+            }
+            Err(e) => tracing::debug!("Can't show notification: {e}"),
+        }
+    });
+}
@@ -0,0 +1,111 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Mounted devices saved across restarts: `<runtime-dir>/ifuse-automount-<hash of the base path>.state.json`
+//!
+//! Without it, the devices mounted before a restart can't be unmounted when they leave.
+
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::mounts::{self, MountEntry};
+use crate::state::{self, MountedDevice};
+use crate::{lock, Error};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedState {
+    /// UNIX timestamp
+    saved_at: u64,
+    devices: Vec<MountedDevice>,
+}
+
+/// State file of the base path
+#[inline]
+pub fn path(base_path: &Path) -> Result<PathBuf, Error> {
+    lock::instance_file(base_path, "state.json")
+}
+
+/// Save the mounted devices
+///
+/// Atomic: written to a temporary file, then renamed.
+pub fn save(path: &Path, devices: Vec<MountedDevice>) -> Result<(), Error> {
+    let tmp: PathBuf = path.with_extension("json.tmp");
+    let saved: SavedState = SavedState {
+        saved_at: state::now(),
+        devices,
+    };
+
+    let mut file: fs::File = fs::File::create(&tmp).map_err(Error::fs("create", &tmp))?;
+    serde_json::to_writer_pretty(&mut file, &saved)?;
+    file.write_all(b"\n").map_err(Error::fs("write", &tmp))?;
+    file.sync_all().map_err(Error::fs("sync", &tmp))?;
+
+    fs::rename(&tmp, path).map_err(Error::fs("rename", &tmp))?;
+    Ok(())
+}
+
+/// Load the devices saved by the previous instance, keeping only the ones still mounted
+///
+/// A corrupt state file is discarded with a warning.
+pub fn load(path: &Path) -> Vec<MountedDevice> {
+    let content: String = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Vec::new(),
+        Err(e) => {
+            tracing::warn!("Can't read the state file {}: {e}", path.display());
+            return Vec::new();
+        }
+    };
+
+    let saved: SavedState = match serde_json::from_str(&content) {
+        Ok(saved) => saved,
+        Err(e) => {
+            tracing::warn!("Discarding the corrupt state file {}: {e}", path.display());
+            discard(path);
+            return Vec::new();
+        }
+    };
+
+    // Trusted only if all its mountpoints are still ifuse mounts
+    let mount_table: Vec<MountEntry> = match mounts::read() {
+        Ok(mount_table) => mount_table,
+        Err(e) => {
+            tracing::warn!("Can't read the mount table, discarding the state file: {e}");
+            return Vec::new();
+        }
+    };
+
+    let (devices, stale): (Vec<MountedDevice>, Vec<MountedDevice>) =
+        saved.devices.into_iter().partition(|device| {
+            !device.mountpoints.is_empty()
+                && device
+                    .mountpoints
+                    .iter()
+                    .all(|path| mounts::find(&mount_table, path).is_some_and(MountEntry::is_ifuse))
+        });
+
+    for device in stale.iter() {
+        tracing::warn!(
+            "Discarding the saved state of {}: no longer mounted",
+            device.serial_number
+        );
+    }
+
+    tracing::debug!(
+        "Loaded {} device(s) from {} (saved at {})",
+        devices.len(),
+        path.display(),
+        saved.saved_at
+    );
+
+    devices
+}
+
+fn discard(path: &Path) {
+    if let Err(e) = fs::remove_file(path) {
+        tracing::warn!("Can't remove {}: {e}", path.display());
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Polling fallback, where libusb hotplug is unsupported (e.g. some BSDs, containers)
+//!
+//! The device list is scanned at a fixed interval, and the differences with the previous scan are
+//! delivered to the hotplug handler, as if libusb had reported them.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use rusb::{Device, DeviceList, Hotplug, UsbContext};
+
+use crate::DeviceAddr;
+
+pub struct Poller<T, H>
+where
+    T: UsbContext,
+    H: Hotplug<T>,
+{
+    interval: Duration,
+    vendor_ids: Vec<u16>,
+    handler: H,
+    /// Devices of the previous scan
+    devices: HashMap<DeviceAddr, Device<T>>,
+    last_scan: Option<Instant>,
+}
+
+impl<T, H> Poller<T, H>
+where
+    T: UsbContext,
+    H: Hotplug<T>,
+{
+    pub fn new(interval: Duration, vendor_ids: Vec<u16>, handler: H) -> Self {
+        Self {
+            interval,
+            vendor_ids,
+            handler,
+            devices: HashMap::new(),
+            last_scan: None,
+        }
+    }
+
+    /// First scan: the connected devices arrive only if `enumerate` is set
+    pub fn start(&mut self, context: &T, enumerate: bool) {
+        self.scan(context, enumerate);
+    }
+
+    /// Scan again, if the interval elapsed
+    pub fn tick(&mut self, context: &T) {
+        let due: bool = self
+            .last_scan
+            .is_none_or(|last| last.elapsed() >= self.interval);
+        if due {
+            self.scan(context, true);
+        }
+    }
+
+    fn scan(&mut self, context: &T, deliver: bool) {
+        self.last_scan = Some(Instant::now());
+
+        let list: DeviceList<T> = match context.devices() {
+            Ok(list) => list,
+            Err(e) => {
+                tracing::warn!("Can't list the USB devices: {e}");
+                return;
+            }
+        };
+
+        let mut current: HashMap<DeviceAddr, Device<T>> = HashMap::new();
+        for device in list.iter() {
+            match device.device_descriptor() {
+                Ok(descriptor) if self.vendor_ids.contains(&descriptor.vendor_id()) => {
+                    let addr: DeviceAddr = DeviceAddr {
+                        bus: device.bus_number(),
+                        addr: device.address(),
+                    };
+                    current.insert(addr, device);
+                }
+                Ok(..) => {}
+                Err(e) => tracing::debug!(
+                    "Can't read the descriptor of {:03}:{:03}: {e}",
+                    device.bus_number(),
+                    device.address()
+                ),
+            }
+        }
+
+        let previous: HashMap<DeviceAddr, Device<T>> = std::mem::take(&mut self.devices);
+
+        if deliver {
+            // Departures first, as libusb would have reported them before
+            for (addr, device) in previous.iter() {
+                if !current.contains_key(addr) {
+                    self.handler.device_left(device.clone());
+                }
+            }
+
+            for (addr, device) in current.iter() {
+                if !previous.contains_key(addr) {
+                    self.handler.device_arrived(device.clone());
+                }
+            }
+        }
+
+        self.devices = current;
+    }
+}
@@ -0,0 +1,99 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Names of the Apple USB product IDs (e.g. `0x12a8` -> `iPhone 5/5C/5S/6/SE/7/8/X/XR`)
+//!
+//! The handled product IDs ([`APPLE_PRODUCT_IDS`](crate::APPLE_PRODUCT_IDS)) are built from this
+//! table, so that each one has a name.
+
+use std::borrow::Cow;
+
+use crate::APPLE_VENDOR_ID;
+
+/// Number of built-in product IDs
+pub const COUNT: usize = PRODUCTS.len();
+
+/// `(product ID, name)`
+const PRODUCTS: [(u16, &str); 25] = [
+    (0x1290, "iPhone"),
+    (0x1291, "iPod Touch 1.Gen"),
+    (0x1292, "iPhone 3G"),
+    (0x1293, "iPod Touch 2.Gen"),
+    (0x1294, "iPhone 3GS"),
+    (0x1296, "iPod Touch 3.Gen (8GB)"),
+    (0x1297, "iPhone 4"),
+    (0x1299, "iPod Touch 3.Gen"),
+    (0x129a, "iPad"),
+    (0x129c, "iPhone 4(CDMA)"),
+    (0x129d, "iPhone"),
+    (0x129e, "iPod Touch 4.Gen"),
+    (0x129f, "iPad 2"),
+    (0x12a0, "iPhone 4S"),
+    (0x12a1, "iPhone"),
+    (0x12a2, "iPad 2 (3G; 64GB)"),
+    (0x12a3, "iPad 2 (CDMA)"),
+    (0x12a4, "iPad 3 (wifi)"),
+    (0x12a5, "iPad 3 (CDMA)"),
+    (0x12a6, "iPad 3 (3G, 16 GB)"),
+    (0x12a8, "iPhone 5/5C/5S/6/SE/7/8/X/XR"),
+    (0x12a9, "iPad 2"),
+    (0x12aa, "iPod Touch 5.Gen [A1421]"),
+    (0x12ab, "iPad"),
+    (0x12ac, "iPhone"),
+];
+
+/// Built-in product IDs, in the order of the table
+pub const fn ids() -> [u16; COUNT] {
+    let mut ids: [u16; COUNT] = [0; COUNT];
+    let mut index: usize = 0;
+    while index < COUNT {
+        ids[index] = PRODUCTS[index].0;
+        index += 1;
+    }
+    ids
+}
+
+/// Name of the product, or its IDs (e.g. `05ac:12b0`) if unknown
+pub fn name(vendor_id: u16, product_id: u16) -> Cow<'static, str> {
+    if vendor_id == APPLE_VENDOR_ID {
+        if let Some((_, name)) = PRODUCTS.iter().find(|(id, _)| *id == product_id) {
+            return Cow::Borrowed(name);
+        }
+    }
+
+    Cow::Owned(format!("{vendor_id:04x}:{product_id:04x}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::APPLE_PRODUCT_IDS;
+
+    #[test]
+    fn every_product_id_named() {
+        for product_id in APPLE_PRODUCT_IDS {
+            let name: Cow<'static, str> = name(APPLE_VENDOR_ID, product_id);
+            assert!(
+                matches!(name, Cow::Borrowed(_)),
+                "{product_id:04x} has no name"
+            );
+            assert!(!name.is_empty());
+        }
+    }
+
+    #[test]
+    fn unique_product_ids() {
+        for (index, (id, _)) in PRODUCTS.iter().enumerate() {
+            assert!(
+                !PRODUCTS[index + 1..].iter().any(|(other, _)| other == id),
+                "{id:04x}"
+            );
+        }
+    }
+
+    #[test]
+    fn unknown_product_id() {
+        assert_eq!(name(APPLE_VENDOR_ID, 0x1234), "05ac:1234");
+        assert_eq!(name(0x18d1, 0x12a8), "18d1:12a8");
+    }
+}
@@ -0,0 +1,133 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Rescan of the connected devices, on SIGUSR2 or with the `rescan` control command
+//!
+//! Every Apple device that isn't mounted is delivered to the handler thread again, as the ones
+//! connected at startup are (e.g. to retry a mount that failed because the device was locked).
+
+use std::sync::{mpsc, Arc, Mutex, PoisonError};
+use std::thread;
+
+use rusb::{Context, DeviceDescriptor, UsbContext};
+use serde::{Deserialize, Serialize};
+use signal_hook::consts::SIGUSR2;
+use signal_hook::iterator::Signals;
+
+use crate::config::Config;
+use crate::state::State;
+use crate::{control, Action, DeviceAddr, Error, Message};
+
+/// Outcome of a rescan
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct RescanResult {
+    /// Devices of the handled vendors on the bus
+    pub candidates: usize,
+    /// Devices not mounted, queued for mounting
+    pub queued: usize,
+}
+
+#[derive(Clone)]
+pub struct Rescanner {
+    /// Replaced when the libusb context is rebuilt
+    context: Arc<Mutex<Context>>,
+    vendor_ids: Vec<u16>,
+    tx: mpsc::Sender<Message<Context>>,
+    state: State,
+}
+
+impl Rescanner {
+    pub fn new(
+        context: Context,
+        vendor_ids: Vec<u16>,
+        tx: mpsc::Sender<Message<Context>>,
+        state: State,
+    ) -> Self {
+        Self {
+            context: Arc::new(Mutex::new(context)),
+            vendor_ids,
+            tx,
+            state,
+        }
+    }
+
+    /// Rescan with a new libusb context, the previous one being dead
+    pub fn set_context(&self, context: Context) {
+        *self.context.lock().unwrap_or_else(PoisonError::into_inner) = context;
+    }
+
+    /// Queue a mount for every device of the handled vendors that isn't mounted
+    ///
+    /// The mounted ones are left alone.
+    pub fn rescan(&self) -> Result<RescanResult, Error> {
+        let mut result: RescanResult = RescanResult::default();
+
+        let context: Context = self
+            .context
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone();
+        for device in context.devices()?.iter() {
+            let descriptor: DeviceDescriptor = match device.device_descriptor() {
+                Ok(descriptor) => descriptor,
+                Err(..) => continue,
+            };
+            if !self.vendor_ids.contains(&descriptor.vendor_id()) {
+                continue;
+            }
+            result.candidates += 1;
+
+            let addr: DeviceAddr = DeviceAddr {
+                bus: device.bus_number(),
+                addr: device.address(),
+            };
+            if self.state.contains(&addr) {
+                continue;
+            }
+
+            self.tx
+                .send(Message::Device(device, Action::Mount))
+                .map_err(|_| Error::HandlerStopped)?;
+            self.state.event_queued();
+            result.queued += 1;
+        }
+
+        tracing::info!(
+            "Rescan: {} candidate device(s) found, {} queued for mounting",
+            result.candidates,
+            result.queued
+        );
+
+        Ok(result)
+    }
+}
+
+/// Rescan on SIGUSR2
+pub fn spawn_listener(rescanner: Rescanner) -> Result<(), Error> {
+    let mut signals: Signals = Signals::new([SIGUSR2])?;
+
+    thread::spawn(move || {
+        for _ in signals.forever() {
+            tracing::info!("Received SIGUSR2, rescanning the devices");
+
+            if let Err(e) = rescanner.rescan() {
+                tracing::error!("Can't rescan the devices: {}", e.report());
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Ask the daemon running on the base path to rescan, and print the outcome
+pub fn request(config: &Config) -> Result<(), Error> {
+    let response: String = control::request(&config.base_path, "rescan")?;
+    let result: RescanResult = serde_json::from_str(&response)?;
+
+    println!(
+        "{} candidate device(s) found, {} queued for mounting",
+        result.candidates, result.queued
+    );
+
+    Ok(())
+}
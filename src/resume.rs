@@ -0,0 +1,57 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Resume detection without logind
+//!
+//! `CLOCK_BOOTTIME` keeps counting during a suspend, `CLOCK_MONOTONIC` doesn't: a growing gap
+//! between them means that the system was suspended.
+
+use std::time::Duration;
+
+/// Shorter gaps are ignored (e.g. a stopped process)
+const MIN_SUSPEND: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub struct ResumeDetector {
+    /// Time spent suspended, at the last check
+    suspended: Duration,
+}
+
+impl Default for ResumeDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ResumeDetector {
+    pub fn new() -> Self {
+        Self {
+            suspended: suspended_time(),
+        }
+    }
+
+    /// Whether the system was suspended since the last check
+    pub fn resumed(&mut self) -> bool {
+        let suspended: Duration = suspended_time();
+        let resumed: bool = suspended.saturating_sub(self.suspended) >= MIN_SUSPEND;
+        self.suspended = suspended;
+        resumed
+    }
+}
+
+/// Time spent suspended since the boot
+fn suspended_time() -> Duration {
+    clock(libc::CLOCK_BOOTTIME).saturating_sub(clock(libc::CLOCK_MONOTONIC))
+}
+
+fn clock(id: libc::clockid_t) -> Duration {
+    let mut ts: libc::timespec = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    // Can't fail with a valid clock ID
+    unsafe {
+        libc::clock_gettime(id, &mut ts);
+    }
+    Duration::new(ts.tv_sec as u64, ts.tv_nsec as u32)
+}
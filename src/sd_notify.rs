@@ -0,0 +1,62 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! systemd service notifications (`Type=notify`)
+//!
+//! No-op if `NOTIFY_SOCKET` is not set (i.e. not started by systemd).
+
+use std::env;
+use std::io;
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+
+/// The daemon is ready: hotplug registered
+#[inline]
+pub fn ready() {
+    send("READY=1");
+}
+
+/// Free-form status, shown by `systemctl status`
+#[inline]
+pub fn status(status: &str) {
+    send(&format!("STATUS={status}"));
+}
+
+#[inline]
+pub fn stopping() {
+    send("STOPPING=1");
+}
+
+/// Keep-alive ping of the watchdog
+#[inline]
+pub fn watchdog() {
+    send("WATCHDOG=1");
+}
+
+/// Send the state to systemd, if it's listening
+///
+/// Failures are only logged.
+fn send(state: &str) {
+    let path: String = match env::var(NOTIFY_SOCKET) {
+        Ok(path) if !path.is_empty() => path,
+        _ => return,
+    };
+
+    if let Err(e) = send_to(&path, state) {
+        tracing::debug!("Can't notify systemd ({state}): {e}");
+    }
+}
+
+fn send_to(path: &str, state: &str) -> io::Result<()> {
+    // `@` is the abstract namespace
+    let addr: SocketAddr = match path.strip_prefix('@') {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+
+    let socket: UnixDatagram = UnixDatagram::unbound()?;
+    socket.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
@@ -0,0 +1,452 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Daemon state
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::path::PathBuf;
+use std::process;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::details::DeviceDetails;
+use crate::persist;
+use crate::template::DeviceInfo;
+use crate::DeviceAddr;
+
+/// Last errors kept for the state dump
+const MAX_RECENT_ERRORS: usize = 10;
+
+/// Mounted device
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MountedDevice {
+    pub serial_number: String,
+    pub mountpoints: Vec<PathBuf>,
+    #[serde(default)]
+    pub read_only: bool,
+    pub bus: u8,
+    pub address: u8,
+    #[serde(default)]
+    pub vendor_id: u16,
+    #[serde(default)]
+    pub product_id: u16,
+    /// USB product string (e.g. `iPhone`), if it was read
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Marketing name used for the directory name, with `naming = "model"`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model: Option<String>,
+    /// UNIX timestamp
+    pub mounted_at: u64,
+    /// User running ifuse, if not the daemon's
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// From `ideviceinfo`, if it could be queried
+    #[serde(default, skip_serializing_if = "DeviceDetails::is_empty")]
+    pub details: DeviceDetails,
+}
+
+impl MountedDevice {
+    /// Device info for the hooks
+    pub fn info(&self) -> DeviceInfo {
+        DeviceInfo {
+            serial_number: self.serial_number.clone(),
+            bus: self.bus,
+            address: self.address,
+            vendor_id: self.vendor_id,
+            product_id: self.product_id,
+            name: self.name.clone(),
+            model: self.model.clone(),
+        }
+    }
+}
+
+/// Runtime counters, since the daemon started
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Counters {
+    /// Arrivals of Apple devices
+    pub arrivals: u64,
+    /// Mount attempts, including the retries
+    pub mounts_attempted: u64,
+    pub mounts_succeeded: u64,
+    /// Failed mount attempts, by error class
+    pub mounts_failed: BTreeMap<String, u64>,
+    /// Devices unmounted
+    pub unmounts: u64,
+    /// Mount retries performed
+    pub retries: u64,
+}
+
+impl Counters {
+    /// Failed mount attempts, of all the classes
+    pub fn total_mounts_failed(&self) -> u64 {
+        self.mounts_failed.values().sum()
+    }
+}
+
+/// Runtime counters of a device, since the daemon started
+#[derive(Debug, Clone, Default)]
+pub struct DeviceCounters {
+    pub mounts_succeeded: u64,
+    /// Failed mount attempts, by error class
+    pub mounts_failed: BTreeMap<String, u64>,
+    pub unmounts: u64,
+    pub retries: u64,
+}
+
+/// Daemon status
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Status {
+    pub pid: u32,
+    /// UNIX timestamp
+    pub started_at: u64,
+    pub mount_failures: u64,
+    /// Whether the devices are reset before reading their serial number
+    pub usb_reset: bool,
+    pub devices: Vec<MountedDevice>,
+    /// Devices gone, with a busy mountpoint (retrying to unmount)
+    #[serde(default)]
+    pub pending_unmounts: Vec<MountedDevice>,
+    #[serde(default)]
+    pub counters: Counters,
+    /// Device events waiting for the handler thread
+    #[serde(default)]
+    pub queued: usize,
+}
+
+/// Mount retry, for the state dump
+#[derive(Debug, Clone)]
+pub struct ScheduledRetry {
+    pub serial_number: String,
+    /// Retries already done
+    pub attempt: u32,
+    pub due: Instant,
+    /// Waiting for the trust dialog
+    pub trust_pending: bool,
+}
+
+/// Error logged by the handler, for the state dump
+#[derive(Debug, Clone)]
+pub struct RecentError {
+    /// UNIX timestamp
+    pub at: u64,
+    pub message: String,
+}
+
+#[derive(Debug)]
+struct InnerState {
+    started_at: u64,
+    devices: HashMap<DeviceAddr, MountedDevice>,
+    pending_unmounts: Vec<MountedDevice>,
+    mount_failures: u64,
+    usb_reset: bool,
+    /// State file, where the devices are saved on every change
+    path: Option<PathBuf>,
+    /// Device events sent to the handler thread, not received yet
+    queued: usize,
+    retries: Vec<ScheduledRetry>,
+    recent_errors: VecDeque<RecentError>,
+    counters: Counters,
+    /// By serial number
+    device_counters: BTreeMap<String, DeviceCounters>,
+}
+
+impl InnerState {
+    #[inline]
+    fn device(&mut self, serial_number: &str) -> &mut DeviceCounters {
+        self.device_counters
+            .entry(serial_number.to_string())
+            .or_default()
+    }
+
+    fn save(&self) {
+        if let Some(path) = &self.path {
+            let devices: Vec<MountedDevice> = self.devices.values().cloned().collect();
+            if let Err(e) = persist::save(path, devices) {
+                tracing::warn!("Can't save the state to {}: {}", path.display(), e.report());
+            }
+        }
+    }
+}
+
+/// State shared between the handler thread and the control socket
+#[derive(Debug, Clone)]
+pub struct State {
+    inner: Arc<Mutex<InnerState>>,
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(InnerState {
+                started_at: now(),
+                devices: HashMap::new(),
+                pending_unmounts: Vec::new(),
+                mount_failures: 0,
+                usb_reset: true,
+                path: None,
+                queued: 0,
+                retries: Vec::new(),
+                recent_errors: VecDeque::with_capacity(MAX_RECENT_ERRORS),
+                counters: Counters::default(),
+                device_counters: BTreeMap::new(),
+            })),
+        }
+    }
+
+    /// State saved to `path` on every change, starting with the devices still mounted from the
+    /// previous instance
+    pub fn persistent(path: PathBuf) -> Self {
+        let state: Self = Self::new();
+        {
+            let mut inner: MutexGuard<'_, InnerState> = state.lock();
+            for device in persist::load(&path) {
+                let addr: DeviceAddr = DeviceAddr {
+                    bus: device.bus,
+                    addr: device.address,
+                };
+                inner.devices.insert(addr, device);
+            }
+            inner.path = Some(path);
+            inner.save();
+        }
+        state
+    }
+
+    // The state is always left consistent, so it's safe to ignore poisoning
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, InnerState> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn insert(&self, addr: DeviceAddr, device: MountedDevice) {
+        let mut inner: MutexGuard<'_, InnerState> = self.lock();
+        inner.devices.insert(addr, device);
+        inner.save();
+    }
+
+    #[inline]
+    pub fn contains(&self, addr: &DeviceAddr) -> bool {
+        self.lock().devices.contains_key(addr)
+    }
+
+    pub fn remove(&self, addr: &DeviceAddr) -> Option<MountedDevice> {
+        let mut inner: MutexGuard<'_, InnerState> = self.lock();
+        let device: Option<MountedDevice> = inner.devices.remove(addr);
+        if device.is_some() {
+            inner.save();
+        }
+        device
+    }
+
+    /// Mounted devices
+    pub fn devices(&self) -> Vec<MountedDevice> {
+        self.lock().devices.values().cloned().collect()
+    }
+
+    /// Remove all the devices
+    ///
+    /// Not saved: the ones still mounted after the shutdown are picked up by the next instance.
+    pub fn drain(&self) -> Vec<MountedDevice> {
+        self.lock()
+            .devices
+            .drain()
+            .map(|(_, device)| device)
+            .collect()
+    }
+
+    pub fn set_pending_unmounts(&self, devices: Vec<MountedDevice>) {
+        self.lock().pending_unmounts = devices;
+    }
+
+    pub fn record_mount_failure(&self) {
+        self.lock().mount_failures += 1;
+    }
+
+    pub fn count_arrival(&self) {
+        self.lock().counters.arrivals += 1;
+    }
+
+    pub fn count_mount_attempt(&self) {
+        self.lock().counters.mounts_attempted += 1;
+    }
+
+    pub fn count_mount_success(&self, serial_number: &str) {
+        let mut inner: MutexGuard<'_, InnerState> = self.lock();
+        inner.counters.mounts_succeeded += 1;
+        inner.device(serial_number).mounts_succeeded += 1;
+    }
+
+    /// Count a failed mount attempt, under the class of its error (e.g. `pairing`)
+    pub fn count_mount_failure(&self, serial_number: &str, class: &str) {
+        let mut inner: MutexGuard<'_, InnerState> = self.lock();
+        *inner
+            .counters
+            .mounts_failed
+            .entry(class.to_string())
+            .or_default() += 1;
+        *inner
+            .device(serial_number)
+            .mounts_failed
+            .entry(class.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn count_unmount(&self, serial_number: &str) {
+        let mut inner: MutexGuard<'_, InnerState> = self.lock();
+        inner.counters.unmounts += 1;
+        inner.device(serial_number).unmounts += 1;
+    }
+
+    pub fn count_retry(&self, serial_number: &str) {
+        let mut inner: MutexGuard<'_, InnerState> = self.lock();
+        inner.counters.retries += 1;
+        inner.device(serial_number).retries += 1;
+    }
+
+    /// Counters of the devices seen since the daemon started, by serial number
+    pub fn device_counters(&self) -> BTreeMap<String, DeviceCounters> {
+        self.lock().device_counters.clone()
+    }
+
+    /// A device event was sent to the handler thread
+    pub fn event_queued(&self) {
+        self.lock().queued += 1;
+    }
+
+    /// A device event was received by the handler thread
+    pub fn event_received(&self) {
+        let mut inner: MutexGuard<'_, InnerState> = self.lock();
+        inner.queued = inner.queued.saturating_sub(1);
+    }
+
+    pub fn set_retries(&self, retries: Vec<ScheduledRetry>) {
+        self.lock().retries = retries;
+    }
+
+    /// Scheduled mount retries, the next first
+    pub fn retries(&self) -> Vec<ScheduledRetry> {
+        let mut retries: Vec<ScheduledRetry> = self.lock().retries.clone();
+        retries.sort_by_key(|retry| retry.due);
+        retries
+    }
+
+    /// Keep the error for the state dump, dropping the oldest one
+    pub fn record_error(&self, message: String) {
+        let mut inner: MutexGuard<'_, InnerState> = self.lock();
+        if inner.recent_errors.len() >= MAX_RECENT_ERRORS {
+            inner.recent_errors.pop_front();
+        }
+        inner
+            .recent_errors
+            .push_back(RecentError { at: now(), message });
+    }
+
+    /// Last errors, the oldest first
+    pub fn recent_errors(&self) -> Vec<RecentError> {
+        self.lock().recent_errors.iter().cloned().collect()
+    }
+
+    pub fn set_usb_reset(&self, usb_reset: bool) {
+        self.lock().usb_reset = usb_reset;
+    }
+
+    pub fn status(&self) -> Status {
+        let inner: MutexGuard<'_, InnerState> = self.lock();
+        let mut devices: Vec<MountedDevice> = inner.devices.values().cloned().collect();
+        devices.sort_by_key(|device| device.mounted_at);
+
+        Status {
+            pid: process::id(),
+            started_at: inner.started_at,
+            mount_failures: inner.mount_failures,
+            usb_reset: inner.usb_reset,
+            devices,
+            pending_unmounts: inner.pending_unmounts.clone(),
+            counters: inner.counters.clone(),
+            queued: inner.queued,
+        }
+    }
+}
+
+/// Current UNIX timestamp
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IPHONE: &str = "00008030001A2C3E0E38802E";
+    const IPAD: &str = "00008101000A1B2C3D4E5F60";
+
+    #[test]
+    fn count_success_and_failure() {
+        let state: State = State::new();
+
+        // Locked, retried, then mounted
+        state.count_arrival();
+        state.count_mount_attempt();
+        state.count_mount_failure(IPHONE, "pairing");
+        state.count_retry(IPHONE);
+        state.count_mount_attempt();
+        state.count_mount_failure(IPHONE, "pairing");
+        state.count_retry(IPHONE);
+        state.count_mount_attempt();
+        state.count_mount_success(IPHONE);
+
+        // Failed for good
+        state.count_arrival();
+        state.count_mount_attempt();
+        state.count_mount_failure(IPAD, "fuse");
+
+        state.count_unmount(IPHONE);
+
+        let counters: Counters = state.status().counters;
+        assert_eq!(counters.arrivals, 2);
+        assert_eq!(counters.mounts_attempted, 4);
+        assert_eq!(counters.mounts_succeeded, 1);
+        assert_eq!(counters.total_mounts_failed(), 3);
+        assert_eq!(counters.mounts_failed.get("pairing"), Some(&2));
+        assert_eq!(counters.mounts_failed.get("fuse"), Some(&1));
+        assert_eq!(counters.unmounts, 1);
+        assert_eq!(counters.retries, 2);
+
+        let devices: BTreeMap<String, DeviceCounters> = state.device_counters();
+        assert_eq!(devices.len(), 2);
+
+        let iphone: &DeviceCounters = &devices[IPHONE];
+        assert_eq!(iphone.mounts_succeeded, 1);
+        assert_eq!(iphone.mounts_failed.get("pairing"), Some(&2));
+        assert_eq!(iphone.unmounts, 1);
+        assert_eq!(iphone.retries, 2);
+
+        let ipad: &DeviceCounters = &devices[IPAD];
+        assert_eq!(ipad.mounts_succeeded, 0);
+        assert_eq!(ipad.mounts_failed.get("fuse"), Some(&1));
+        assert_eq!(ipad.unmounts, 0);
+        assert_eq!(ipad.retries, 0);
+    }
+
+    #[test]
+    fn counters_start_at_zero() {
+        let state: State = State::new();
+        let counters: Counters = state.status().counters;
+        assert_eq!(counters.total_mounts_failed(), 0);
+        assert_eq!(counters.mounts_succeeded, 0);
+        assert!(state.device_counters().is_empty());
+    }
+}
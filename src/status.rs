@@ -0,0 +1,122 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Status of the running daemon
+
+use crate::config::Config;
+use crate::state::{self, Counters, MountedDevice, Status};
+use crate::{control, products, Error};
+
+/// Query the daemon running on the base path and print its status
+pub fn status(config: &Config, json: bool) -> Result<(), Error> {
+    let response: String = control::request(&config.base_path, "status")?;
+    let status: Status = serde_json::from_str(&response)?;
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&status)?);
+        return Ok(());
+    }
+
+    let now: u64 = state::now();
+
+    println!(
+        "Running (pid {}) for {}, {} mount failure(s)",
+        status.pid,
+        format_secs(now.saturating_sub(status.started_at)),
+        status.mount_failures
+    );
+    println!(
+        "USB reset before mounting: {}",
+        if status.usb_reset {
+            "enabled"
+        } else {
+            "disabled"
+        }
+    );
+
+    println!("{}", counters_summary(&status.counters));
+    println!("Queued device events: {}", status.queued);
+
+    if status.devices.is_empty() {
+        println!("No devices mounted");
+    } else {
+        println!();
+        println!(
+            "{:<40} {:<28} {:<12} {:<4} MOUNTPOINT",
+            "SERIAL", "PRODUCT", "MOUNTED FOR", "MODE"
+        );
+        for device in status.devices.iter() {
+            print_device(device, now);
+        }
+    }
+
+    // Gone, but still mounted
+    if !status.pending_unmounts.is_empty() {
+        println!();
+        println!("Pending unmount (mountpoint busy):");
+        for device in status.pending_unmounts.iter() {
+            print_device(device, now);
+        }
+    }
+
+    Ok(())
+}
+
+fn print_device(device: &MountedDevice, now: u64) {
+    println!(
+        "{:<40} {:<28} {:<12} {:<4} {}",
+        device.serial_number,
+        products::name(device.vendor_id, device.product_id),
+        format_secs(now.saturating_sub(device.mounted_at)),
+        if device.read_only { "ro" } else { "rw" },
+        device
+            .mountpoints
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    );
+
+    if !device.details.is_empty() {
+        println!("  {}", device.details.summary());
+    }
+}
+
+/// One-line summary of the counters, with the failures by error class
+pub fn counters_summary(counters: &Counters) -> String {
+    let mut summary: String = format!(
+        "Arrivals: {}, mounts: {} attempted, {} succeeded, {} failed",
+        counters.arrivals,
+        counters.mounts_attempted,
+        counters.mounts_succeeded,
+        counters.total_mounts_failed()
+    );
+
+    if !counters.mounts_failed.is_empty() {
+        let classes: Vec<String> = counters
+            .mounts_failed
+            .iter()
+            .map(|(class, count)| format!("{class}: {count}"))
+            .collect();
+        summary.push_str(&format!(" ({})", classes.join(", ")));
+    }
+
+    summary.push_str(&format!(
+        ", unmounts: {}, retries: {}",
+        counters.unmounts, counters.retries
+    ));
+    summary
+}
+
+/// Format as `1h 2m 3s`
+pub fn format_secs(secs: u64) -> String {
+    let (hours, mins, secs) = (secs / 3600, secs % 3600 / 60, secs % 60);
+
+    if hours > 0 {
+        format!("{hours}h {mins}m {secs}s")
+    } else if mins > 0 {
+        format!("{mins}m {secs}s")
+    } else {
+        format!("{secs}s")
+    }
+}
@@ -0,0 +1,98 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! syslog output, to the local syslog daemon (facility `daemon`)
+//!
+//! Each line of a multi-line message (e.g. the state dump) is sent as its own message, with the
+//! same header. A message that can't be sent is written to stderr instead.
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use crate::fields::{self, EventFields};
+
+/// Linux, then the BSDs and macOS
+const SYSLOG_SOCKETS: [&str; 3] = ["/dev/log", "/var/run/log", "/var/run/syslog"];
+const FACILITY_DAEMON: u8 = 3;
+
+pub struct SyslogLayer {
+    socket: UnixDatagram,
+    identifier: String,
+    /// Already warned that syslog is unreachable
+    failed: AtomicBool,
+}
+
+impl SyslogLayer {
+    pub fn new(identifier: String) -> io::Result<Self> {
+        let socket: UnixDatagram = UnixDatagram::unbound()?;
+
+        let mut last_error: io::Error = io::Error::from(io::ErrorKind::NotFound);
+        for path in SYSLOG_SOCKETS {
+            match socket.connect(path) {
+                Ok(()) => {
+                    return Ok(Self {
+                        socket,
+                        identifier,
+                        failed: AtomicBool::new(false),
+                    })
+                }
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+impl<S> Layer<S> for SyslogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        fields::on_new_span(attrs, id, &ctx);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        fields::on_record(id, values, &ctx);
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let line: String = EventFields::collect(event, &ctx).to_line();
+        let level: Level = *event.metadata().level();
+
+        // `<PRI>ident[pid]: message`, the daemon adds the time and the host
+        let header: String = format!(
+            "<{}>{}[{}]: ",
+            FACILITY_DAEMON * 8 + severity(level),
+            self.identifier,
+            process::id()
+        );
+
+        for line in line.lines().filter(|line| !line.trim().is_empty()) {
+            let message: String = format!("{header}{line}");
+            if let Err(e) = self.socket.send(message.as_bytes()) {
+                if !self.failed.swap(true, Ordering::Relaxed) {
+                    eprintln!("Can't write to syslog ({e}), logging to stderr");
+                }
+                eprintln!("{level:>5} {line}");
+            }
+        }
+    }
+}
+
+/// Usual mapping: error → err, warn → warning, info → info, debug and trace → debug
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    }
+}
@@ -0,0 +1,253 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Mountpoint templates
+//!
+//! Placeholders: `{serial}`, `{bus}`, `{address}`, `{vendor_id}`, `{product_id}` and `{name}`.
+//! The numeric ones accept a `:x`/`:X` suffix to format them as hex (e.g. `{product_id:x}`).
+//! Use `{{` and `}}` for literal braces.
+
+use std::fmt;
+use std::str::FromStr;
+
+/// Default template: the serial number
+pub const DEFAULT_TEMPLATE: &str = "{serial}";
+/// Longest directory name, in bytes (`NAME_MAX`)
+const MAX_NAME_LEN: usize = 255;
+
+/// Device info available to the templates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub serial_number: String,
+    pub bus: u8,
+    pub address: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    /// Product string (e.g. `iPhone`), if it could be read
+    pub name: Option<String>,
+    /// Marketing name (e.g. `iPhone 13`), with `naming = "model"`
+    pub model: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Placeholder {
+    Serial,
+    Bus,
+    Address,
+    VendorId,
+    ProductId,
+    Name,
+}
+
+impl Placeholder {
+    fn parse(name: &str) -> Option<Self> {
+        match name {
+            "serial" => Some(Self::Serial),
+            "bus" => Some(Self::Bus),
+            "address" => Some(Self::Address),
+            "vendor_id" => Some(Self::VendorId),
+            "product_id" => Some(Self::ProductId),
+            "name" => Some(Self::Name),
+            _ => None,
+        }
+    }
+
+    #[inline]
+    fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            Self::Bus | Self::Address | Self::VendorId | Self::ProductId
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Decimal,
+    LowerHex,
+    UpperHex,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Placeholder(Placeholder, Format),
+}
+
+/// Parsed mountpoint template
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl Default for Template {
+    fn default() -> Self {
+        Self::from_str(DEFAULT_TEMPLATE).expect("valid default template")
+    }
+}
+
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        let mut parts: Vec<Part> = Vec::new();
+        let mut literal: String = String::new();
+        let mut chars = source.chars();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest: &str = chars.as_str();
+                    let end: usize = rest
+                        .find('}')
+                        .ok_or_else(|| String::from("unclosed placeholder"))?;
+                    let placeholder: Part = parse_placeholder(&rest[..end])?;
+                    chars = rest[end + 1..].chars();
+
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(placeholder);
+                }
+                '}' => return Err(String::from("unmatched '}' (use '}}' for a literal brace)")),
+                '/' | '\0' => return Err(format!("invalid character {c:?}")),
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        // Reject templates that can't produce a directory name
+        let only_dots: bool = parts
+            .iter()
+            .all(|part| matches!(part, Part::Literal(l) if l.chars().all(|c| c == '.')));
+        if parts.is_empty() || only_dots {
+            return Err(format!("'{source}' is not a valid directory name"));
+        }
+
+        Ok(Self {
+            source: source.to_string(),
+            parts,
+        })
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Template {
+    /// Check if the template needs the device name (an extra USB request)
+    pub fn uses_name(&self) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Placeholder(Placeholder::Name, ..)))
+    }
+
+    /// Render the template, if it only depends on the serial number
+    pub fn render_serial_only(&self, serial_number: &str) -> Option<String> {
+        let mut rendered: String = String::new();
+
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(literal) => rendered.push_str(literal),
+                Part::Placeholder(Placeholder::Serial, ..) => {
+                    rendered.push_str(&escape(serial_number))
+                }
+                Part::Placeholder(..) => return None,
+            }
+        }
+
+        Some(rendered)
+    }
+
+    /// Render the template into a directory name
+    ///
+    /// If the name is not available, the serial number is used instead.
+    pub fn render(&self, info: &DeviceInfo) -> String {
+        let mut rendered: String = String::new();
+
+        for part in self.parts.iter() {
+            match part {
+                Part::Literal(literal) => rendered.push_str(literal),
+                Part::Placeholder(placeholder, format) => {
+                    let value: String = match placeholder {
+                        Placeholder::Serial => escape(&info.serial_number),
+                        Placeholder::Bus => format_number(u16::from(info.bus), *format),
+                        Placeholder::Address => format_number(u16::from(info.address), *format),
+                        Placeholder::VendorId => format_number(info.vendor_id, *format),
+                        Placeholder::ProductId => format_number(info.product_id, *format),
+                        Placeholder::Name => {
+                            escape(info.name.as_deref().unwrap_or(&info.serial_number))
+                        }
+                    };
+                    rendered.push_str(&value);
+                }
+            }
+        }
+
+        rendered
+    }
+}
+
+fn parse_placeholder(content: &str) -> Result<Part, String> {
+    let (name, format) = match content.split_once(':') {
+        Some((name, "x")) => (name, Format::LowerHex),
+        Some((name, "X")) => (name, Format::UpperHex),
+        Some((name, spec)) => return Err(format!("unknown format '{spec}' for '{name}'")),
+        None => (content, Format::Decimal),
+    };
+
+    let placeholder: Placeholder =
+        Placeholder::parse(name).ok_or_else(|| format!("unknown placeholder '{name}'"))?;
+
+    if format != Format::Decimal && !placeholder.is_numeric() {
+        return Err(format!("'{name}' can't be formatted as hex"));
+    }
+
+    Ok(Part::Placeholder(placeholder, format))
+}
+
+#[inline]
+fn format_number(value: u16, format: Format) -> String {
+    match format {
+        Format::Decimal => value.to_string(),
+        Format::LowerHex => format!("{value:x}"),
+        Format::UpperHex => format!("{value:X}"),
+    }
+}
+
+/// Replace the characters that are invalid (or awkward) in a directory name, and truncate it to `NAME_MAX`
+pub fn escape(value: &str) -> String {
+    let mut escaped: String = String::with_capacity(value.len());
+    for c in value.chars() {
+        let c: char = match c {
+            '/' | '\0' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        };
+
+        if escaped.len() + c.len_utf8() > MAX_NAME_LEN {
+            break;
+        }
+        escaped.push(c);
+    }
+
+    match escaped.as_str() {
+        "" | "." | ".." => String::from("_"),
+        _ => escaped,
+    }
+}
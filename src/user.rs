@@ -0,0 +1,138 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Target user of the mounts, when running as root
+
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::Error;
+
+const PASSWD: &str = "/etc/passwd";
+const GROUP: &str = "/etc/group";
+/// Seat of the local console, maintained by systemd-logind
+const LOGIND_SEAT: &str = "/run/systemd/seats/seat0";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: PathBuf,
+    /// Supplementary groups
+    pub groups: Vec<u32>,
+}
+
+impl User {
+    #[inline]
+    pub fn runtime_dir(&self) -> PathBuf {
+        PathBuf::from(format!("/run/user/{}", self.uid))
+    }
+
+    /// Run the command as this user, with access to their session bus (e.g. notifications)
+    pub fn apply_session(&self, cmd: &mut Command) {
+        self.apply(cmd);
+        cmd.env(
+            "DBUS_SESSION_BUS_ADDRESS",
+            format!("unix:path={}/bus", self.runtime_dir().display()),
+        );
+    }
+
+    /// Run the command as this user
+    ///
+    /// The privileges are dropped in the child, right before `exec`.
+    pub fn apply(&self, cmd: &mut Command) {
+        cmd.env("HOME", &self.home)
+            .env("USER", &self.name)
+            .env("LOGNAME", &self.name)
+            .env("XDG_RUNTIME_DIR", self.runtime_dir());
+
+        let uid: libc::uid_t = self.uid;
+        let gid: libc::gid_t = self.gid;
+        let groups: Vec<libc::gid_t> = self.groups.clone();
+
+        // Only async-signal-safe calls, the groups are resolved beforehand
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::setgroups(groups.len(), groups.as_ptr()) < 0
+                    || libc::setgid(gid) < 0
+                    || libc::setuid(uid) < 0
+                {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+/// Look up a user by name or uid
+pub fn lookup(name_or_uid: &str) -> Result<User, Error> {
+    let passwd: String =
+        fs::read_to_string(PASSWD).map_err(Error::fs("read", Path::new(PASSWD)))?;
+
+    // `name:password:uid:gid:gecos:home:shell`
+    let entry: Option<User> = passwd.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 7 || (fields[0] != name_or_uid && fields[2] != name_or_uid) {
+            return None;
+        }
+
+        Some(User {
+            name: fields[0].to_string(),
+            uid: fields[2].parse().ok()?,
+            gid: fields[3].parse().ok()?,
+            home: PathBuf::from(fields[5]),
+            groups: Vec::new(),
+        })
+    });
+
+    let mut user: User = entry.ok_or_else(|| Error::UserNotFound(name_or_uid.to_string()))?;
+    user.groups = groups_of(&user.name, user.gid);
+    Ok(user)
+}
+
+/// User of the active session on the local seat, if any
+pub fn active() -> Option<User> {
+    let seat: String = fs::read_to_string(LOGIND_SEAT).ok()?;
+    let uid: &str = seat
+        .lines()
+        .find_map(|line| line.strip_prefix("ACTIVE_UID="))?;
+
+    match lookup(uid.trim()) {
+        Ok(user) => Some(user),
+        Err(e) => {
+            tracing::warn!(
+                "Can't look up the user of the active session: {}",
+                e.report()
+            );
+            None
+        }
+    }
+}
+
+/// Primary and supplementary groups of the user
+fn groups_of(name: &str, gid: u32) -> Vec<u32> {
+    let mut groups: Vec<u32> = vec![gid];
+
+    // `name:password:gid:member1,member2`
+    if let Ok(content) = fs::read_to_string(GROUP) {
+        for line in content.lines() {
+            let fields: Vec<&str> = line.split(':').collect();
+            if fields.len() < 4 || !fields[3].split(',').any(|member| member == name) {
+                continue;
+            }
+
+            if let Ok(gid) = fields[2].parse() {
+                if !groups.contains(&gid) {
+                    groups.push(gid);
+                }
+            }
+        }
+    }
+
+    groups
+}
@@ -0,0 +1,183 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! systemd watchdog (`WatchdogSec=`)
+//!
+//! The main loop pets the watchdog only while the handler thread is alive (i.e. it beats recently),
+//! so that systemd restarts the daemon if the handler is stuck (e.g. on a hung ifuse).
+
+use std::env;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::sd_notify;
+
+/// Max time between two beats of an idle handler
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Liveness of the handler thread
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    start: Instant,
+    /// Milliseconds since `start`
+    last: Arc<AtomicU64>,
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Self {
+            start: Instant::now(),
+            last: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+impl Heartbeat {
+    #[inline]
+    pub fn beat(&self) {
+        let elapsed: u64 = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+    }
+
+    /// Time since the last beat
+    #[inline]
+    pub fn age(&self) -> Duration {
+        let last: Duration = Duration::from_millis(self.last.load(Ordering::Relaxed));
+        self.start.elapsed().saturating_sub(last)
+    }
+}
+
+#[derive(Debug)]
+pub struct Watchdog {
+    /// `WatchdogSec=`
+    timeout: Duration,
+    heartbeat: Heartbeat,
+    last_ping: Option<Instant>,
+    /// Pings skipped because of a stuck handler
+    stalled: bool,
+}
+
+impl Watchdog {
+    /// Watchdog requested by systemd (`WATCHDOG_USEC`, for this process if `WATCHDOG_PID` is set)
+    pub fn from_env(heartbeat: Heartbeat) -> Option<Self> {
+        if let Ok(pid) = env::var("WATCHDOG_PID") {
+            if pid.parse::<u32>().ok() != Some(process::id()) {
+                return None;
+            }
+        }
+
+        let usec: u64 = env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        if usec == 0 {
+            return None;
+        }
+
+        let timeout: Duration = Duration::from_micros(usec);
+        tracing::debug!("systemd watchdog enabled: {} ms", timeout.as_millis());
+
+        Some(Self::new(timeout, heartbeat))
+    }
+
+    fn new(timeout: Duration, heartbeat: Heartbeat) -> Self {
+        Self {
+            timeout,
+            heartbeat,
+            last_ping: None,
+            stalled: false,
+        }
+    }
+
+    /// Pet the watchdog every half timeout, if the handler beat within the timeout
+    pub fn tick(&mut self) {
+        let due: bool = self
+            .last_ping
+            .is_none_or(|last| last.elapsed() >= self.timeout / 2);
+        if !due {
+            return;
+        }
+
+        let age: Duration = self.heartbeat.age();
+        if age >= self.timeout {
+            if !self.stalled {
+                tracing::error!(
+                    "Handler thread stuck for {} s, letting the watchdog expire",
+                    age.as_secs()
+                );
+                self.stalled = true;
+            }
+            return;
+        }
+
+        if self.stalled {
+            tracing::info!("Handler thread alive again");
+            self.stalled = false;
+        }
+
+        sd_notify::watchdog();
+        self.last_ping = Some(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::os::unix::net::UnixDatagram;
+    use std::path::PathBuf;
+    use std::process::Command;
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+    use crate::child;
+
+    /// Receive a notification, if any within 100 ms
+    fn recv(socket: &UnixDatagram) -> Option<String> {
+        let mut buf: [u8; 64] = [0; 64];
+        match socket.recv(&mut buf) {
+            Ok(len) => Some(String::from_utf8_lossy(&buf[..len]).into_owned()),
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => None,
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn stop_petting_while_the_handler_hangs() {
+        let path: PathBuf =
+            env::temp_dir().join(format!("ifuse-automount-test-{}.sock", process::id()));
+        let _ = std::fs::remove_file(&path);
+        let socket: UnixDatagram = UnixDatagram::bind(&path).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+        env::set_var("NOTIFY_SOCKET", &path);
+
+        let heartbeat: Heartbeat = Heartbeat::default();
+        heartbeat.beat();
+        let mut watchdog: Watchdog = Watchdog::new(Duration::from_millis(400), heartbeat.clone());
+
+        // Alive
+        watchdog.tick();
+        assert_eq!(recv(&socket).as_deref(), Some("WATCHDOG=1"));
+
+        // The handler hangs on a slow command (e.g. ifuse), without beating
+        let handler: JoinHandle<()> = thread::spawn(move || {
+            let mut cmd: Command = Command::new("sleep");
+            cmd.arg("1");
+            child::output(&mut cmd, Duration::from_secs(5)).unwrap();
+            heartbeat.beat();
+        });
+        thread::sleep(Duration::from_millis(600));
+        watchdog.tick();
+        assert_eq!(recv(&socket), None);
+        assert!(watchdog.stalled);
+
+        // Alive again
+        handler.join().unwrap();
+        watchdog.tick();
+        assert_eq!(recv(&socket).as_deref(), Some("WATCHDOG=1"));
+        assert!(!watchdog.stalled);
+
+        env::remove_var("NOTIFY_SOCKET");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
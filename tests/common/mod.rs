@@ -0,0 +1,84 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Helpers of the integration tests: the binary, in an isolated environment
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const BIN: &str = env!("CARGO_BIN_EXE_ifuse-automount");
+
+pub const EXIT_IFUSE_NOT_INSTALLED: i32 = 2;
+pub const EXIT_UNMOUNT_COMMAND_NOT_INSTALLED: i32 = 3;
+pub const EXIT_USB: i32 = 4;
+pub const EXIT_ALREADY_RUNNING: i32 = 6;
+pub const EXIT_DAEMON_NOT_RUNNING: i32 = 7;
+pub const EXIT_USAGE: i32 = 64;
+pub const EXIT_CONFIG: i32 = 78;
+
+/// Temporary home, runtime and base dirs of a test, removed on drop
+pub struct TestDir {
+    pub path: PathBuf,
+}
+
+impl TestDir {
+    pub fn new(name: &str) -> Self {
+        let path: PathBuf = std::env::temp_dir().join(format!(
+            "ifuse-automount-test-{name}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir_all(path.join("runtime")).unwrap();
+        Self { path }
+    }
+
+    pub fn base_path(&self) -> PathBuf {
+        self.path.join("mnt")
+    }
+
+    /// Write an executable shell script
+    pub fn script(&self, name: &str, body: &str) -> PathBuf {
+        let path: PathBuf = self.path.join(name);
+        fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    /// The binary, with the dirs of the test and without the user's config
+    pub fn command(&self) -> Command {
+        let mut cmd: Command = Command::new(BIN);
+        cmd.env("HOME", &self.path)
+            .env("XDG_RUNTIME_DIR", self.path.join("runtime"))
+            .env_remove("XDG_CONFIG_HOME")
+            .env_remove("XDG_STATE_HOME")
+            .env_remove("XDG_CACHE_HOME")
+            .env_remove("NOTIFY_SOCKET")
+            .env_remove("WATCHDOG_USEC")
+            .stdin(Stdio::null());
+        cmd
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
+
+/// Wait for a file to exist
+pub fn wait_for(path: &Path, timeout: Duration) -> bool {
+    let start: Instant = Instant::now();
+    while start.elapsed() < timeout {
+        if path.exists() {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    false
+}
@@ -0,0 +1,128 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Exit codes of the binary in broken environments
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Child, Output, Stdio};
+use std::time::Duration;
+
+mod common;
+
+use self::common::*;
+
+fn exit_code(output: Output) -> Option<i32> {
+    output.status.code()
+}
+
+#[test]
+fn invalid_config() {
+    let dir: TestDir = TestDir::new("invalid-config");
+    let config: PathBuf = dir.path.join("config.toml");
+    fs::write(&config, "base_path =").unwrap();
+
+    let output: Output = dir.command().arg("--config").arg(&config).output().unwrap();
+    assert_eq!(exit_code(output), Some(EXIT_CONFIG));
+
+    // Unknown setting
+    fs::write(&config, "base_pth = \"/media\"").unwrap();
+    let output: Output = dir.command().arg("--config").arg(&config).output().unwrap();
+    assert_eq!(exit_code(output), Some(EXIT_CONFIG));
+}
+
+#[test]
+fn invalid_env_var() {
+    let dir: TestDir = TestDir::new("invalid-env");
+    let output: Output = dir
+        .command()
+        .env("IFUSE_AUTOMOUNT_SETTLE_DELAY_MS", "soon")
+        .output()
+        .unwrap();
+    assert_eq!(exit_code(output), Some(EXIT_CONFIG));
+}
+
+#[test]
+fn invalid_command_line() {
+    let dir: TestDir = TestDir::new("usage");
+    let output: Output = dir.command().arg("--no-such-flag").output().unwrap();
+    assert_eq!(exit_code(output), Some(EXIT_USAGE));
+
+    let output: Output = dir.command().args(["--usb-timeout", "0"]).output().unwrap();
+    assert_eq!(exit_code(output), Some(EXIT_USAGE));
+}
+
+#[test]
+fn ifuse_missing() {
+    let dir: TestDir = TestDir::new("ifuse-missing");
+    let output: Output = dir
+        .command()
+        .arg("--base-path")
+        .arg(dir.base_path())
+        .arg("--ifuse-path")
+        .arg(dir.path.join("no-ifuse"))
+        .output()
+        .unwrap();
+    assert_eq!(exit_code(output), Some(EXIT_IFUSE_NOT_INSTALLED));
+}
+
+#[test]
+fn unmount_command_missing() {
+    let dir: TestDir = TestDir::new("unmount-missing");
+    let ifuse: PathBuf = dir.script("ifuse", "exit 0");
+    let output: Output = dir
+        .command()
+        .arg("--base-path")
+        .arg(dir.base_path())
+        .arg("--ifuse-path")
+        .arg(&ifuse)
+        .arg("--unmount-command")
+        .arg(dir.path.join("no-fusermount"))
+        .output()
+        .unwrap();
+    assert_eq!(exit_code(output), Some(EXIT_UNMOUNT_COMMAND_NOT_INSTALLED));
+}
+
+#[test]
+fn lock_held() {
+    let dir: TestDir = TestDir::new("lock-held");
+
+    // The first instance holds the lock while checking ifuse, which hangs
+    let started: PathBuf = dir.path.join("started");
+    let ifuse: PathBuf = dir.script(
+        "ifuse",
+        &format!("touch '{}'\nexec sleep 10", started.display()),
+    );
+    let mut first: Child = dir
+        .command()
+        .arg("--base-path")
+        .arg(dir.base_path())
+        .arg("--ifuse-path")
+        .arg(&ifuse)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    assert!(wait_for(&started, Duration::from_secs(10)));
+
+    let output: Output = dir
+        .command()
+        .arg("--base-path")
+        .arg(dir.base_path())
+        .arg("--ifuse-path")
+        .arg(&ifuse)
+        .output()
+        .unwrap();
+
+    first.kill().unwrap();
+    first.wait().unwrap();
+
+    assert_eq!(exit_code(output), Some(EXIT_ALREADY_RUNNING));
+}
+
+#[test]
+fn daemon_not_running() {
+    let dir: TestDir = TestDir::new("not-running");
+    let output: Output = dir.command().arg("status").output().unwrap();
+    assert_eq!(exit_code(output), Some(EXIT_DAEMON_NOT_RUNNING));
+}
@@ -0,0 +1,95 @@
+// Copyright (c) 2025 Yuki Kishimoto
+// Distributed under the MIT software license
+
+//! Graceful shutdown on SIGTERM and SIGINT
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+use std::process::{Child, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+mod common;
+
+use self::common::*;
+
+/// Start the daemon in dry-run mode and wait for `READY=1`
+///
+/// `None` if libusb is unusable here (e.g. in a container without USB).
+fn start(dir: &TestDir) -> Option<Child> {
+    let socket_path: PathBuf = dir.path.join("notify.sock");
+    let socket: UnixDatagram = UnixDatagram::bind(&socket_path).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(100)))
+        .unwrap();
+
+    let mut child: Child = dir
+        .command()
+        .arg("--dry-run")
+        .arg("--base-path")
+        .arg(dir.base_path())
+        .env("NOTIFY_SOCKET", &socket_path)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let start: Instant = Instant::now();
+    let mut buf: [u8; 256] = [0; 256];
+    while start.elapsed() < Duration::from_secs(10) {
+        match socket.recv(&mut buf) {
+            Ok(len) if buf[..len].split(|b| *b == b'\n').any(|l| l == b"READY=1") => {
+                return Some(child)
+            }
+            Ok(..) => {}
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) => {}
+            Err(e) => panic!("{e}"),
+        }
+
+        if let Some(status) = child.try_wait().unwrap() {
+            assert_eq!(status.code(), Some(EXIT_USB), "exited before being ready");
+            eprintln!("libusb unusable, skipping");
+            return None;
+        }
+    }
+
+    child.kill().unwrap();
+    panic!("not ready after 10 s");
+}
+
+/// Send the signal and wait for the exit
+fn stop(mut child: Child, signal: i32) -> ExitStatus {
+    assert_eq!(unsafe { libc::kill(child.id() as libc::pid_t, signal) }, 0);
+
+    let start: Instant = Instant::now();
+    while start.elapsed() < Duration::from_secs(10) {
+        if let Some(status) = child.try_wait().unwrap() {
+            return status;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+
+    child.kill().unwrap();
+    panic!("still running 10 s after the signal");
+}
+
+#[test]
+fn exit_on_sigterm() {
+    let dir: TestDir = TestDir::new("sigterm");
+    if let Some(child) = start(&dir) {
+        assert_eq!(stop(child, libc::SIGTERM).code(), Some(0));
+    }
+}
+
+#[test]
+fn exit_on_sigint() {
+    let dir: TestDir = TestDir::new("sigint");
+    if let Some(child) = start(&dir) {
+        assert_eq!(stop(child, libc::SIGINT).code(), Some(0));
+    }
+}